@@ -0,0 +1,32 @@
+//! What each segment costs on the sending side of a bulk transfer: building and writing out the
+//! data segments, and taking in the pure ACKs that come back for them.
+//!
+//! Run with `cargo bench --features testing`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use trust::bench::{Burst, PureAck};
+
+/// segments in a send burst
+const BURST: usize = 32;
+
+fn send_burst(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send");
+    group.throughput(Throughput::Elements(BURST as u64));
+    group.bench_function("burst", |b| {
+        b.iter_batched_ref(
+            || Burst::new(BURST),
+            |burst| burst.send(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn pure_ack(c: &mut Criterion) {
+    c.bench_function("pure_ack", |b| {
+        b.iter_batched_ref(PureAck::new, |ack| ack.deliver(), BatchSize::SmallInput)
+    });
+}
+
+criterion_group!(benches, send_burst, pure_ack);
+criterion_main!(benches);
@@ -0,0 +1,43 @@
+//! Connects to a TCP server on the other side of tun0, sends it a line, and prints what comes
+//! back.
+//!
+//! Set up tun0 as in run.sh, start something listening on the host (say, `nc -l 8000`, and type
+//! a reply), and then run
+//!
+//! ```text
+//! cargo run --example client -- 192.168.0.1:8000
+//! ```
+
+use std::io::prelude::*;
+use std::{env, io, net::Ipv4Addr, net::SocketAddrV4};
+
+/// the address we use on the tun0 subnet
+const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+fn main() -> io::Result<()> {
+    let remote: SocketAddrV4 = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "192.168.0.1:8000".to_string())
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut i = trust::Interface::new()?;
+    eprintln!("created interface");
+    let mut stream = match i.connect(LOCAL, remote) {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            eprintln!("nothing is listening on {}", remote);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    eprintln!("connected to {}", remote);
+
+    stream.write_all(b"hello from rust-tcp\n")?;
+    let mut buf = [0; 512];
+    let n = stream.read(&mut buf[..])?;
+    println!("{}", String::from_utf8_lossy(&buf[..n]));
+
+    stream.shutdown(std::net::Shutdown::Write)?;
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! Just enough of the TCP Authentication Option (RFC 5925) to sign and verify segments with
+//! HMAC-SHA-1-96, using traffic keys derived with KDF_HMAC_SHA1 (RFC 5926).
+
+use crate::sha1;
+
+/// TCP-AO option kind
+const KIND: u8 = 29;
+/// HMAC-SHA-1-96 truncates the HMAC to 96 bits
+pub const MAC_LEN: usize = 12;
+/// kind, length, KeyID, RNextKeyID and the MAC
+pub const OPTION_LEN: usize = 4 + MAC_LEN;
+
+/// One end of a segment, as it goes into a traffic key: address, port and ISN.
+pub type End = ([u8; 4], u16, u32);
+
+/// Derives the key for segments going from `src` to `dst` from a master key (RFC 5926 S3.1.1).
+///
+/// On a SYN, the receiver hasn't picked its ISN yet, so `dst` gets an ISN of zero.
+pub fn traffic_key(master_key: &[u8], src: End, dst: End) -> [u8; 20] {
+    // i || Label || Context || Output_Length, with a single 160-bit iteration
+    let mut input = Vec::with_capacity(1 + 6 + 20 + 2);
+    input.push(1);
+    input.extend_from_slice(b"TCP-AO");
+    input.extend_from_slice(&src.0);
+    input.extend_from_slice(&dst.0);
+    input.extend_from_slice(&src.1.to_be_bytes());
+    input.extend_from_slice(&dst.1.to_be_bytes());
+    input.extend_from_slice(&src.2.to_be_bytes());
+    input.extend_from_slice(&dst.2.to_be_bytes());
+    input.extend_from_slice(&160u16.to_be_bytes());
+    sha1::hmac(master_key, &input)
+}
+
+/// The MAC of a segment (RFC 5925 S5.1) with the given serialized tcp header, options and all.
+///
+/// The header's checksum and the MAC in its TCP-AO option count as zero, whatever is in them.
+pub fn mac(
+    traffic_key: &[u8; 20],
+    sne: u32,
+    src: &[u8],
+    dst: &[u8],
+    header: &[u8],
+    payload: &[u8],
+) -> [u8; MAC_LEN] {
+    let mut input = Vec::with_capacity(4 + 12 + header.len() + payload.len());
+    input.extend_from_slice(&sne.to_be_bytes());
+    // pseudo-header
+    input.extend_from_slice(src);
+    input.extend_from_slice(dst);
+    input.extend_from_slice(&[0, 0x06]);
+    input.extend_from_slice(&((header.len() + payload.len()) as u16).to_be_bytes());
+
+    let header_at = input.len();
+    input.extend_from_slice(header);
+    input[header_at + 16..header_at + 18].fill(0);
+    if let Some(at) = mac_offset(header) {
+        input[header_at + at..header_at + at + MAC_LEN].fill(0);
+    }
+    input.extend_from_slice(payload);
+
+    let mut out = [0u8; MAC_LEN];
+    out.copy_from_slice(&sha1::hmac(traffic_key, &input)[..MAC_LEN]);
+    out
+}
+
+/// Lays out a TCP-AO option.
+pub fn option(key_id: u8, rnext_key_id: u8, mac: &[u8; MAC_LEN]) -> Vec<u8> {
+    let mut option = vec![KIND, OPTION_LEN as u8, key_id, rnext_key_id];
+    option.extend_from_slice(mac);
+    option
+}
+
+/// Finds the TCP-AO option in a raw TCP options area, returning its KeyID, RNextKeyID and MAC.
+pub fn parse(options: &[u8]) -> Option<(u8, u8, &[u8])> {
+    let at = find(options)?;
+    let option = &options[at..at + OPTION_LEN];
+    Some((option[2], option[3], &option[4..]))
+}
+
+/// Where the MAC of the TCP-AO option in a serialized tcp header starts, if it has one.
+fn mac_offset(header: &[u8]) -> Option<usize> {
+    find(header.get(20..)?).map(|at| 20 + at + 4)
+}
+
+/// Where the TCP-AO option starts in a raw TCP options area, if it is there and well-formed.
+fn find(options: &[u8]) -> Option<usize> {
+    let mut at = 0;
+    while let Some(&kind) = options.get(at) {
+        match kind {
+            // end of option list
+            0 => return None,
+            // no-operation
+            1 => at += 1,
+            _ => {
+                let len = *options.get(at + 1)? as usize;
+                if len < 2 || at + len > options.len() {
+                    return None;
+                }
+                if kind == KIND {
+                    return Some(at).filter(|_| len == OPTION_LEN);
+                }
+                at += len;
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,59 @@
+//! Where connections get the time from, so that tests can control it.
+
+use std::{fmt::Debug, time::Instant};
+#[cfg(any(test, feature = "testing"))]
+use std::{sync::Mutex, time::Duration};
+
+/// A source of [`Instant`]s for connection timers.
+///
+/// Everything a connection times (retransmissions, delayed ACKs, probes, timeouts) goes by this
+/// clock, so one that is moved along by hand makes the timers fire exactly when a test wants.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real time, as given by [`Instant::now`]. This is what connections use by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it is [advanced](MockClock::advance).
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockClock {
+    /// A clock that starts out at the current time.
+    pub fn new() -> Self {
+        MockClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
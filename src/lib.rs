@@ -1,17 +1,45 @@
 use std::{
-    collections::{hash_map::Entry, HashMap, VecDeque},
-    io::{self, Read, Write},
-    net::{Ipv4Addr, Shutdown},
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    io::{self, IoSlice, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
     os::fd::AsRawFd,
-    sync::{Arc, Condvar, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 use nix::poll::{poll, EventFlags, PollFd};
 
+mod ao;
+mod clock;
+mod md5;
+mod nic;
+mod sha1;
 mod tcp;
+mod trace;
 
-const SENDQUEUE_SIZE: usize = 1024;
+#[cfg(any(test, feature = "testing"))]
+pub use clock::MockClock;
+pub use clock::{Clock, SystemClock};
+use nic::Nic;
+pub use nic::{Capture, Direction};
+#[cfg(feature = "testing")]
+#[doc(hidden)]
+pub use tcp::bench;
+#[cfg(any(test, feature = "testing"))]
+pub use tcp::State;
+pub use tcp::{
+    AoKey, Available, ConnectionConfig, Drops, IssStrategy, LogEntry, LoggedSegment,
+    NegotiatedOptions, ResetReason, Snapshot, Stats, DEFAULT_ACK_DELAY, DEFAULT_ACK_EVERY,
+    DEFAULT_INITIAL_RTO, DEFAULT_MAX_RTO, DEFAULT_MIN_RTO, DEFAULT_MSL, DEFAULT_SYN_ACK_RETRIES,
+    DEFAULT_SYN_RETRIES,
+};
+pub use trace::segment_trace;
+
+use tcp::SENDQUEUE_SIZE;
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
 struct Quad {
@@ -24,6 +52,9 @@ struct Foobar {
     manager: Mutex<ConnectionManager>,
     pending_var: Condvar,
     rcv_var: Condvar,
+    snd_var: Condvar,
+    /// shared with the packet loop's [`Nic`]
+    capture: Arc<Mutex<Option<Capture>>>,
 }
 
 type InterfaceHandle = Arc<Foobar>;
@@ -47,127 +78,616 @@ impl Drop for Interface {
     }
 }
 
+/// How long the packet loop waits for a packet before ticking connections anyway.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Local ports handed out to active opens (RFC 6335 S6).
+const EPHEMERAL_PORTS: (u16, u16) = (49152, 65535);
+
+/// Most packets the packet loop takes from the nic before handling them.
+const MAX_BATCH: usize = 64;
+
 #[derive(Default)]
 struct ConnectionManager {
     terminate: bool,
+    config: tcp::ConnectionConfig,
+    /// refuse new connections once there are this many
+    max_connections: Option<usize>,
+    /// refuse new connections on a listener once this many are waiting to be accepted
+    backlog: Option<usize>,
+    /// the application has asked us to turn away new connections for now
+    refusing: bool,
+    overload_policy: OverloadPolicy,
+    /// ssthresh learned per peer, if connections should share what they learn about a path
+    ssthresh_cache: Option<HashMap<Ipv4Addr, u32>>,
     connections: HashMap<Quad, tcp::Connection>,
-    pending: HashMap<u16, VecDeque<Quad>>,
+    /// connections the application has a handle on: a stream, or a connect waiting for its
+    /// handshake. these are kept around once closed, until the application has heard how.
+    held: HashSet<Quad>,
+    pending: HashMap<u16, Vec<Listener>>,
+    next_listener: usize,
+    /// active opens waiting for the packet loop to send their SYN
+    connecting: VecDeque<(Quad, tcp::ConnectionConfig)>,
+    /// the tun device went away and the packet loop has stopped
+    device_gone: bool,
+}
+
+impl ConnectionManager {
+    /// Fails if there is no device left to send or receive on.
+    fn check_device(&self) -> io::Result<()> {
+        if self.device_gone {
+            let reason = ResetReason::DeviceError;
+            return Err(io::Error::new(reason.kind(), reason));
+        }
+        Ok(())
+    }
+
+    /// The connections that need servicing right now: ones with something to read or room to
+    /// write, and ones with a timer that is due.
+    fn poll(&self) -> Vec<(Quad, tcp::Available)> {
+        let now = self.config.now();
+        self.connections
+            .iter()
+            .filter_map(|(&q, c)| {
+                let mut ready = tcp::Available::empty();
+                if c.is_readable() {
+                    ready |= tcp::Available::READ;
+                }
+                if c.is_writable() {
+                    ready |= tcp::Available::WRITE;
+                }
+                if c.next_timeout().is_some_and(|t| t <= now) {
+                    ready |= tcp::Available::TIMER;
+                }
+                Some((q, ready)).filter(|(_, ready)| !ready.is_empty())
+            })
+            .collect()
+    }
+
+    /// Stops accepting new connections, and starts closing every one that is open. Returns how
+    /// many connections haven't finished closing yet.
+    fn shutdown_all(&mut self) -> usize {
+        self.refusing = true;
+        let mut outstanding = self.connecting.len();
+        for c in self.connections.values_mut() {
+            if c.is_done() {
+                continue;
+            }
+            outstanding += 1;
+            // this fails in SYN-SENT, where there's nothing to close yet. a later call gets to it
+            // once the handshake is done.
+            c.close().ok();
+        }
+        outstanding
+    }
+
+    /// The earliest timer deadline across all connections.
+    fn next_timeout(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .filter_map(|c| c.next_timeout())
+            .min()
+    }
+}
+
+/// Seeds a new connection to `peer` with what earlier connections learned, if connections share
+/// what they learn about a path at all.
+///
+/// This only takes the cache, rather than the whole manager, so that new connections can be
+/// seeded while the manager's other maps are borrowed.
+fn seed(ssthresh_cache: &Option<HashMap<Ipv4Addr, u32>>, c: &mut tcp::Connection, peer: Ipv4Addr) {
+    if let Some(&ssthresh) = ssthresh_cache.as_ref().and_then(|cache| cache.get(&peer)) {
+        c.inherit_ssthresh(ssthresh);
+    }
+}
+
+/// What to do with a SYN that arrives while the interface isn't accepting connections (see
+/// [`Interface::set_accepting`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Ignore the SYN, so that the client tries again a little later.
+    #[default]
+    Drop,
+    /// Answer the SYN with a RST, so that the client gives up straight away.
+    Reset,
+}
+
+/// A bound [`TcpListener`], along with the connections it has yet to accept.
+struct Listener {
+    id: usize,
+    /// the local address this listener accepts connections on, or `None` for any of them
+    local: Option<Ipv4Addr>,
+    /// source networks (address, prefix length) this listener accepts SYNs from, or `None` for
+    /// any source
+    allow: Option<Vec<(Ipv4Addr, u8)>>,
+    pending: VecDeque<Quad>,
+}
+
+impl Listener {
+    /// Whether this listener takes connections to `addr`.
+    fn listens_on(&self, addr: Ipv4Addr) -> bool {
+        self.local.is_none_or(|local| local == addr)
+    }
+
+    fn allows(&self, addr: Ipv4Addr) -> bool {
+        let allow = match self.allow {
+            Some(ref allow) => allow,
+            None => return true,
+        };
+        allow.iter().any(|&(net, len)| {
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(net) & mask
+        })
+    }
+}
+
+/// Whether an error from the nic means the tun device itself is gone, rather than one packet
+/// failing.
+fn is_device_gone(e: &io::Error) -> bool {
+    use nix::errno::Errno;
+    e.raw_os_error().is_some_and(|errno| {
+        [Errno::ENODEV, Errno::ENXIO, Errno::EIO, Errno::EBADFD]
+            .iter()
+            .any(|&gone| gone as i32 == errno)
+    })
+}
+
+/// Runs the packet loop, and if the tun device disappears from under it, aborts every connection
+/// so that nothing waits on it forever.
+fn run_packet_loop(nic: Nic, ih: InterfaceHandle) -> io::Result<()> {
+    match packet_loop(nic, ih.clone()) {
+        Err(e) if is_device_gone(&e) => {
+            eprintln!("tun device gone ({}); aborting all connections", e);
+            on_device_gone(&ih);
+            Ok(())
+        }
+        result => result,
+    }
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
+/// Aborts every connection for want of a device, and wakes up everyone waiting on one.
+fn on_device_gone(ih: &InterfaceHandle) {
+    let mut cm = ih.manager.lock().unwrap();
+    cm.device_gone = true;
+    cm.connecting.clear();
+    for c in cm.connections.values_mut() {
+        c.abort(ResetReason::DeviceError);
+    }
+    drop(cm);
+    ih.pending_var.notify_all();
+    ih.rcv_var.notify_all();
+    ih.snd_var.notify_all();
+}
+
+fn packet_loop(mut nic: Nic, ih: InterfaceHandle) -> io::Result<()> {
     let mut buf = [0u8; 1504];
 
     loop {
+        {
+            let mut cmg = ih.manager.lock().unwrap();
+            if cmg.terminate && Arc::strong_count(&ih) == 1 {
+                // the interface and every listener and stream are gone, so nothing can use the
+                // remaining connections any more. let the peers know rather than leave them
+                // hanging.
+                for (_, mut c) in cmg.connections.drain() {
+                    c.teardown(&mut nic)?;
+                }
+                return Ok(());
+            }
+
+            start_connecting(&mut nic, &mut cmg)?;
+        }
+
         // we want to read from nic, but we want to make sure that we'll wake up when the next
         // timer has to be triggered!
+        let timeout = {
+            let cm = ih.manager.lock().unwrap();
+            let now = cm.config.now();
+            cm.next_timeout().map_or(TICK_INTERVAL, |t| {
+                std::cmp::min(TICK_INTERVAL, t.saturating_duration_since(now))
+            })
+        };
         let mut pfd = [PollFd::new(nic.as_raw_fd(), EventFlags::POLLIN)];
-        let n = poll(&mut pfd[..], 10).map_err(|e| e.as_errno().unwrap())?;
+        let n =
+            poll(&mut pfd[..], timeout.as_millis() as i32).map_err(|e| e.as_errno().unwrap())?;
         assert_ne!(n, -1);
         if n == 0 {
-            let mut cmg = ih.manager.lock().unwrap();
-            for connection in cmg.connections.values_mut() {
-                connection.on_tick(&mut nic)?;
-            }
+            on_tick(&mut nic, &ih)?;
             continue;
         }
         assert_eq!(n, 1);
-        let nbytes = nic.recv(&mut buf[..])?;
-
-        // TODO: if self.terminate && Arc::get_strong_refs(ih) == 1; the tear down all connections
-        // and return
-
-        // if s/without_packet_info/new/:
-        //
-        // let _eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
-        // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
-        // if eth_proto != 0x800 {
-        //      // not ipv4
-        //     continue;
-        // }
-        //
-        // and also include on send
-
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            Ok(iph) => {
-                let src = iph.source_addr();
-                let dst = iph.destination_addr();
-
-                if iph.protocol() != 0x06 {
-                    eprintln!("BAD PROTOCOL");
-                    // not tcp
-                    continue;
+
+        // take everything that's already waiting, so that segments for the same connection can
+        // be handled (and ACKed) together
+        let mut packets = Vec::new();
+        loop {
+            let nbytes = nic.recv(&mut buf[..])?;
+            packets.push(buf[..nbytes].to_vec());
+            if packets.len() == MAX_BATCH
+                || poll(&mut pfd[..], 0).map_err(|e| e.as_errno().unwrap())? == 0
+            {
+                break;
+            }
+        }
+
+        let mut batches = HashMap::new();
+        for packet in &packets {
+            on_ip_packet(&mut nic, &ih, packet, &mut batches)?;
+        }
+
+        for (q, segments) in batches {
+            on_segments(&mut nic, &ih, q, &segments)?;
+        }
+    }
+}
+
+/// Sends the SYN of every active open waiting for one.
+fn start_connecting(nic: &mut Nic, cm: &mut ConnectionManager) -> io::Result<()> {
+    for (q, config) in std::mem::take(&mut cm.connecting) {
+        let local = SocketAddrV4::new(q.dst.0, q.dst.1);
+        let remote = SocketAddrV4::new(q.src.0, q.src.1);
+        let mut c = tcp::Connection::connect(nic, local, remote, config)?;
+        seed(&cm.ssthresh_cache, &mut c, q.src.0);
+        cm.connections.insert(q, c);
+    }
+    Ok(())
+}
+
+/// Hands a batch of segments to the connection they are for.
+fn on_segments(
+    nic: &mut Nic,
+    ih: &InterfaceHandle,
+    q: Quad,
+    segments: &[tcp::Segment<'_>],
+) -> io::Result<()> {
+    let mut cmg = ih.manager.lock().unwrap();
+    let cm = &mut *cmg;
+    let c = cm
+        .connections
+        .get_mut(&q)
+        .expect("connection went away while batching its segments");
+    eprintln!("got {} packet(s) for known quad: {:?}", segments.len(), q);
+    let was_connecting = c.is_connecting();
+    let a = c.on_packets(nic, segments)?;
+    let connected = was_connecting && !c.is_connecting();
+    if let (Some(cache), Some(ssthresh)) = (&mut cm.ssthresh_cache, c.learned_ssthresh()) {
+        cache.insert(q.src.0, ssthresh);
+    }
+
+    // TODO: compare before/after
+    drop(cmg);
+    if connected {
+        ih.pending_var.notify_all();
+    }
+    if a.contains(tcp::Available::READ) {
+        ih.rcv_var.notify_all();
+    }
+
+    if a.contains(tcp::Available::WRITE) {
+        ih.snd_var.notify_all();
+    }
+    Ok(())
+}
+
+/// Runs every connection's timers, and forgets the connections that have closed once nothing
+/// holds on to them any more.
+fn on_tick(nic: &mut Nic, ih: &InterfaceHandle) -> io::Result<()> {
+    let mut cmg = ih.manager.lock().unwrap();
+    let cm = &mut *cmg;
+    let mut readable = false;
+    let mut writable = false;
+    let mut gave_up = false;
+    let mut done = Vec::new();
+    for (&q, connection) in cm.connections.iter_mut() {
+        let was_readable = connection.is_readable();
+        let was_writable = connection.is_writable();
+        let was_connecting = connection.is_connecting();
+        if connection.on_tick(nic)? && !cm.held.contains(&q) {
+            done.push(q);
+        }
+        readable |= !was_readable && connection.is_readable();
+        writable |= !was_writable && connection.is_writable();
+        gave_up |= was_connecting && !connection.is_connecting();
+    }
+    for q in done {
+        // closed, whether it went through TIME-WAIT or was reset or timed out, and nobody is
+        // left to tell. that includes connections that closed before they could be accepted.
+        cm.connections.remove(&q);
+        for listener in cm.pending.values_mut().flatten() {
+            listener.pending.retain(|&p| p != q);
+        }
+    }
+    drop(cmg);
+    if gave_up {
+        // a connect waiting on a handshake that timed out
+        ih.pending_var.notify_all();
+    }
+    if readable {
+        ih.rcv_var.notify_all();
+    }
+    if writable {
+        ih.snd_var.notify_all();
+    }
+    Ok(())
+}
+
+/// Handles one packet from the nic. Segments for connections we already have are added to
+/// `batches` rather than handled straight away.
+fn on_ip_packet<'a>(
+    nic: &mut Nic,
+    ih: &InterfaceHandle,
+    buf: &'a [u8],
+    batches: &mut HashMap<Quad, Vec<tcp::Segment<'a>>>,
+) -> io::Result<()> {
+    let nbytes = buf.len();
+
+    // if s/without_packet_info/new/:
+    //
+    // let _eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
+    // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
+    // if eth_proto != 0x800 {
+    //      // not ipv4
+    //     return Ok(());
+    // }
+    //
+    // and also include on send
+
+    match etherparse::Ipv4HeaderSlice::from_slice(buf) {
+        Ok(iph) => {
+            // the kernel won't have checked this for us on a tun device
+            match iph.to_header().calc_header_checksum() {
+                Ok(checksum) if checksum == iph.header_checksum() => {}
+                _ => {
+                    eprintln!("Ignoring packet with bad ip header checksum");
+                    return Ok(());
                 }
+            }
 
-                match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
-                    Ok(tcph) => {
-                        let datai = iph.slice().len() + tcph.slice().len();
-                        let mut cmg = ih.manager.lock().unwrap();
-                        let cm = &mut *cmg;
-                        let q = Quad {
-                            src: (src, tcph.source_port()),
-                            dst: (dst, tcph.destination_port()),
-                        };
-                        match cm.connections.entry(q) {
-                            Entry::Occupied(mut c) => {
-                                eprintln!("got packet for known quad: {:?}", q);
-                                let a = c.get_mut().on_packet(
-                                    &mut nic,
-                                    iph,
-                                    tcph,
-                                    &buf[datai..nbytes],
-                                )?;
-
-                                // TODO: compare before/after
-                                drop(cmg);
-                                if a.contains(tcp::Available::READ) {
-                                    ih.rcv_var.notify_all();
-                                }
+            // the packet ends where its ip header says it does, not where the read did. that
+            // way the tcp header parse below also catches a data offset that runs past the end
+            // of the segment.
+            let total_len = iph.total_len() as usize;
+            if total_len < iph.slice().len() || total_len > nbytes {
+                eprintln!(
+                    "Ignoring packet with bad ip total length {} (got {}b)",
+                    total_len, nbytes
+                );
+                return Ok(());
+            }
+            let nbytes = total_len;
 
-                                if a.contains(tcp::Available::WRITE) {
-                                    // TODO:  ih.snd_var.notify_all();
-                                }
-                            }
-                            Entry::Vacant(e) => {
-                                eprintln!("got packet for unknown quad {:?}", q);
-                                if let Some(pending) = cm.pending.get_mut(&tcph.destination_port())
-                                {
+            let src = iph.source_addr();
+            let dst = iph.destination_addr();
+
+            if iph.protocol() == 0x01 {
+                let mut cmg = ih.manager.lock().unwrap();
+                if on_icmp(nic, &mut cmg, &buf[iph.slice().len()..nbytes])? {
+                    drop(cmg);
+                    ih.pending_var.notify_all();
+                    ih.rcv_var.notify_all();
+                    ih.snd_var.notify_all();
+                }
+                return Ok(());
+            }
+
+            if iph.protocol() != 0x06 {
+                eprintln!("BAD PROTOCOL");
+                // not tcp
+                return Ok(());
+            }
+
+            // the ip header's slice runs to its IHL, so any ip options are already skipped over
+            match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
+                Ok(tcph) => {
+                    // the payload starts after the whole tcp header, options and all, and ends
+                    // where the ip total length says, not where the read did
+                    debug_assert_eq!(tcph.slice().len(), tcph.data_offset() as usize * 4);
+                    let datai = iph.slice().len() + tcph.slice().len();
+                    let mut cmg = ih.manager.lock().unwrap();
+                    let cm = &mut *cmg;
+                    let q = Quad {
+                        src: (src, tcph.source_port()),
+                        dst: (dst, tcph.destination_port()),
+                    };
+                    // connections that have closed are only waiting to be reaped
+                    let at_capacity = !cm.connections.contains_key(&q)
+                        && cm.max_connections.is_some_and(|max| {
+                            cm.connections.values().filter(|c| !c.is_closed()).count() >= max
+                        });
+                    match cm.connections.entry(q) {
+                        Entry::Occupied(_) => {
+                            batches.entry(q).or_default().push(tcp::Segment {
+                                iph,
+                                tcph,
+                                data: &buf[datai..nbytes],
+                            });
+                        }
+                        Entry::Vacant(e) => {
+                            eprintln!("got packet for unknown quad {:?}", q);
+                            if let Some(listeners) = cm.pending.get_mut(&tcph.destination_port()) {
+                                let listener = listeners
+                                    .iter_mut()
+                                    .find(|l| l.listens_on(dst) && l.allows(src));
+                                if let Some(listener) = listener {
+                                    if !tcph.syn() {
+                                        // nothing that could start a connection, so however
+                                        // full we are, it's answered as in LISTEN: an ACK
+                                        // can't be for anything we know of (RFC 793 S3.9)
+                                        if tcph.ack() {
+                                            tcp::reset_segment(nic, &iph, &tcph, nbytes - datai)?;
+                                        }
+                                        return Ok(());
+                                    }
+                                    if cm.refusing {
+                                        eprintln!(
+                                            "not accepting connections; turning away {}",
+                                            src
+                                        );
+                                        if cm.overload_policy == OverloadPolicy::Reset {
+                                            tcp::reset_segment(nic, &iph, &tcph, nbytes - datai)?;
+                                        }
+                                        return Ok(());
+                                    }
+                                    let backlog_full =
+                                        cm.backlog.is_some_and(|max| listener.pending.len() >= max);
+                                    if at_capacity || backlog_full {
+                                        eprintln!("too many connections; refusing {}", src);
+                                        tcp::reset_segment(nic, &iph, &tcph, nbytes - datai)?;
+                                        return Ok(());
+                                    }
                                     eprintln!("listening, so accepting");
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &mut nic,
+                                    if let Some(mut c) = tcp::Connection::accept(
+                                        nic,
                                         iph,
                                         tcph,
                                         &buf[datai..nbytes],
+                                        cm.config.clone(),
                                     )? {
+                                        seed(&cm.ssthresh_cache, &mut c, src);
                                         e.insert(c);
-                                        pending.push_back(q);
+                                        listener.pending.push_back(q);
                                         drop(cmg);
                                         ih.pending_var.notify_all();
                                     }
+                                } else if tcph.syn() {
+                                    eprintln!("{} is not allowed to connect; resetting", src);
+                                    tcp::reset_segment(nic, &iph, &tcph, nbytes - datai)?;
+                                } else if tcph.ack() {
+                                    // as in LISTEN, an ACK can't be for anything we know of
+                                    // (RFC 793 S3.9): <SEQ=SEG.ACK><CTL=RST>
+                                    tcp::reset_segment(nic, &iph, &tcph, nbytes - datai)?;
                                 }
+                            } else {
+                                // nobody is listening, so as far as this port goes everything is
+                                // CLOSED (RFC 793 S3.9). most likely the peer still has a
+                                // connection we lost track of, say by restarting, and the RST
+                                // tells it so straight away rather than after it times out.
+                                tcp::reset_segment(nic, &iph, &tcph, nbytes - datai)?;
                             }
                         }
                     }
-                    Err(e) => {
-                        eprintln!("Ignoring weird packet {:?}", e);
-                    }
+                }
+                Err(etherparse::ReadError::TcpDataOffsetTooSmall(offset)) => {
+                    eprintln!("Ignoring segment with data offset {} < 5", offset);
+                }
+                Err(etherparse::ReadError::UnexpectedEndOfSlice(len)) => {
+                    eprintln!("Ignoring segment whose {}b header overruns the packet", len);
+                }
+                Err(e) => {
+                    eprintln!("Ignoring weird packet {:?}", e);
                 }
             }
-            Err(_e) => {
-                // eprintln!("Ignoring weird packet {:?}", e);
-            }
+        }
+        Err(_e) => {
+            // eprintln!("Ignoring weird packet {:?}", e);
         }
     }
+    Ok(())
 }
 
-impl Interface {
-    pub fn new() -> io::Result<Self> {
-        let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
+/// Hands an ICMP error to the connection whose segment triggered it. Returns whether that
+/// aborted the connection.
+fn on_icmp(nic: &mut Nic, cm: &mut ConnectionManager, icmp: &[u8]) -> io::Result<bool> {
+    // type, code, checksum, 4 bytes of type-specific data, and then the header of the offending
+    // ip packet followed by (at least) the first 8 bytes of its tcp header
+    if icmp.len() < 8 {
+        return Ok(false);
+    }
+    let (icmp_type, icmp_code) = (icmp[0], icmp[1]);
+    let next_hop_mtu = u16::from_be_bytes([icmp[6], icmp[7]]);
+
+    let orig = match etherparse::Ipv4HeaderSlice::from_slice(&icmp[8..]) {
+        Ok(orig) if orig.protocol() == 0x06 => orig,
+        _ => return Ok(false),
+    };
+    // source and destination port, then the sequence number
+    let quoted = &icmp[8 + orig.slice().len()..];
+    if quoted.len() < 8 {
+        return Ok(false);
+    }
+    let ports = &quoted[..4];
+    let seq = u32::from_be_bytes([quoted[4], quoted[5], quoted[6], quoted[7]]);
+
+    // the offending segment was one of ours, so it's going the opposite way to our quads
+    let q = Quad {
+        src: (
+            orig.destination_addr(),
+            u16::from_be_bytes([ports[2], ports[3]]),
+        ),
+        dst: (orig.source_addr(), u16::from_be_bytes([ports[0], ports[1]])),
+    };
+    if let Some(c) = cm.connections.get_mut(&q) {
+        eprintln!("icmp type {} code {} for {:?}", icmp_type, icmp_code, q);
+        c.on_icmp_error(nic, icmp_type, icmp_code, next_hop_mtu, seq)?;
+        return Ok(c.error().is_some());
+    }
+    Ok(false)
+}
+
+/// Sets up an [`Interface`] with something other than the defaults.
+///
+/// By default there is no limit on the number of connections.
+#[derive(Debug, Default)]
+pub struct InterfaceBuilder {
+    config: ConnectionConfig,
+    max_connections: Option<usize>,
+    backlog: Option<usize>,
+    overload_policy: OverloadPolicy,
+    remember_ssthresh: bool,
+}
+
+impl InterfaceBuilder {
+    /// The configuration every accepted connection uses.
+    pub fn config(mut self, config: ConnectionConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Refuse incoming connections with a RST while there are `max` connections already, in any
+    /// state and on any port.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Refuse incoming connections with a RST while `max` connections are waiting to be accepted
+    /// on the listener they would go to.
+    pub fn backlog(mut self, max: usize) -> Self {
+        self.backlog = Some(max);
+        self
+    }
+
+    /// How to turn away connections while the interface isn't accepting them. Defaults to
+    /// [`OverloadPolicy::Drop`].
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> Self {
+        self.overload_policy = policy;
+        self
+    }
+
+    /// Have new connections start with the ssthresh that earlier connections to the same peer
+    /// ended up with, so that they don't overshoot in slow start (RFC 2140).
+    pub fn remember_ssthresh(mut self, remember: bool) -> Self {
+        self.remember_ssthresh = remember;
+        self
+    }
+
+    pub fn build(self) -> io::Result<Interface> {
+        let iface = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
 
         let ih: InterfaceHandle = Arc::default();
+        let nic = Nic::new(iface, ih.capture.clone());
+        {
+            let mut cm = ih.manager.lock().unwrap();
+            cm.config = self.config;
+            cm.max_connections = self.max_connections;
+            cm.backlog = self.backlog;
+            cm.overload_policy = self.overload_policy;
+            cm.ssthresh_cache = self.remember_ssthresh.then(HashMap::new);
+        }
 
         let jh = {
             let ih = ih.clone();
-            thread::spawn(move || packet_loop(nic, ih))
+            thread::spawn(move || run_packet_loop(nic, ih))
         };
 
         Ok(Interface {
@@ -175,43 +695,296 @@ impl Interface {
             jh: Some(jh),
         })
     }
+}
+
+impl Interface {
+    pub fn new() -> io::Result<Self> {
+        Self::builder().build()
+    }
+
+    /// Like [`Interface::new`], but every accepted connection uses `config`.
+    pub fn with_config(config: ConnectionConfig) -> io::Result<Self> {
+        Self::builder().config(config).build()
+    }
+
+    pub fn builder() -> InterfaceBuilder {
+        InterfaceBuilder::default()
+    }
 
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
+        self.bind_listener(port, None, None)
+    }
+
+    /// Like [`Interface::bind`], but only accepts connections to one local address, for when
+    /// several addresses are routed to the tun device.
+    pub fn bind_to(&mut self, addr: SocketAddrV4) -> io::Result<TcpListener> {
+        self.bind_listener(addr.port(), Some(*addr.ip()), None)
+    }
+
+    /// Like [`Interface::bind`], but only accepts connections from the given source networks,
+    /// each given as an address and a prefix length. SYNs from anywhere else are reset.
+    ///
+    /// Several filtered listeners can share a port; a SYN goes to the first one that allows it.
+    pub fn bind_filtered(
+        &mut self,
+        port: u16,
+        allow: &[(Ipv4Addr, u8)],
+    ) -> io::Result<TcpListener> {
+        if let Some(&(net, len)) = allow.iter().find(|&&(_, len)| len > 32) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid prefix {}/{}", net, len),
+            ));
+        }
+        self.bind_listener(port, None, Some(allow.to_vec()))
+    }
+
+    fn bind_listener(
+        &mut self,
+        port: u16,
+        local: Option<Ipv4Addr>,
+        allow: Option<Vec<(Ipv4Addr, u8)>>,
+    ) -> io::Result<TcpListener> {
         let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
-        match cm.pending.entry(port) {
-            Entry::Vacant(v) => {
-                v.insert(VecDeque::new());
-            }
-            Entry::Occupied(_) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::AddrInUse,
-                    "port already bound",
-                ));
-            }
+        let id = cm.next_listener;
+        cm.next_listener += 1;
+        let listeners = cm.pending.entry(port).or_default();
+        // an unfiltered listener takes every SYN to its address, so it can't share its port with
+        // anything else on that address
+        let overlaps = |l: &Listener| l.local.is_none() || local.is_none() || l.local == local;
+        if listeners
+            .iter()
+            .any(|l| overlaps(l) && (l.allow.is_none() || allow.is_none()))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "port already bound",
+            ));
         }
+        listeners.push(Listener {
+            id,
+            local,
+            allow,
+            pending: VecDeque::new(),
+        });
         drop(cm);
         Ok(TcpListener {
             port,
+            id,
             h: self.ih.as_mut().unwrap().clone(),
         })
     }
+
+    /// Has `capture` called with every packet the interface sends or receives, for example to
+    /// write them to a pcap file, or to print a [`segment_trace`]. Replaces any earlier capture;
+    /// `None` stops capturing.
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        *self.ih.as_ref().unwrap().capture.lock().unwrap() = capture;
+    }
+
+    /// Stops (or resumes) accepting new connections on every listener, for shedding load.
+    ///
+    /// SYNs are turned away according to the [`OverloadPolicy`] the interface was built with.
+    /// Connections that are already there carry on as normal.
+    pub fn set_accepting(&mut self, accepting: bool) {
+        let mut cm = self.ih.as_ref().unwrap().manager.lock().unwrap();
+        cm.refusing = !accepting;
+    }
+
+    /// Waits up to `timeout` for any connection to need servicing, and returns each one that
+    /// does by its local and remote address, along with what it is ready for.
+    ///
+    /// This is for a single thread multiplexing many connections. An empty result means the
+    /// timeout passed with nothing to do.
+    pub fn poll(
+        &self,
+        timeout: Duration,
+    ) -> io::Result<Vec<(SocketAddrV4, SocketAddrV4, Available)>> {
+        let ih = self.ih.as_ref().unwrap();
+        let deadline = Instant::now() + timeout;
+        let mut cm = ih.manager.lock().unwrap();
+        loop {
+            let ready = cm.poll();
+            let now = Instant::now();
+            if !ready.is_empty() || now >= deadline {
+                return Ok(ready
+                    .into_iter()
+                    .map(|(q, ready)| {
+                        (
+                            SocketAddrV4::new(q.dst.0, q.dst.1),
+                            SocketAddrV4::new(q.src.0, q.src.1),
+                            ready,
+                        )
+                    })
+                    .collect());
+            }
+            // readers are woken when data arrives, but writers and timers aren't, so check
+            // again at least every tick
+            let wait = std::cmp::min(deadline - now, TICK_INTERVAL);
+            cm = ih.rcv_var.wait_timeout(cm, wait).unwrap().0;
+        }
+    }
+
+    /// Gracefully closes every connection and stops accepting new ones, for shutting down: waits
+    /// up to `timeout` for all of them to get to CLOSED or TIME-WAIT.
+    ///
+    /// Returns how many connections were still closing when the timeout passed, so zero means
+    /// it's safe to exit.
+    pub fn shutdown_all(&mut self, timeout: Duration) -> io::Result<usize> {
+        let ih = self.ih.as_ref().unwrap();
+        let deadline = Instant::now() + timeout;
+        let mut cm = ih.manager.lock().unwrap();
+        loop {
+            let outstanding = cm.shutdown_all();
+            let now = Instant::now();
+            if outstanding == 0 || now >= deadline {
+                return Ok(outstanding);
+            }
+            let wait = std::cmp::min(deadline - now, TICK_INTERVAL);
+            cm = ih.rcv_var.wait_timeout(cm, wait).unwrap().0;
+        }
+    }
+
+    /// Opens a connection to `remote` from an ephemeral port on `local`, and blocks until the
+    /// handshake is done.
+    ///
+    /// `local` has to be an address that the tun device's routes send back to us.
+    pub fn connect(&mut self, local: Ipv4Addr, remote: SocketAddrV4) -> io::Result<TcpStream> {
+        let h = self.ih.as_ref().unwrap().clone();
+        let mut cm = h.manager.lock().unwrap();
+        cm.check_device()?;
+
+        let src = (*remote.ip(), remote.port());
+        let port = (EPHEMERAL_PORTS.0..=EPHEMERAL_PORTS.1)
+            .find(|&port| {
+                let quad = Quad {
+                    src,
+                    dst: (local, port),
+                };
+                !cm.pending.contains_key(&port)
+                    && !cm.connections.contains_key(&quad)
+                    && !cm.connecting.iter().any(|&(q, _)| q == quad)
+            })
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "out of ephemeral ports")
+            })?;
+        let quad = Quad {
+            src,
+            dst: (local, port),
+        };
+
+        // only the packet loop has the nic, so leave sending the SYN to it
+        let config = cm.config.clone();
+        cm.connecting.push_back((quad, config));
+        cm.held.insert(quad);
+        loop {
+            match cm.connections.get(&quad) {
+                Some(c) if c.is_connecting() => {}
+                Some(c) if c.is_closed() => {
+                    let err = c.error().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused")
+                    });
+                    cm.connections.remove(&quad);
+                    cm.held.remove(&quad);
+                    return Err(err);
+                }
+                Some(_) => return Ok(TcpStream::new(quad, h.clone())),
+                None => {
+                    if let Err(e) = cm.check_device() {
+                        cm.held.remove(&quad);
+                        return Err(e);
+                    }
+                }
+            }
+            cm = h.pending_var.wait(cm).unwrap();
+        }
+    }
+
+    /// Puts a connection from `local` to `remote` straight into `state`, with `iss` as our ISN
+    /// and `irs` as the peer's, so that a test can start from there without driving a handshake.
+    ///
+    /// Nothing is sent; the peer is assumed to be in the matching state already.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn insert_fixture(
+        &mut self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        state: State,
+        iss: u32,
+        irs: u32,
+    ) -> io::Result<TcpStream> {
+        let h = self.ih.as_ref().unwrap();
+        let mut cm = h.manager.lock().unwrap();
+        let quad = Quad {
+            src: (*remote.ip(), remote.port()),
+            dst: (*local.ip(), local.port()),
+        };
+        let config = cm.config.clone();
+        match cm.connections.entry(quad) {
+            Entry::Occupied(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "connection already exists",
+            )),
+            Entry::Vacant(v) => {
+                v.insert(tcp::Connection::fixture(
+                    local, remote, state, config, iss, irs,
+                ));
+                cm.held.insert(quad);
+                Ok(TcpStream::new(quad, h.clone()))
+            }
+        }
+    }
+
+    /// Picks up a connection from a [`Snapshot`], which may have been taken on another
+    /// interface.
+    ///
+    /// The peer won't notice anything as long as its packets now arrive here.
+    pub fn restore(&mut self, snapshot: Snapshot) -> io::Result<TcpStream> {
+        let h = self.ih.as_ref().unwrap();
+        let mut cm = h.manager.lock().unwrap();
+        let (src, dst) = snapshot.endpoints();
+        let quad = Quad { src, dst };
+        match cm.connections.entry(quad) {
+            Entry::Occupied(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "connection already exists",
+            )),
+            Entry::Vacant(v) => {
+                v.insert(tcp::Connection::restore(snapshot));
+                cm.held.insert(quad);
+                Ok(TcpStream::new(quad, h.clone()))
+            }
+        }
+    }
 }
 
 pub struct TcpListener {
     port: u16,
+    id: usize,
     h: InterfaceHandle,
 }
 
 impl Drop for TcpListener {
     fn drop(&mut self) {
         let mut cm = self.h.manager.lock().unwrap();
-        let pending = cm
+        let listeners = cm
             .pending
-            .remove(&self.port)
+            .get_mut(&self.port)
             .expect("port closed while listener still active");
-        for _quad in pending {
-            // TODO: terminate cm.connections[quad]
-            unimplemented!();
+        let i = listeners
+            .iter()
+            .position(|l| l.id == self.id)
+            .expect("listener closed while still active");
+        let listener = listeners.remove(i);
+        if listeners.is_empty() {
+            cm.pending.remove(&self.port);
+        }
+        // nobody is left to accept these. only the packet loop has the nic, so have its next
+        // tick reset them.
+        for q in listener.pending {
+            if let Some(c) = cm.connections.get_mut(&q) {
+                c.abort_discard();
+            }
         }
     }
 }
@@ -223,14 +996,15 @@ impl TcpListener {
             if let Some(quad) = cm
                 .pending
                 .get_mut(&self.port)
+                .and_then(|ls| ls.iter_mut().find(|l| l.id == self.id))
                 .expect("port closed while listener still active")
+                .pending
                 .pop_front()
             {
-                return Ok(TcpStream {
-                    quad,
-                    h: self.h.clone(),
-                });
+                cm.held.insert(quad);
+                return Ok(TcpStream::new(quad, self.h.clone()));
             }
+            cm.check_device()?;
 
             cm = self.h.pending_var.wait(cm).unwrap();
         }
@@ -240,16 +1014,16 @@ impl TcpListener {
 pub struct TcpStream {
     quad: Quad,
     h: InterfaceHandle,
+    /// return `WouldBlock` rather than waiting for data or send space
+    nonblocking: AtomicBool,
 }
 
 impl Drop for TcpStream {
     fn drop(&mut self) {
-        let cm = self.h.manager.lock().unwrap();
+        let mut cm = self.h.manager.lock().unwrap();
         // TODO: send FIN on cm.connections[quad]
-        // TODO: _eventually_ remove self.quad from cm.connections
-        // if let Some(_c) = cm.connections.remove(&self.quad) {
-        // unimplemented!();
-        // };
+        // once the connection has closed, the packet loop forgets about it
+        cm.held.remove(&self.quad);
     }
 }
 
@@ -264,29 +1038,25 @@ impl Read for TcpStream {
                 )
             })?;
 
-            if c.is_rcv_closed() && c.incoming.is_empty() {
-                // no more data to read, and no need to block, because there won't be any more
-                return Ok(0);
+            if c.is_readable() {
+                // once receiving is closed, this returns 0 when there's nothing left, rather
+                // than blocking, because there won't be any more. if the connection was aborted,
+                // what was received before that is handed over first, and then the error.
+                let n = c.read(buf);
+                if n == 0 && !buf.is_empty() {
+                    if let Some(err) = c.error() {
+                        return Err(err);
+                    }
+                }
+                return Ok(n);
             }
 
-            if !c.incoming.is_empty() {
-                let mut nread = 0;
-                let (head, tail) = c.incoming.as_slices();
-                let hread = std::cmp::min(buf.len(), head.len());
-                buf[..hread].copy_from_slice(&head[..hread]);
-                nread += hread;
-
-                let tread = std::cmp::min(buf.len() - nread, tail.len());
-                buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
-                nread += tread;
-                let thread = std::cmp::min(buf.len() - nread, tail.len());
-                buf[hread..(hread + thread)].copy_from_slice(&tail[..thread]);
-                nread += thread;
-                drop(c.incoming.drain(..nread));
-
-                return Ok(nread);
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no data received yet",
+                ));
             }
-
             cm = self.h.rcv_var.wait(cm).unwrap();
         }
     }
@@ -294,6 +1064,93 @@ impl Read for TcpStream {
 
 impl Write for TcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.with_send_space(|c| {
+            let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
+            c.unacked.extend(&mut buf[..nwrite].iter());
+            nwrite
+        })
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Ok(0);
+        }
+        self.with_send_space(|c| {
+            // the slices go straight into the send queue; on_tick segments it without caring
+            // where one slice ended and the next began
+            let mut nwrite = 0;
+            for buf in bufs {
+                let n = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
+                c.unacked.extend(&buf[..n]);
+                nwrite += n;
+                if n < buf.len() {
+                    break;
+                }
+            }
+            nwrite
+        })
+    }
+
+    /// Waits for the peer to acknowledge everything written so far, or fails with `WouldBlock`
+    /// if the stream is non-blocking and it hasn't yet.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "stream was terminated unexpectedly.",
+                )
+            })?;
+
+            if c.unacked.is_empty() {
+                return Ok(());
+            }
+            if let Some(err) = c.error() {
+                return Err(err);
+            }
+            if c.is_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "connection is closed",
+                ));
+            }
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "too many bytes buffered",
+                ));
+            }
+            cm = self.h.snd_var.wait(cm).unwrap();
+        }
+    }
+}
+
+impl TcpStream {
+    fn new(quad: Quad, h: InterfaceHandle) -> Self {
+        TcpStream {
+            quad,
+            h,
+            nonblocking: AtomicBool::new(false),
+        }
+    }
+
+    /// Makes reads and writes return [`io::ErrorKind::WouldBlock`] instead of waiting for data to
+    /// arrive or for the peer to make room in the send queue.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The sequence ranges (`start..end`) of data the peer has sent us past a hole that hasn't
+    /// been filled yet.
+    ///
+    /// Useful for figuring out why a read is stuck even though segments are arriving.
+    pub fn out_of_order_ranges(&self) -> io::Result<Vec<(u32, u32)>> {
         let mut cm = self.h.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
             io::Error::new(
@@ -302,43 +1159,193 @@ impl Write for TcpStream {
             )
         })?;
 
-        if c.unacked.len() >= SENDQUEUE_SIZE {
-            // TODO: block
-            return Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
-            ));
+        Ok(c.out_of_order_ranges())
+    }
+
+    /// The sequence ranges (`start..end`) that are missing from what the peer has sent us, up to
+    /// the furthest data we have received. These are the holes in
+    /// [`TcpStream::out_of_order_ranges`], plus the one in front of them.
+    pub fn missing_ranges(&self) -> io::Result<Vec<(u32, u32)>> {
+        self.with_connection(|c| c.missing_ranges())
+    }
+
+    /// Captures the state of this connection so that it can be carried on with
+    /// [`Interface::restore`].
+    pub fn snapshot(&self) -> io::Result<Snapshot> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "tcp was terminated unexpectedly.",
+            )
+        })?;
+
+        Ok(c.snapshot())
+    }
+
+    /// Like [`Read::read`], except that the data stays in the receive buffer, so the next read
+    /// returns it again.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "tcp was terminated unexpectedly.",
+                )
+            })?;
+
+            if c.is_readable() {
+                return Ok(c.peek(buf));
+            }
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no data received yet",
+                ));
+            }
+            cm = self.h.rcv_var.wait(cm).unwrap();
         }
+    }
 
-        let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
-        c.unacked.extend(&mut buf[..nwrite].iter());
+    /// Has everything written so far go out as soon as the windows allow, without waiting for
+    /// pacing.
+    ///
+    /// Unlike [`Write::flush`], this doesn't wait for the data to be acknowledged.
+    pub fn flush_output(&self) -> io::Result<()> {
+        self.with_connection(|c| c.request_flush())
+    }
 
-        Ok(nwrite)
+    /// Corks (or uncorks) the stream, like `TCP_CORK`: while corked, written data is only sent
+    /// once it fills a whole segment. Uncorking sends what is left straight away.
+    ///
+    /// Unlike Nagle's algorithm, this holds back small segments even when nothing is in flight,
+    /// so a message written in several pieces goes out in as few segments as possible.
+    pub fn set_cork(&self, corked: bool) -> io::Result<()> {
+        self.with_connection(|c| c.set_cork(corked))
     }
 
-    fn flush(&mut self) -> io::Result<()> {
+    /// Changes how many received bytes the connection holds before it closes the window on the
+    /// peer. The default is 1024.
+    ///
+    /// Window the peer has already been offered is never taken back, so the buffer only shrinks
+    /// as the peer's data is read.
+    pub fn resize_recv_buffer(&self, capacity: usize) -> io::Result<()> {
+        self.with_connection(|c| c.resize_recv_buffer(capacity))
+    }
+
+    /// Resets the connection, discarding anything that is still waiting to be sent or read.
+    ///
+    /// Unlike [`TcpStream::shutdown`], the peer is not guaranteed to receive what was written
+    /// before this; it just sees the connection reset.
+    pub fn abort_discard(&self) -> io::Result<()> {
+        self.with_connection(|c| c.abort_discard())
+    }
+
+    /// The most recent round-trip time sample, or `None` if nothing has been acknowledged yet.
+    pub fn last_rtt(&self) -> io::Result<Option<Duration>> {
+        self.with_connection(|c| c.last_rtt())
+    }
+
+    /// The smoothed round-trip time that retransmission timeouts are based on, or `None` if
+    /// nothing has been acknowledged yet.
+    pub fn smoothed_rtt(&self) -> io::Result<Option<Duration>> {
+        self.with_connection(|c| c.smoothed_rtt())
+    }
+
+    /// Every segment in and out and every state change so far, if
+    /// [`ConnectionConfig::event_log`] is on.
+    pub fn event_log(&self) -> io::Result<Vec<LogEntry>> {
+        self.with_connection(|c| c.event_log())
+    }
+
+    /// The options agreed on in the handshake.
+    pub fn negotiated_options(&self) -> io::Result<NegotiatedOptions> {
+        self.with_connection(|c| c.negotiated_options())
+    }
+
+    /// How much data has gone each way, and how fast it has been going lately.
+    pub fn stats(&self) -> io::Result<Stats> {
+        self.with_connection(|c| c.stats())
+    }
+
+    /// Zeroes the counters in [`TcpStream::stats`], for sampling them over an interval.
+    pub fn reset_stats(&self) -> io::Result<()> {
+        self.with_connection(|c| c.reset_stats())
+    }
+
+    /// The congestion window, in bytes.
+    pub fn cwnd(&self) -> io::Result<u32> {
+        self.with_connection(|c| c.cwnd())
+    }
+
+    /// The slow start threshold, in bytes.
+    pub fn ssthresh(&self) -> io::Result<u32> {
+        self.with_connection(|c| c.ssthresh())
+    }
+
+    /// Overrides the congestion window, to put the connection in a known state for a test.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn set_cwnd(&self, cwnd: u32) -> io::Result<()> {
+        self.with_connection(|c| c.set_cwnd(cwnd))
+    }
+
+    /// Overrides the slow start threshold, to put the connection in a known state for a test.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn set_ssthresh(&self, ssthresh: u32) -> io::Result<()> {
+        self.with_connection(|c| c.set_ssthresh(ssthresh))
+    }
+
+    /// Like [`TcpStream::with_connection`], but first waits for there to be room in the send
+    /// queue (or fails with `WouldBlock` if the stream is non-blocking).
+    fn with_send_space<T>(&self, f: impl FnOnce(&mut tcp::Connection) -> T) -> io::Result<T> {
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "tcp was terminated unexpectedly.",
+                )
+            })?;
+
+            if let Some(err) = c.error() {
+                return Err(err);
+            }
+            if c.is_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "connection is closed",
+                ));
+            }
+
+            if c.has_send_room() {
+                return Ok(f(c));
+            }
+
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "too many bytes buffered",
+                ));
+            }
+            cm = self.h.snd_var.wait(cm).unwrap();
+        }
+    }
+
+    fn with_connection<T>(&self, f: impl FnOnce(&mut tcp::Connection) -> T) -> io::Result<T> {
         let mut cm = self.h.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::ConnectionAborted,
-                "stream was terminated unexpectedly.",
+                "tcp was terminated unexpectedly.",
             )
         })?;
 
-        if c.unacked.is_empty() {
-            Ok(())
-        } else {
-            // TODO: block
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
-            ))
-        }
+        Ok(f(c))
     }
-}
 
-impl TcpStream {
-    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+    pub fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
         let mut cm = self.h.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
             io::Error::new(
@@ -350,3 +1357,6 @@ impl TcpStream {
         c.close()
     }
 }
+
+#[cfg(test)]
+mod tests;
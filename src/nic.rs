@@ -0,0 +1,147 @@
+//! The tun device, along with anything that wants to see the packets going through it.
+
+#[cfg(any(test, feature = "testing"))]
+use std::collections::VecDeque;
+use std::{
+    io,
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+};
+
+/// Which way a captured packet was going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// Called with every IP packet sent or received, exactly as it is on the wire.
+pub type Capture = Box<dyn Fn(&[u8], Direction) + Send>;
+
+pub(crate) struct Nic {
+    device: Device,
+    capture: Arc<Mutex<Option<Capture>>>,
+}
+
+enum Device {
+    Tun(tun_tap::Iface),
+    /// no device at all: whatever is sent is kept, for tests to look at, and what they inject
+    /// is what gets received
+    #[cfg(any(test, feature = "testing"))]
+    Mock {
+        sent: Vec<Vec<u8>>,
+        incoming: VecDeque<Vec<u8>>,
+        /// the errno every send and receive fails with, once the "device" has gone
+        gone: Option<i32>,
+    },
+}
+
+impl Nic {
+    pub(crate) fn new(iface: tun_tap::Iface, capture: Arc<Mutex<Option<Capture>>>) -> Self {
+        Nic {
+            device: Device::Tun(iface),
+            capture,
+        }
+    }
+
+    /// A nic that holds on to every packet sent on it, and only receives what is injected.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn mock() -> Self {
+        Nic::mock_capturing(Default::default())
+    }
+
+    /// Like [`Nic::mock`], with packets going through `capture` as they would on a real device.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn mock_capturing(capture: Arc<Mutex<Option<Capture>>>) -> Self {
+        Nic {
+            device: Device::Mock {
+                sent: Vec::new(),
+                incoming: VecDeque::new(),
+                gone: None,
+            },
+            capture,
+        }
+    }
+
+    /// Has a [mock](Nic::mock) nic receive `packet` next.
+    #[cfg(test)]
+    pub(crate) fn inject(&mut self, packet: Vec<u8>) {
+        match self.device {
+            Device::Mock {
+                ref mut incoming, ..
+            } => incoming.push_back(packet),
+            Device::Tun(_) => panic!("only a mock nic can have packets injected"),
+        }
+    }
+
+    /// Has every send and receive on a [mock](Nic::mock) nic fail with `errno` from now on, as
+    /// they would once the tun device is removed.
+    #[cfg(test)]
+    pub(crate) fn remove(&mut self, errno: i32) {
+        match self.device {
+            Device::Mock { ref mut gone, .. } => *gone = Some(errno),
+            Device::Tun(_) => panic!("only a mock nic can be removed"),
+        }
+    }
+
+    /// The packets sent on a [mock](Nic::mock) nic since the last call.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn take_sent(&mut self) -> Vec<Vec<u8>> {
+        match self.device {
+            Device::Mock { ref mut sent, .. } => std::mem::take(sent),
+            Device::Tun(_) => panic!("only a mock nic keeps what it sent"),
+        }
+    }
+
+    pub(crate) fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.capture(buf, Direction::Outgoing);
+        match self.device {
+            Device::Tun(ref mut iface) => iface.send(buf),
+            #[cfg(any(test, feature = "testing"))]
+            Device::Mock {
+                gone: Some(errno), ..
+            } => Err(io::Error::from_raw_os_error(errno)),
+            #[cfg(any(test, feature = "testing"))]
+            Device::Mock { ref mut sent, .. } => {
+                sent.push(buf.to_vec());
+                Ok(buf.len())
+            }
+        }
+    }
+
+    pub(crate) fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let nbytes = match self.device {
+            Device::Tun(ref mut iface) => iface.recv(buf)?,
+            #[cfg(any(test, feature = "testing"))]
+            Device::Mock {
+                gone: Some(errno), ..
+            } => return Err(io::Error::from_raw_os_error(errno)),
+            #[cfg(any(test, feature = "testing"))]
+            Device::Mock {
+                ref mut incoming, ..
+            } => {
+                let packet = incoming.pop_front().ok_or(io::ErrorKind::WouldBlock)?;
+                buf[..packet.len()].copy_from_slice(&packet);
+                packet.len()
+            }
+        };
+        self.capture(&buf[..nbytes], Direction::Incoming);
+        Ok(nbytes)
+    }
+
+    fn capture(&self, packet: &[u8], direction: Direction) {
+        if let Some(ref capture) = *self.capture.lock().unwrap() {
+            capture(packet, direction);
+        }
+    }
+}
+
+impl AsRawFd for Nic {
+    fn as_raw_fd(&self) -> RawFd {
+        match self.device {
+            Device::Tun(ref iface) => iface.as_raw_fd(),
+            #[cfg(any(test, feature = "testing"))]
+            Device::Mock { .. } => panic!("a mock nic has nothing to poll"),
+        }
+    }
+}
@@ -1,33 +1,273 @@
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{hash_map::RandomState, BTreeMap, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hash},
     io::{self, Write},
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, OnceLock,
+    },
     time::{self, Duration},
 };
 
 use bitflags::bitflags;
 
+use crate::ao;
+use crate::clock::Clock;
+use crate::nic::Nic;
+use crate::sha1;
+
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_OPTION_SACK_PERMITTED: u8 = 4;
+const TCP_OPTION_SACK: u8 = 5;
+const TCP_OPTION_MD5: u8 = 19;
+const TCP_OPTION_USER_TIMEOUT: u8 = 28;
+const TCP_OPTION_FAST_OPEN: u8 = 34;
+/// room for options after the fixed part of the TCP header (RFC 793 S3.1)
+const MAX_OPTIONS_LEN: usize = 40;
+/// the fixed part of the TCP header
+const TCP_HEADER_LEN: usize = 20;
+/// an IP header and a TCP header, neither with any options
+const PLAIN_HEADER_LEN: usize = 20 + TCP_HEADER_LEN;
+/// what goes in the IP header's protocol field for TCP
+const IP_PROTOCOL_TCP: u8 = 6;
+
+/// MSS to assume when the peer doesn't tell us (RFC 1122 S4.2.2.6)
+const DEFAULT_MSS: u16 = 536;
+/// largest payload that fits in our 1500-byte send buffer after the IP and TCP headers
+const MAX_MSS: u16 = 1460;
+/// give up on the current MSS after this many retransmission timeouts in a row, in case the
+/// path is silently dropping packets that are too large (RFC 4821 S1)
+const PMTU_BLACKHOLE_TIMEOUTS: u32 = 3;
+/// how much received data is buffered for the application before the window closes
+const RECVQUEUE_SIZE: usize = 1024;
+/// how much written data is buffered before writers have to wait for the peer to ACK some of it
+pub(crate) const SENDQUEUE_SIZE: usize = 1024;
+/// with a read low-water mark, how long received data may wait before readers are woken anyway
+const READ_COALESCE_DELAY: Duration = Duration::from_millis(10);
+/// warn that the receiver looks stuck behind a hole after taking in this many out-of-order
+/// segments without `recv.nxt` moving
+const STUCK_RECEIVER_SEGMENTS: u32 = 16;
+/// how far back the throughput estimates in [`Stats`] look
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+
+/// A TCP segment as it came off the wire.
+pub struct Segment<'a> {
+    pub iph: etherparse::Ipv4HeaderSlice<'a>,
+    pub tcph: etherparse::TcpHeaderSlice<'a>,
+    pub data: &'a [u8],
+}
+
 bitflags! {
+    /// What a connection is ready for; see [`Interface::poll`](crate::Interface::poll).
     pub struct Available: u8 {
         const READ = 0b00000001;
         const WRITE = 0b00000010;
+        /// one of the connection's timers is due
+        const TIMER = 0b00000100;
+    }
+}
+
+/// Per-connection tunables, handed to every connection the interface creates.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionConfig {
+    /// Gracefully close a connection that has neither sent nor received anything for this long.
+    ///
+    /// Unlike keepalive, nothing is probed; the connection is simply torn down.
+    pub idle_timeout: Option<Duration>,
+    /// Enable TCP Fast Open (RFC 7413) for passive opens, with cookies keyed on this secret.
+    ///
+    /// A SYN carrying a valid cookie has its data delivered to the application straight away,
+    /// before the handshake completes.
+    pub tfo_key: Option<[u8; 16]>,
+    /// Spread new data out over the round-trip time instead of sending it as fast as the window
+    /// allows.
+    ///
+    /// Pacing only kicks in once there is an RTT sample to pace against.
+    pub pacing: bool,
+    /// Send at most this many segments of new data in one go, leaving the rest for the next tick,
+    /// rather than a whole window at once when output is flushed.
+    pub max_burst: Option<usize>,
+    /// Sign every segment with a TCP MD5 signature (RFC 2385) using this key, and drop incoming
+    /// segments that aren't signed with it.
+    pub md5_key: Option<Vec<u8>>,
+    /// Sign every segment with the TCP Authentication Option (RFC 5925) and drop incoming
+    /// segments that aren't signed with one of these keys.
+    ///
+    /// We send with the first key until the peer asks for another one. TCP-AO replaces MD5
+    /// signatures, so `md5_key` is ignored when there are any keys here.
+    pub ao_keys: Vec<AoKey>,
+    /// Abort the connection if sent data goes unacknowledged for this long (RFC 5482), no
+    /// matter how many retransmissions that took.
+    ///
+    /// The timeout is advertised to the peer, and if the peer advertises a longer one, that is
+    /// used instead.
+    pub user_timeout: Option<Duration>,
+    /// Don't wake up readers until at least this many bytes have been received, unless the peer
+    /// pushes (PSH) them or they have been waiting for longer than a short delay.
+    ///
+    /// This cuts down on wakeups when the peer sends lots of tiny segments.
+    pub read_low_water: Option<usize>,
+    /// Retransmission timeout to use until there is an RTT sample. Defaults to
+    /// [`DEFAULT_INITIAL_RTO`].
+    pub initial_rto: Option<Duration>,
+    /// Never let the retransmission timeout drop below this. Defaults to [`DEFAULT_MIN_RTO`].
+    pub min_rto: Option<Duration>,
+    /// Never let the retransmission timeout grow beyond this. Defaults to [`DEFAULT_MAX_RTO`].
+    pub max_rto: Option<Duration>,
+    /// Detect lost segments by when they were sent relative to ones that have been ACKed (RACK,
+    /// RFC 8985), on top of duplicate ACKs and the retransmission timer.
+    pub rack: bool,
+    /// How to pick the initial sequence number of each connection.
+    pub iss: IssStrategy,
+    /// Give up on a passive open once its SYN-ACK has been retransmitted this many times without
+    /// the handshake completing. Defaults to [`DEFAULT_SYN_ACK_RETRIES`].
+    pub syn_ack_retries: Option<u32>,
+    /// Give up on an active open once its SYN has been retransmitted this many times without an
+    /// answer. Defaults to [`DEFAULT_SYN_RETRIES`].
+    pub syn_retries: Option<u32>,
+    /// Hold back the ACK for in-order data for up to this long, in the hope that it can ride
+    /// along with data of our own or cover more segments. Defaults to [`DEFAULT_ACK_DELAY`]; zero
+    /// ACKs every segment as soon as it arrives.
+    pub ack_delay: Option<Duration>,
+    /// Don't hold back the ACK once this many segments are waiting for one. Defaults to
+    /// [`DEFAULT_ACK_EVERY`].
+    pub ack_every: Option<u32>,
+    /// Offer selective acknowledgements (RFC 2018), and when the peer agrees, tell it which
+    /// out-of-order data we are holding on to.
+    pub sack: bool,
+    /// Put at most this many blocks in each SACK option. However many there are, they are
+    /// limited to what fits in the header.
+    pub max_sack_blocks: Option<usize>,
+    /// Hold on to at most this many bytes of out-of-order data. Past that, the data furthest
+    /// from `RCV.NXT` is thrown away (and no longer SACKed), for the peer to retransmit later.
+    ///
+    /// The receive window already bounds where out-of-order data can start and end, but not how
+    /// much of it overlaps, so without a cap a peer could make us hold many copies of a window.
+    pub max_out_of_order: Option<usize>,
+    /// Where the connection's timers get the time from. Defaults to the system clock.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Hold writers back once this many bytes are queued to send, until the peer has ACKed
+    /// enough to bring it back below, even though the send queue has room for more.
+    ///
+    /// A write that starts below the mark can still fill the queue, so this only makes
+    /// producers wait earlier rather than capping what is queued.
+    pub send_high_water: Option<usize>,
+    /// Silently drop segments that fall outside the receive window, instead of answering each
+    /// with an ACK as RFC 793 asks.
+    ///
+    /// This keeps a flood of forged segments from turning into a flood of ACKs, at the cost of
+    /// slower recovery when a real peer gets out of step.
+    pub drop_unacceptable: bool,
+    /// Give up on a connection that has been in FIN-WAIT-2 for this long, waiting on a FIN the
+    /// peer never sends (like Linux's `tcp_fin_timeout`).
+    pub fin_wait2_timeout: Option<Duration>,
+    /// When `fin_wait2_timeout` gives up on a connection, reset it rather than just forgetting
+    /// about it, so that the peer knows to stop sending.
+    pub fin_wait2_reset: bool,
+    /// The maximum segment lifetime: how long a segment may linger in the network. TIME-WAIT
+    /// lasts for twice this. Defaults to [`DEFAULT_MSL`].
+    pub msl: Option<Duration>,
+    /// Keep a log of every segment in and out and every state change, for
+    /// [`TcpStream::event_log`](crate::TcpStream::event_log).
+    ///
+    /// The log is never trimmed, so this is for debugging short sessions.
+    pub event_log: bool,
+}
+
+impl ConnectionConfig {
+    pub(crate) fn now(&self) -> time::Instant {
+        self.clock
+            .as_ref()
+            .map_or_else(time::Instant::now, |clock| clock.now())
+    }
+
+    /// The MD5 key to sign with, unless TCP-AO takes its place.
+    fn md5(&self) -> Option<&[u8]> {
+        self.md5_key.as_deref().filter(|_| self.ao_keys.is_empty())
+    }
+
+    /// How much room a signature takes up in the options of every segment.
+    fn signature_len(&self) -> usize {
+        if !self.ao_keys.is_empty() {
+            ao::OPTION_LEN
+        } else if self.md5_key.is_some() {
+            // two NOPs, kind, length and a 16-byte digest
+            20
+        } else {
+            0
+        }
     }
 }
 
-#[derive(Debug)]
-enum State {
-    // Listen,
+/// A TCP-AO master key tuple (RFC 5925 S3.1), using HMAC-SHA-1-96 and KDF_HMAC_SHA1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AoKey {
+    /// the KeyID we put on segments we sign with this key
+    pub send_id: u8,
+    /// the KeyID the peer puts on segments it signs with this key
+    pub recv_id: u8,
+    pub master_key: Vec<u8>,
+}
+
+/// How connections pick their initial send sequence number (ISS).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IssStrategy {
+    /// Hard for an off-path attacker to guess (RFC 6528).
+    #[default]
+    Random,
+    /// The same ISS for every connection. Only useful for reproducible tests.
+    Fixed(u32),
+    /// 0 for the first connection, 1 for the next, and so on. Only useful for reproducible
+    /// tests.
+    Incrementing,
+}
+
+/// RFC 6298 S2.1
+pub const DEFAULT_INITIAL_RTO: Duration = Duration::from_secs(1);
+/// RFC 6298 S2.4
+pub const DEFAULT_MIN_RTO: Duration = Duration::from_secs(1);
+/// RFC 6298 S2.5
+pub const DEFAULT_MAX_RTO: Duration = Duration::from_secs(60);
+/// The same as Linux's `tcp_synack_retries`.
+pub const DEFAULT_SYN_ACK_RETRIES: u32 = 5;
+/// The same as Linux's `tcp_syn_retries`.
+pub const DEFAULT_SYN_RETRIES: u32 = 6;
+/// Shorter than RFC 793 S3.3's two minutes, the same as Linux, which has TIME-WAIT last a minute.
+pub const DEFAULT_MSL: Duration = Duration::from_secs(30);
+/// Comfortably inside RFC 1122 S4.2.3.2's limit of half a second.
+pub const DEFAULT_ACK_DELAY: Duration = Duration::from_millis(200);
+/// RFC 1122 S4.2.3.2: at least every second full-sized segment.
+pub const DEFAULT_ACK_EVERY: u32 = 2;
+
+/// Where a connection is in the TCP state machine (RFC 793 S3.2).
+#[derive(Debug, Clone)]
+pub enum State {
+    Listen,
+    SynSent,
     SynRcvd,
     Estab,
     FinWait1,
     FinWait2,
+    Closing,
+    CloseWait,
+    LastAck,
     TimeWait,
+    Closed,
 }
 
 impl State {
-    fn is_synchoronized(&self) -> bool {
+    fn is_synchronized(&self) -> bool {
         match *self {
-            State::SynRcvd => false,
-            State::Estab | State::FinWait1 | State::FinWait2 | State::TimeWait => true,
+            State::Listen | State::SynSent | State::SynRcvd | State::Closed => false,
+            State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::Closing
+            | State::CloseWait
+            | State::LastAck
+            | State::TimeWait => true,
         }
     }
 }
@@ -39,45 +279,1022 @@ pub struct Connection {
     ip: etherparse::Ipv4Header,
     tcp: etherparse::TcpHeader,
     timers: Timers,
+    congestion: Congestion,
+    config: ConnectionConfig,
+    /// largest segment the peer is willing to receive
+    mss: u16,
+    /// negotiated USER_TIMEOUT (RFC 5482)
+    user_timeout: Option<Duration>,
 
-    pub(crate) incoming: VecDeque<u8>,
+    incoming: VecDeque<u8>,
+    /// how much received data we want to hold for the application
+    recv_capacity: usize,
+    /// readers should be woken for what's in `incoming`, even if it's below the low-water mark
+    pushed: bool,
+    /// hold back ACKs for received data until the end of the current batch
+    defer_ack: bool,
+    /// an ACK was held back
+    ack_pending: bool,
+    /// in-order segments received since we last sent an ACK
+    segments_since_ack: u32,
+    /// the application asked for everything queued to go out on the next tick
+    flush_requested: bool,
+    /// hold back new data until there's a full segment's worth
+    corked: bool,
+    /// the application asked for the connection to be reset on the next tick
+    abort_requested: bool,
+    /// the last segment we sent advertised a zero window
+    advertised_zero_window: bool,
+    /// reading has reopened a window we had advertised as closed, and the peer needs to hear
+    window_update_pending: bool,
+    /// segments that arrived ahead of `recv.nxt`, keyed by their sequence number
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+    /// out-of-order segments received since `recv.nxt` last moved
+    stalled_segments: u32,
+    /// duplicate ACKs received in a row
+    dup_acks: u32,
+    /// sequence number of a FIN that arrived behind a hole, to act on once the hole is filled
+    fin_at: Option<u32>,
     pub(crate) unacked: VecDeque<u8>,
 
     pub(crate) closed: bool,
     closed_at: Option<u32>,
+    /// why the connection was aborted, if it was
+    error: Option<ResetReason>,
+    /// the options our SYN (or SYN-ACK) carried, to go on it again if it has to be retransmitted
+    syn_options: Vec<u8>,
+    /// both ends agreed to use SACK
+    sack_permitted: bool,
+    /// which of `config.ao_keys` we sign with, if we use TCP-AO
+    ao_key: Option<usize>,
+    /// how often our sequence numbers have wrapped, for TCP-AO
+    send_sne: Sne,
+    /// how often the peer's sequence numbers have wrapped, for TCP-AO
+    recv_sne: Sne,
+    /// sequence number of the out-of-order segment that arrived last, which the first SACK block
+    /// has to cover (RFC 2018 S4)
+    sack_latest: Option<u32>,
+    /// everything that has happened to the connection, if it is keeping an event log
+    log: Option<Vec<LogEntry>>,
+    /// our IP and TCP headers as they go out on a segment without options, to copy in and patch
+    /// for each such segment instead of having etherparse write them from scratch
+    header_template: Option<[u8; PLAIN_HEADER_LEN]>,
+    /// new data sent, for [`Stats`]
+    sent: Throughput,
+    /// data handed to `incoming`, for [`Stats`]
+    delivered: Throughput,
+    /// urgent pointers that pointed past the end of their segment, for [`Stats`]
+    urgent_clamped: u64,
+    /// segments dropped so far, for [`Stats`]
+    drops: Drops,
+}
+
+/// A running byte count, along with what was counted when over the last [`THROUGHPUT_WINDOW`]
+#[derive(Debug, Clone, Default)]
+struct Throughput {
+    total: u64,
+    recent: VecDeque<(time::Instant, usize)>,
+}
+
+impl Throughput {
+    fn record(&mut self, now: time::Instant, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        self.total += bytes as u64;
+        self.recent.push_back((now, bytes));
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: time::Instant) {
+        while let Some(&(at, _)) = self.recent.front() {
+            if now.duration_since(at) <= THROUGHPUT_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    /// bytes per second over the last [`THROUGHPUT_WINDOW`]
+    fn rate(&self, now: time::Instant) -> f64 {
+        let bytes: usize = self
+            .recent
+            .iter()
+            .filter(|&&(at, _)| now.duration_since(at) <= THROUGHPUT_WINDOW)
+            .map(|&(_, bytes)| bytes)
+            .sum();
+        bytes as f64 / THROUGHPUT_WINDOW.as_secs_f64()
+    }
+}
+
+/// Per-connection transfer counters.
+///
+/// See [`TcpStream::stats`](crate::TcpStream::stats). Like timers, they aren't part of a
+/// [`Snapshot`] and start over from zero when one is restored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// bytes of data sent, not counting retransmissions
+    pub bytes_sent: u64,
+    /// bytes received in order and handed to the receive buffer
+    pub bytes_received: u64,
+    /// bytes per second sent over the last second
+    pub send_rate: f64,
+    /// bytes per second received over the last second
+    pub recv_rate: f64,
+    /// segments whose urgent pointer pointed past the end of their data, and was cut back to it
+    pub urgent_pointers_clamped: u64,
+    /// segments thrown away without being acted on, by why
+    pub dropped: Drops,
+}
+
+/// Counts of segments a connection has dropped, by reason, as part of its [`Stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Drops {
+    /// the tcp checksum didn't match
+    pub checksum: u64,
+    /// the MD5 signature or TCP-AO MAC was missing or wrong
+    pub signature: u64,
+    /// the segment fell outside the receive window
+    pub out_of_window: u64,
+    /// the segment carried data while the receive window was closed
+    pub zero_window: u64,
+}
+
+/// The parts of a segment that matter for following a connection, as kept in its event log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggedSegment {
+    pub seq: u32,
+    /// the acknowledgement number, if the ACK bit was set
+    pub ack: Option<u32>,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub window: u16,
+    /// bytes of data
+    pub len: usize,
+}
+
+impl LoggedSegment {
+    fn received(tcph: &etherparse::TcpHeaderSlice, len: usize) -> Self {
+        LoggedSegment {
+            seq: tcph.sequence_number(),
+            ack: Some(tcph.acknowledgment_number()).filter(|_| tcph.ack()),
+            syn: tcph.syn(),
+            fin: tcph.fin(),
+            rst: tcph.rst(),
+            window: tcph.window_size(),
+            len,
+        }
+    }
+
+    fn sent(tcp: &etherparse::TcpHeader, len: usize) -> Self {
+        LoggedSegment {
+            seq: tcp.sequence_number,
+            ack: Some(tcp.acknowledgment_number).filter(|_| tcp.ack),
+            syn: tcp.syn,
+            fin: tcp.fin,
+            rst: tcp.rst,
+            window: tcp.window_size,
+            len,
+        }
+    }
+}
+
+/// One entry in a connection's event log; see [`ConnectionConfig::event_log`].
+///
+/// Two sessions that behaved the same produce equal logs, so a failing one can be diffed
+/// against a good one entry by entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogEntry {
+    /// a segment for this connection arrived
+    In(LoggedSegment),
+    /// the connection sent a segment
+    Out(LoggedSegment),
+    /// the connection moved to this state (like `"Estab"`)
+    State(String),
+    /// out-of-order data kept arriving while the hole `start..end` in front of it stayed open
+    Stuck(u32, u32),
+}
+
+/// Why a connection was torn down abnormally.
+///
+/// Once it has been, reads and writes fail with an [`io::Error`] whose kind matches
+/// [`ResetReason::kind`], and whose [`get_ref`](io::Error::get_ref) can be downcast to the
+/// reason itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    /// the peer sent a RST
+    PeerReset,
+    /// the peer stopped acknowledging what we sent, for longer than the user timeout or the
+    /// handshake retries allow
+    Timeout,
+    /// the application aborted the connection
+    UserAbort,
+    /// the peer answered our SYN with a RST, or an ICMP error came back for it
+    RefusedOnConnect,
+    /// an ICMP error said the peer can no longer be reached
+    Unreachable,
+    /// the tun device went away, taking every connection on it along
+    DeviceError,
+}
+
+impl ResetReason {
+    /// The [`io::ErrorKind`] this is reported as.
+    pub fn kind(self) -> io::ErrorKind {
+        match self {
+            ResetReason::PeerReset | ResetReason::Unreachable => io::ErrorKind::ConnectionReset,
+            ResetReason::Timeout => io::ErrorKind::TimedOut,
+            ResetReason::UserAbort | ResetReason::DeviceError => io::ErrorKind::ConnectionAborted,
+            ResetReason::RefusedOnConnect => io::ErrorKind::ConnectionRefused,
+        }
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResetReason::PeerReset => "connection reset by peer",
+            ResetReason::Timeout => "connection timed out",
+            ResetReason::UserAbort => "connection aborted",
+            ResetReason::RefusedOnConnect => "connection refused",
+            ResetReason::Unreachable => "connection aborted by an icmp error",
+            ResetReason::DeviceError => "network device went away",
+        })
+    }
+}
+
+impl std::error::Error for ResetReason {}
+
+/// Reno congestion control state (RFC 5681), growing the window by bytes rather than ACKs
+/// (RFC 3465)
+#[derive(Debug, Clone)]
+struct Congestion {
+    /// congestion window, in bytes
+    cwnd: u32,
+    /// slow start threshold, in bytes
+    ssthresh: u32,
+    /// bytes ACKed in congestion avoidance that haven't grown the window yet
+    bytes_acked: u32,
+    /// slow starting after a retransmission timeout, when the window grows more cautiously
+    after_timeout: bool,
+}
+
+impl Congestion {
+    /// Initial window for a path with the given MSS (RFC 6928)
+    fn initial_window(mss: u16) -> u32 {
+        let mss = mss as u32;
+        std::cmp::min(10 * mss, std::cmp::max(2 * mss, 14600))
+    }
+
+    fn new(mss: u16) -> Self {
+        Congestion {
+            cwnd: Self::initial_window(mss),
+            ssthresh: u32::MAX,
+            bytes_acked: 0,
+            after_timeout: false,
+        }
+    }
+
+    /// `acked` new bytes were acknowledged
+    ///
+    /// Counting bytes means a receiver that ACKs every other segment (or several at once) gets
+    /// the window opened just as fast as one that ACKs each, and one that splits its ACKs up
+    /// gets it opened no faster.
+    fn on_ack(&mut self, acked: u32, mss: u16) {
+        let mss = mss as u32;
+        if self.cwnd < self.ssthresh {
+            // slow start, by at most L = 2 segments per ACK, or 1 after a timeout (RFC 3465 S2.2)
+            let limit = if self.after_timeout { mss } else { 2 * mss };
+            self.cwnd = self.cwnd.saturating_add(std::cmp::min(acked, limit));
+            self.bytes_acked = 0;
+        } else {
+            // congestion avoidance: one segment per window's worth of bytes ACKed (RFC 3465 S2.1)
+            self.after_timeout = false;
+            self.bytes_acked = self.bytes_acked.saturating_add(acked);
+            if self.bytes_acked >= self.cwnd {
+                self.bytes_acked -= self.cwnd;
+                self.cwnd = self.cwnd.saturating_add(mss);
+            }
+        }
+    }
+
+    /// a segment was fast retransmitted with `flight` bytes outstanding
+    fn on_fast_retransmit(&mut self, flight: u32, mss: u16) {
+        self.ssthresh = std::cmp::max(flight / 2, 2 * mss as u32);
+        self.cwnd = self.ssthresh;
+        self.bytes_acked = 0;
+    }
+
+    /// the retransmission timer fired with `flight` bytes outstanding
+    fn on_timeout(&mut self, flight: u32, mss: u16) {
+        let mss = mss as u32;
+        self.ssthresh = std::cmp::max(flight / 2, 2 * mss);
+        self.cwnd = mss;
+        self.bytes_acked = 0;
+        self.after_timeout = true;
+    }
+
+    /// the application started sending again after nothing was sent for over an RTO, so the
+    /// window no longer reflects what the path can take (RFC 5681 S4.1)
+    fn on_idle_restart(&mut self, mss: u16) {
+        self.cwnd = std::cmp::min(self.cwnd, Self::initial_window(mss));
+        self.bytes_acked = 0;
+    }
 }
 
 struct Timers {
-    send_times: BTreeMap<u32, time::Instant>,
+    /// when each segment in flight was last sent, keyed by its sequence number, and whether it
+    /// had been sent before. the ACK of a retransmission can't be timed, since there's no knowing
+    /// which transmission it is for (Karn's algorithm).
+    send_times: BTreeMap<u32, (time::Instant, bool)>,
     srtt: f64,
+    /// most recent round-trip time sample, if any segment has been ACKed yet
+    last_rtt: Option<Duration>,
+    /// smallest round-trip time sample so far
+    min_rtt: Option<Duration>,
+    /// when the most recently sent segment that has been ACKed was sent (RACK.xmit_ts)
+    rack_xmit_ts: Option<time::Instant>,
+    /// with pacing on, new data isn't sent before this instant
+    paced_until: Option<time::Instant>,
+    /// last time a segment was sent or received on this connection
+    last_activity: time::Instant,
+    /// last time we sent a segment that took up sequence space
+    last_send: Option<time::Instant>,
+    /// retransmission timeouts since the last ACK that acknowledged new data
+    retransmits: u32,
+    /// when the data at SND.UNA was first sent, or the last time the peer ACKed new data
+    unacked_since: Option<time::Instant>,
+    /// when the oldest data in `incoming` arrived
+    unread_since: Option<time::Instant>,
+    /// when we last probed the peer's zero window, or first noticed it was zero
+    zero_window_since: Option<time::Instant>,
+    /// zero window probes sent since the peer's window was last open
+    probes: u32,
+    /// a delayed ACK has to go out by this instant
+    ack_due: Option<time::Instant>,
+    /// when we first noticed the connection was in FIN-WAIT-2
+    fin_wait2_since: Option<time::Instant>,
+    /// when the connection went into TIME-WAIT, or last heard a retransmitted FIN there
+    time_wait_since: Option<time::Instant>,
+}
+
+impl Timers {
+    fn new(now: time::Instant) -> Self {
+        Timers {
+            send_times: Default::default(),
+            srtt: Duration::from_secs(60).as_secs_f64(),
+            last_rtt: None,
+            min_rtt: None,
+            rack_xmit_ts: None,
+            paced_until: None,
+            last_activity: now,
+            last_send: None,
+            retransmits: 0,
+            unacked_since: None,
+            unread_since: None,
+            zero_window_since: None,
+            probes: 0,
+            ack_due: None,
+            fin_wait2_since: None,
+            time_wait_since: None,
+        }
+    }
+}
+
+/// Sequence number extension for one direction of a connection (RFC 5925 S6.2): how many times
+/// its sequence numbers have wrapped.
+#[derive(Debug, Clone, Copy, Default)]
+struct Sne {
+    wraps: u32,
+    /// the newest sequence number seen so far
+    last: Option<u32>,
+}
+
+impl Sne {
+    /// The SNE for a segment at `seq`, taking a segment from just before the last wrap (like a
+    /// retransmission) into account.
+    fn at(&self, seq: u32) -> u32 {
+        match self.last {
+            Some(last) if wrapping_lt(last, seq) && seq < last => self.wraps.wrapping_add(1),
+            Some(last) if wrapping_lt(seq, last) && seq > last => self.wraps.wrapping_sub(1),
+            _ => self.wraps,
+        }
+    }
+
+    /// Notes that a segment at `seq` was sent or accepted.
+    fn advance(&mut self, seq: u32) {
+        if self.last.is_none_or(|last| wrapping_lt(last, seq)) {
+            self.wraps = self.at(seq);
+            self.last = Some(seq);
+        }
+    }
+}
+
+/// The state of a connection, detached from the connection itself.
+///
+/// See [`TcpStream::snapshot`](crate::TcpStream::snapshot). Timers aren't part of a snapshot;
+/// they start over when it is restored.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    state: State,
+    send: SendSequenceSpace,
+    recv: RecvSequenceSpace,
+    ip: etherparse::Ipv4Header,
+    tcp: etherparse::TcpHeader,
+    srtt: f64,
+    last_rtt: Option<Duration>,
+    congestion: Congestion,
+    config: ConnectionConfig,
+    mss: u16,
+    user_timeout: Option<Duration>,
+    incoming: VecDeque<u8>,
+    recv_capacity: usize,
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+    fin_at: Option<u32>,
+    unacked: VecDeque<u8>,
+    closed: bool,
+    closed_at: Option<u32>,
+    sack_permitted: bool,
+    ao_key: Option<usize>,
+    send_sne: Sne,
+    recv_sne: Sne,
+}
+
+/// What the two ends agreed on in the handshake.
+///
+/// See [`TcpStream::negotiated_options`](crate::TcpStream::negotiated_options). Window scaling
+/// and timestamps aren't implemented, so there is nothing to report for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedOptions {
+    /// largest segment the peer is willing to receive, from its MSS option or the default
+    pub mss: u16,
+    /// both ends sent SACK-permitted, so we send SACK blocks
+    pub sack_permitted: bool,
+    /// the USER_TIMEOUT we settled on, if we asked for one (RFC 5482)
+    pub user_timeout: Option<Duration>,
+}
+
+impl Snapshot {
+    /// The remote and local ends of the connection, in that order.
+    pub(crate) fn endpoints(&self) -> ((Ipv4Addr, u16), (Ipv4Addr, u16)) {
+        (
+            (
+                Ipv4Addr::from(self.ip.destination),
+                self.tcp.destination_port,
+            ),
+            (Ipv4Addr::from(self.ip.source), self.tcp.source_port),
+        )
+    }
 }
 
 impl Connection {
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            state: self.state.clone(),
+            send: self.send.clone(),
+            recv: self.recv.clone(),
+            ip: self.ip.clone(),
+            tcp: self.tcp.clone(),
+            srtt: self.timers.srtt,
+            last_rtt: self.timers.last_rtt,
+            congestion: self.congestion.clone(),
+            config: self.config.clone(),
+            mss: self.mss,
+            user_timeout: self.user_timeout,
+            incoming: self.incoming.clone(),
+            recv_capacity: self.recv_capacity,
+            out_of_order: self.out_of_order.clone(),
+            fin_at: self.fin_at,
+            unacked: self.unacked.clone(),
+            closed: self.closed,
+            closed_at: self.closed_at,
+            sack_permitted: self.sack_permitted,
+            ao_key: self.ao_key,
+            send_sne: self.send_sne,
+            recv_sne: self.recv_sne,
+        }
+    }
+
+    pub(crate) fn restore(snapshot: Snapshot) -> Self {
+        let mut timers = Timers {
+            srtt: snapshot.srtt,
+            last_rtt: snapshot.last_rtt,
+            ..Timers::new(snapshot.config.now())
+        };
+        let log = snapshot.config.event_log.then(Vec::new);
+        if snapshot.send.nxt != snapshot.send.una {
+            // pretend everything in flight was sent just now, so it is retransmitted if the
+            // peer doesn't ACK it within an RTO
+            timers
+                .send_times
+                .insert(snapshot.send.una, (timers.last_activity, true));
+            timers.unacked_since = Some(timers.last_activity);
+        }
+
+        Connection {
+            state: snapshot.state,
+            send: snapshot.send,
+            recv: snapshot.recv,
+            ip: snapshot.ip,
+            tcp: snapshot.tcp,
+            timers,
+            congestion: snapshot.congestion,
+            config: snapshot.config,
+            mss: snapshot.mss,
+            user_timeout: snapshot.user_timeout,
+            incoming: snapshot.incoming,
+            recv_capacity: snapshot.recv_capacity,
+            pushed: false,
+            defer_ack: false,
+            ack_pending: false,
+            segments_since_ack: 0,
+            flush_requested: false,
+            corked: false,
+            abort_requested: false,
+            advertised_zero_window: false,
+            window_update_pending: false,
+            out_of_order: snapshot.out_of_order,
+            stalled_segments: 0,
+            dup_acks: 0,
+            fin_at: snapshot.fin_at,
+            unacked: snapshot.unacked,
+            closed: snapshot.closed,
+            closed_at: snapshot.closed_at,
+            error: None,
+            syn_options: Vec::new(),
+            sack_permitted: snapshot.sack_permitted,
+            ao_key: snapshot.ao_key,
+            send_sne: snapshot.send_sne,
+            recv_sne: snapshot.recv_sne,
+            sack_latest: None,
+            log,
+            header_template: None,
+            sent: Throughput::default(),
+            delivered: Throughput::default(),
+            urgent_clamped: 0,
+            drops: Drops::default(),
+        }
+    }
+
+    pub(crate) fn last_rtt(&self) -> Option<Duration> {
+        self.timers.last_rtt
+    }
+
+    pub(crate) fn smoothed_rtt(&self) -> Option<Duration> {
+        // srtt starts out as a conservative guess, which isn't worth reporting
+        self.timers
+            .last_rtt
+            .map(|_| Duration::from_secs_f64(self.timers.srtt))
+    }
+
+    pub(crate) fn negotiated_options(&self) -> NegotiatedOptions {
+        NegotiatedOptions {
+            mss: self.mss,
+            sack_permitted: self.sack_permitted,
+            user_timeout: self.user_timeout,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        let now = self.config.now();
+        Stats {
+            bytes_sent: self.sent.total,
+            bytes_received: self.delivered.total,
+            send_rate: self.sent.rate(now),
+            recv_rate: self.delivered.rate(now),
+            urgent_pointers_clamped: self.urgent_clamped,
+            dropped: self.drops,
+        }
+    }
+
+    /// Starts all the [`Stats`] counters over from zero, so that they cover the time since.
+    pub(crate) fn reset_stats(&mut self) {
+        self.sent = Throughput::default();
+        self.delivered = Throughput::default();
+        self.urgent_clamped = 0;
+        self.drops = Drops::default();
+    }
+
+    pub(crate) fn cwnd(&self) -> u32 {
+        self.congestion.cwnd
+    }
+
+    pub(crate) fn ssthresh(&self) -> u32 {
+        self.congestion.ssthresh
+    }
+
+    /// Starts out with an ssthresh learned from earlier connections to the same peer, instead of
+    /// slow starting until the first loss (RFC 2140 S2.2).
+    pub(crate) fn inherit_ssthresh(&mut self, ssthresh: u32) {
+        self.congestion.ssthresh = ssthresh;
+    }
+
+    /// The ssthresh worth passing on to later connections to the same peer, if a loss has told
+    /// us anything about the path yet.
+    pub(crate) fn learned_ssthresh(&self) -> Option<u32> {
+        Some(self.congestion.ssthresh).filter(|&ssthresh| ssthresh != u32::MAX)
+    }
+
+    /// A connection from `local` to `remote` that is already in `state`, with `iss` as our ISN
+    /// and `irs` as the peer's, as if everything it took to get there had been exchanged.
+    ///
+    /// Our SYN has been ACKed in any synchronized state. In the closing states, our FIN went out
+    /// right after it, and the peer's FIN has been received in TIME-WAIT. The peer's window is
+    /// taken to be as large as ours.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn fixture(
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        state: State,
+        config: ConnectionConfig,
+        iss: u32,
+        irs: u32,
+    ) -> Self {
+        let mut c = Connection::new(local, remote, state, config);
+        c.send.iss = iss;
+        c.send.una = iss;
+        c.send.nxt = iss.wrapping_add(1);
+        c.send.wnd = c.recv.wnd;
+        c.recv.irs = irs;
+        c.recv.nxt = irs.wrapping_add(1);
+        c.tcp.sequence_number = iss;
+        if c.state.is_synchronized() {
+            c.tcp.ack = true;
+            c.send.una = c.send.nxt;
+            c.send.wl1 = irs;
+            c.send.wl2 = c.send.una;
+        }
+        if let State::FinWait1
+        | State::FinWait2
+        | State::Closing
+        | State::LastAck
+        | State::TimeWait = c.state
+        {
+            c.closed = true;
+            c.closed_at = Some(c.send.nxt);
+            c.send.nxt = c.send.nxt.wrapping_add(1);
+            if !matches!(c.state, State::FinWait1 | State::Closing | State::LastAck) {
+                c.send.una = c.send.nxt;
+            }
+        }
+        if let State::Closing | State::CloseWait | State::LastAck | State::TimeWait = c.state {
+            c.recv.nxt = c.recv.nxt.wrapping_add(1);
+        }
+        if let State::SynRcvd = c.state {
+            c.tcp.ack = true;
+        }
+        // as if the connection had just got there
+        match c.state {
+            State::TimeWait => c.timers.time_wait_since = Some(c.config.now()),
+            State::FinWait2 => c.timers.fin_wait2_since = Some(c.config.now()),
+            _ => {}
+        }
+        c.log_state();
+        c
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn set_cwnd(&mut self, cwnd: u32) {
+        self.congestion.cwnd = cwnd;
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn set_ssthresh(&mut self, ssthresh: u32) {
+        self.congestion.ssthresh = ssthresh;
+    }
+
+    /// Moves to `state`, logging the change.
+    fn set_state(&mut self, state: State) {
+        let changed = std::mem::discriminant(&self.state) != std::mem::discriminant(&state);
+        self.state = state;
+        if changed {
+            match self.state {
+                State::TimeWait => self.timers.time_wait_since = Some(self.config.now()),
+                State::FinWait2 => self.timers.fin_wait2_since = Some(self.config.now()),
+                _ => {}
+            }
+            self.log_state();
+        }
+    }
+
+    fn log_state(&mut self) {
+        if let Some(ref mut log) = self.log {
+            log.push(LogEntry::State(format!("{:?}", self.state)));
+        }
+    }
+
+    fn log(&mut self, entry: LogEntry) {
+        if let Some(ref mut log) = self.log {
+            log.push(entry);
+        }
+    }
+
+    /// Everything logged so far, or nothing if the connection isn't keeping an event log.
+    pub(crate) fn event_log(&self) -> Vec<LogEntry> {
+        self.log.clone().unwrap_or_default()
+    }
+
+    /// Whether the handshake is still in progress.
+    pub(crate) fn is_connecting(&self) -> bool {
+        matches!(self.state, State::SynSent | State::SynRcvd)
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    /// Whether the connection is through closing, save perhaps for waiting out TIME-WAIT.
+    pub(crate) fn is_done(&self) -> bool {
+        matches!(self.state, State::Closed | State::TimeWait)
+    }
+
+    /// The error that aborted the connection, if it didn't close normally.
+    pub(crate) fn error(&self) -> Option<io::Error> {
+        self.error
+            .map(|reason| io::Error::new(reason.kind(), reason))
+    }
+
     pub(crate) fn is_rcv_closed(&self) -> bool {
         eprintln!("asked if closed when in {:?}", self.state);
-        if let State::TimeWait = self.state {
-            // TODO: any state after rcvd FIN, so also CLOSE-WAIT, LAST-ACK, CLOSED, CLOSING
+        if let State::Closing
+        | State::CloseWait
+        | State::LastAck
+        | State::TimeWait
+        | State::Closed = self.state
+        {
+            // we have the peer's FIN (or there's no connection left to get data from)
             true
         } else {
             false
         }
     }
 
+    /// The sequence ranges (`start..end`) of out-of-order data we are holding on to, in the order
+    /// they appear after `recv.nxt`.
+    pub(crate) fn out_of_order_ranges(&self) -> Vec<(u32, u32)> {
+        let mut ranges: Vec<_> = self
+            .out_of_order
+            .iter()
+            .map(|(&seq, data)| (seq, seq.wrapping_add(data.len() as u32)))
+            .collect();
+        ranges.sort_by_key(|&(start, _)| start.wrapping_sub(self.recv.nxt));
+        ranges
+    }
+
+    /// The sequence ranges (`start..end`) we are still waiting for the peer to fill in, from
+    /// `recv.nxt` up to the last out-of-order byte we are holding: what SACK blocks would tell the
+    /// peer is missing.
+    pub(crate) fn missing_ranges(&self) -> Vec<(u32, u32)> {
+        let mut missing = Vec::new();
+        let mut next = self.recv.nxt;
+        for (start, end) in self.out_of_order_ranges() {
+            if wrapping_lt(next, start) {
+                missing.push((next, start));
+            }
+            if wrapping_lt(next, end) {
+                next = end;
+            }
+        }
+        missing
+    }
+
+    /// A SACK option reporting the out-of-order data we hold, padded to fit in `room` bytes, or
+    /// nothing if there's nothing to report or SACK wasn't agreed on.
+    ///
+    /// The block holding the segment that arrived last goes first, and the rest follow in
+    /// sequence order for as long as there's space (RFC 2018 S4).
+    fn sack_option(&self, room: usize) -> Vec<u8> {
+        if !self.sack_permitted || self.out_of_order.is_empty() {
+            return Vec::new();
+        }
+
+        // neighbouring segments make up one block
+        let mut blocks: Vec<(u32, u32)> = Vec::new();
+        for (start, end) in self.out_of_order_ranges() {
+            match blocks.last_mut() {
+                Some((_, last_end)) if !wrapping_lt(*last_end, start) => {
+                    if wrapping_lt(*last_end, end) {
+                        *last_end = end;
+                    }
+                }
+                _ => blocks.push((start, end)),
+            }
+        }
+        if let Some(latest) = self.sack_latest {
+            if let Some(i) = blocks
+                .iter()
+                .position(|&(start, end)| !wrapping_lt(latest, start) && wrapping_lt(latest, end))
+            {
+                let block = blocks.remove(i);
+                blocks.insert(0, block);
+            }
+        }
+
+        // two NOPs, kind and length, then 8 bytes per block
+        let fit = room.saturating_sub(4) / 8;
+        let max = self.config.max_sack_blocks.unwrap_or(usize::MAX);
+        blocks.truncate(fit.min(max));
+        if blocks.is_empty() {
+            return Vec::new();
+        }
+
+        let mut option = vec![1, 1, TCP_OPTION_SACK, 2 + 8 * blocks.len() as u8];
+        for (start, end) in blocks {
+            option.extend_from_slice(&start.to_be_bytes());
+            option.extend_from_slice(&end.to_be_bytes());
+        }
+        option
+    }
+
+    /// Appends in-order data to `incoming`.
+    fn deliver(&mut self, data: &[u8]) {
+        self.incoming.extend(data);
+        self.delivered.record(self.config.now(), data.len());
+    }
+
+    /// Throws away out-of-order data, furthest from `recv.nxt` first, until what's left fits in
+    /// `config.max_out_of_order`.
+    ///
+    /// A FIN we're holding on to for the end of that data is kept: the peer will have to resend
+    /// the data, but the FIN still comes after it.
+    fn evict_out_of_order(&mut self) {
+        let max = match self.config.max_out_of_order {
+            Some(max) => max,
+            None => return,
+        };
+        let mut buffered: usize = self.out_of_order.values().map(Vec::len).sum();
+        while buffered > max {
+            let furthest = self
+                .out_of_order
+                .keys()
+                .copied()
+                .max_by_key(|&seq| seq.wrapping_sub(self.recv.nxt))
+                .expect("buffered data is in some segment");
+            let data = self
+                .out_of_order
+                .remove(&furthest)
+                .expect("key was just found");
+            eprintln!(
+                "holding more than {}b out of order; dropping {}b at {}",
+                max,
+                data.len(),
+                furthest
+            );
+            buffered -= data.len();
+        }
+    }
+
+    /// Moves buffered out-of-order data that `recv.nxt` has caught up with into `incoming`.
+    fn reassemble(&mut self) {
+        // sequence numbers wrap, so we can't rely on the map's ordering to find the next segment
+        while let Some(seq) = self
+            .out_of_order
+            .keys()
+            .copied()
+            .find(|&seq| !wrapping_lt(self.recv.nxt, seq))
+        {
+            let data = self.out_of_order.remove(&seq).expect("key was just found");
+            let skip = self.recv.nxt.wrapping_sub(seq) as usize;
+            if skip < data.len() {
+                self.deliver(&data[skip..]);
+                self.recv.nxt = seq.wrapping_add(data.len() as u32);
+            }
+        }
+    }
+
+    /// Whether a reader should be woken up to read what has been received so far.
+    pub(crate) fn is_readable(&self) -> bool {
+        if self.is_rcv_closed() {
+            return true;
+        }
+        match self.config.read_low_water {
+            Some(low_water) => {
+                !self.incoming.is_empty() && (self.pushed || self.incoming.len() >= low_water)
+            }
+            None => !self.incoming.is_empty(),
+        }
+    }
+
+    /// Whether a writer should stop waiting: there is room in the send queue, or there never will
+    /// be.
+    pub(crate) fn is_writable(&self) -> bool {
+        self.is_closed() || self.has_send_room()
+    }
+
+    /// Whether writers may queue more data: the send queue is below its high-water mark, if
+    /// there is one, and isn't full.
+    pub(crate) fn has_send_room(&self) -> bool {
+        let mark = self
+            .config
+            .send_high_water
+            .map_or(SENDQUEUE_SIZE, |mark| mark.min(SENDQUEUE_SIZE));
+        self.unacked.len() < mark
+    }
+
+    /// Moves as much received data as fits into `buf`.
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> usize {
+        let nread = std::cmp::min(buf.len(), self.incoming.len());
+        for (b, d) in buf.iter_mut().zip(self.incoming.drain(..nread)) {
+            *b = d;
+        }
+        if self.incoming.is_empty() {
+            self.pushed = false;
+            self.timers.unread_since = None;
+        }
+
+        self.reopen_recv_window();
+        nread
+    }
+
+    /// Changes how much received data we are willing to hold for the application.
+    ///
+    /// Shrinking only stops new space from being advertised: the peer may still send up to the
+    /// right edge of a window it has already been given (RFC 793 S3.7), so `incoming` can briefly
+    /// hold more than `capacity`.
+    pub(crate) fn resize_recv_buffer(&mut self, capacity: usize) {
+        self.recv_capacity = capacity;
+        self.reopen_recv_window();
+    }
+
+    /// Recomputes the receive window, and arranges to tell the peer if that reopened a window it
+    /// was told is closed.
+    fn reopen_recv_window(&mut self) {
+        self.update_recv_window();
+        // don't bother the peer until the window is worth sending into (RFC 1122 S4.2.3.3)
+        let worthwhile = std::cmp::min(self.mss as usize, self.recv_capacity / 2);
+        if self.advertised_zero_window && self.recv.wnd != 0 && self.recv.wnd as usize >= worthwhile
+        {
+            self.window_update_pending = true;
+        }
+    }
+
+    /// Shrinks or grows the receive window to match the space left in `incoming`.
+    ///
+    /// The right edge never moves backwards, even if that means advertising more than there is
+    /// space for.
+    fn update_recv_window(&mut self) {
+        let space = self.recv_capacity.saturating_sub(self.incoming.len());
+        let promised = match self.recv.edge {
+            Some(edge) if wrapping_lt(self.recv.nxt, edge) => edge.wrapping_sub(self.recv.nxt),
+            _ => 0,
+        };
+        self.recv.wnd = std::cmp::max(space, promised as usize).min(u16::MAX as usize) as u16;
+    }
+
+    /// Like [`Connection::read`], but leaves the data where it is.
+    pub(crate) fn peek(&self, buf: &mut [u8]) -> usize {
+        let npeek = std::cmp::min(buf.len(), self.incoming.len());
+        for (b, &d) in buf.iter_mut().zip(self.incoming.range(..npeek)) {
+            *b = d;
+        }
+        npeek
+    }
+
+    /// Notes that data was just added to `incoming`.
+    fn received(&mut self, push: bool) {
+        self.update_recv_window();
+        if self.incoming.is_empty() {
+            return;
+        }
+        if self.timers.unread_since.is_none() {
+            self.timers.unread_since = Some(self.config.now());
+        }
+        self.pushed |= push;
+    }
+
     fn availability(&self) -> Available {
         let mut a = Available::empty();
-        if self.is_rcv_closed() || !self.incoming.is_empty() {
+        if self.is_readable() {
             a |= Available::READ;
         }
+        if self.is_writable() {
+            a |= Available::WRITE;
+        }
         // TODO: take into account self.state
-        // TODO: set Available::WRITE
 
         a
     }
 }
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // in LISTEN and SYN-SENT the peer has nothing to be left hanging on, just as `teardown`
+        // wouldn't send anything there
+        if !matches!(
+            self.state,
+            State::Listen | State::SynSent | State::Closed | State::TimeWait
+        ) {
+            eprintln!(
+                "connection dropped in {:?} without a teardown; the peer is left half-open",
+                self.state
+            );
+        }
+    }
+}
+
 /// State of Send Sequence Space (RFC 793 S3.2)
 ///
-/// ```
+/// ```text
 ///                    1         2          3          4
 ///               ----------|----------|----------|----------
 ///                      SND.UNA    SND.NXT    SND.UNA
@@ -87,11 +1304,12 @@ impl Connection {
 /// 2 - sequence numbers of unacknowledged data
 /// 3 - sequence numbers allowed for new data transmission
 /// 4 - future sequence numbers which are not yet allowed
-/// ```
 ///
 ///                           Send Sequence Space
 ///
 ///                                Figure 4.
+/// ```
+#[derive(Debug, Clone)]
 struct SendSequenceSpace {
     /// send unacknowledged
     una: u32,
@@ -100,16 +1318,19 @@ struct SendSequenceSpace {
     /// send window
     wnd: u16,
     /// send urgent pointer
+    #[allow(dead_code)]
     up: bool,
-    wl1: usize,
-    wl2: usize,
+    /// segment sequence number used for last window update
+    wl1: u32,
+    /// segment acknowledgment number used for last window update
+    wl2: u32,
     /// initial receive sequence number
     iss: u32,
 }
 
 /// State of Receive Sequence Space (RFC 793 S3.2)
 ///
-/// ```
+/// ```text
 ///                        1          2          3
 ///                    ----------|----------|----------
 ///                           RCV.NXT    RCV.NXT
@@ -118,108 +1339,426 @@ struct SendSequenceSpace {
 /// 1 - old sequence numbers which have been acknowledged
 /// 2 - sequence numbers allowed for new reception
 /// 3 - future sequence numbers which are not yet allowed
-/// ```
 ///
 ///                          Receive Sequence Space
 ///
 ///                                Figure 5.
+/// ```
+#[derive(Debug, Clone)]
 struct RecvSequenceSpace {
     /// receive next
     nxt: u32,
     /// receive window
     wnd: u16,
-    /// receive urgent pointer
-    up: bool,
+    /// right edge of the furthest window we have advertised (RCV.NXT + RCV.WND), once we have
+    /// advertised one
+    edge: Option<u32>,
+    /// receive urgent pointer: the sequence number just past the last urgent byte, once the peer
+    /// has sent any
+    up: Option<u32>,
     /// initial receive sequence number
     irs: u32,
 }
 
 impl Connection {
+    /// Answers a segment arriving on a listening port, starting a connection if it's a SYN.
+    ///
+    /// This is [`Connection::listen`] and [`Connection::on_listen`] in one go, for when a
+    /// listener has no endpoint of its own until the first segment comes in.
     pub fn accept<'a>(
-        nic: &mut tun_tap::Iface,
+        nic: &mut Nic,
         iph: etherparse::Ipv4HeaderSlice<'a>,
         tcph: etherparse::TcpHeaderSlice<'a>,
         data: &'a [u8],
+        config: ConnectionConfig,
     ) -> io::Result<Option<Self>> {
-        let buf = [0u8; 1500];
+        let local = SocketAddrV4::new(iph.destination_addr(), tcph.destination_port());
+        let mut c = Connection::listen(local, config);
+        let started = c.on_listen(nic, &iph, &tcph, data);
+        if let Ok(true) = started {
+            return Ok(Some(c));
+        }
+        // the endpoint was only ever listening for this one segment
+        c.set_state(State::Closed);
+        started.map(|_| None)
+    }
 
+    /// Starts a passive open on `local` (RFC 793 S3.8): an endpoint in LISTEN, with no peer
+    /// until a SYN arrives.
+    pub fn listen(local: SocketAddrV4, config: ConnectionConfig) -> Self {
+        let unspecified = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+        let mut c = Connection::new(local, unspecified, State::Listen, config);
+        c.log_state();
+        c
+    }
+
+    /// Handles a segment arriving in LISTEN (RFC 793 S3.9).
+    ///
+    /// A valid SYN makes its sender our peer, and moves us to SYN-RECEIVED with a SYN-ACK on
+    /// its way; that returns `true`. Anything else leaves us listening: a RST is ignored, an ACK
+    /// can't be for anything of ours and is reset, and whatever is left is dropped.
+    pub fn on_listen(
+        &mut self,
+        nic: &mut Nic,
+        iph: &etherparse::Ipv4HeaderSlice,
+        tcph: &etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<bool> {
+        if !checksum_ok(iph, tcph, data) {
+            eprintln!("dropping segment with a bad checksum in Listen");
+            return Ok(false);
+        }
+        // first, check for a RST
+        if tcph.rst() {
+            return Ok(false);
+        }
+        // second, check for an ACK: <SEQ=SEG.ACK><CTL=RST>
+        if tcph.ack() {
+            eprintln!("ack in Listen; resetting");
+            reset_segment(nic, iph, tcph, data.len())?;
+            return Ok(false);
+        }
+        // third, check for a SYN. anything else has no business here, and is dropped.
         if !tcph.syn() {
-            // only expected SYN packet
-            return Ok(None);
+            return Ok(false);
+        }
+
+        if let Some(key) = self.config.md5() {
+            if !md5_ok(key, iph, tcph, data) {
+                eprintln!("dropping SYN with a missing or bad md5 signature");
+                return Ok(false);
+            }
+        }
+        let mut ao_key = None;
+        if !self.config.ao_keys.is_empty() {
+            // the SYN is signed with the client's ISN and a zero for the one we haven't picked
+            let isns = (tcph.sequence_number(), 0);
+            match ao_ok(&self.config.ao_keys, iph, tcph, data, isns, 0) {
+                Some(key) => ao_key = Some(key),
+                None => {
+                    eprintln!("dropping SYN with a missing or bad tcp-ao mac");
+                    return Ok(false);
+                }
+            }
         }
 
-        let iss = 0;
-        let wnd = 1024;
+        // with a peer at last, the headers, ISS and all the rest can be set up for it
+        let mut c = Connection::new(
+            SocketAddrV4::new(Ipv4Addr::from(self.ip.source), self.tcp.source_port),
+            SocketAddrV4::new(iph.source_addr(), tcph.source_port()),
+            State::SynRcvd,
+            self.config.clone(),
+        );
+        c.log = self.log.take();
+        c.log(LogEntry::In(LoggedSegment::received(tcph, data.len())));
+        c.log_state();
+        if ao_key.is_some() {
+            c.ao_key = ao_key;
+            c.recv_sne.advance(tcph.sequence_number());
+        }
+        c.mss = peer_mss(tcph);
+        c.congestion = Congestion::new(c.mss);
+        c.send.wnd = tcph.window_size();
+        c.send.wl1 = tcph.sequence_number();
+        c.recv.irs = tcph.sequence_number();
+        c.recv.nxt = tcph.sequence_number().wrapping_add(1);
+
+        // most important first, in case they don't all fit. without an MSS, the peer has to
+        // assume 536 bytes (RFC 1122 S4.2.2.6).
+        let mut syn_ack_options = vec![mss_option()];
+        if let Some(key) = c.config.tfo_key {
+            if let Some(cookie) = find_option(tcph.options(), TCP_OPTION_FAST_OPEN) {
+                let expected = tfo_cookie(&key, iph.source());
+                if constant_time_eq(cookie, &expected) {
+                    // the client has been here before, so we can trust the data on its SYN
+                    let early = std::cmp::min(data.len(), c.recv.wnd as usize);
+                    c.deliver(&data[..early]);
+                    c.received(tcph.psh());
+                    c.recv.nxt = c.recv.nxt.wrapping_add(early as u32);
+                } else {
+                    // either a cookie request or a cookie we didn't issue: any SYN data is
+                    // ignored, and the client gets a fresh cookie for next time
+                    let mut option = vec![1, 1, TCP_OPTION_FAST_OPEN, 2 + expected.len() as u8];
+                    option.extend_from_slice(&expected);
+                    syn_ack_options.push(option);
+                }
+            }
+        }
+        if c.config.sack && find_option(tcph.options(), TCP_OPTION_SACK_PERMITTED).is_some() {
+            c.sack_permitted = true;
+            syn_ack_options.push(vec![1, 1, TCP_OPTION_SACK_PERMITTED, 2]);
+        }
+        if let Some(option) = c.negotiate_user_timeout(tcph) {
+            syn_ack_options.push(option.to_vec());
+        }
+        // no window scale option, whatever the SYN carried: we don't scale windows. if we ever
+        // do, it may only go on the SYN-ACK when the SYN had one too (RFC 7323 S2.2); offering it
+        // unasked leaves the peer unscaled while we scale, or the other way around.
+        c.syn_options = fit_syn_options(syn_ack_options, c.config.signature_len());
+
+        // need to start establishing a connection
+        c.tcp.ack = true;
+        c.write_syn(nic)?;
+
+        // the listening endpoint has become the connection, and is done with
+        let mut listener = std::mem::replace(self, c);
+        listener.set_state(State::Closed);
+        Ok(true)
+    }
+
+    /// Starts an active open from `local` to `remote` by sending a SYN.
+    pub fn connect(
+        nic: &mut Nic,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        config: ConnectionConfig,
+    ) -> io::Result<Self> {
+        let mut c = Connection::new(local, remote, State::SynSent, config);
+        c.log_state();
+
+        let mut syn_options = vec![mss_option()];
+        if c.config.sack {
+            syn_options.push(vec![1, 1, TCP_OPTION_SACK_PERMITTED, 2]);
+        }
+        if let Some(local) = c.config.user_timeout {
+            let mut option = vec![TCP_OPTION_USER_TIMEOUT, 4];
+            option.extend_from_slice(&encode_user_timeout(local).to_be_bytes());
+            syn_options.push(option);
+        }
+        c.syn_options = fit_syn_options(syn_options, c.config.signature_len());
+        c.write_syn(nic)?;
+
+        Ok(c)
+    }
+
+    /// Sends our SYN (a SYN-ACK if ACK is set), with the options it was first sent with.
+    fn write_syn(&mut self, nic: &mut Nic) -> io::Result<()> {
+        self.tcp
+            .set_options_raw(&self.syn_options)
+            .expect("syn options do not fit in tcp header");
+        self.tcp.syn = true;
+        self.write(nic, self.send.iss, 0)?;
+        self.tcp
+            .set_options_raw(&[])
+            .expect("failed to clear tcp options");
+        Ok(())
+    }
+
+    /// A connection in `state` that hasn't heard from the peer yet.
+    fn new(
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        state: State,
+        config: ConnectionConfig,
+    ) -> Self {
+        let iss = choose_iss(config.iss, local, remote);
+        let wnd = RECVQUEUE_SIZE as u16;
+        let log = config.event_log.then(Vec::new);
         let mut c = Connection {
-            timers: Timers {
-                send_times: Default::default(),
-                srtt: Duration::from_secs(1 * 60).as_secs_f64(),
-            },
-            state: State::SynRcvd,
+            timers: Timers::new(config.now()),
+            congestion: Congestion::new(DEFAULT_MSS),
+            config,
+            mss: DEFAULT_MSS,
+            user_timeout: None,
+            state,
             send: SendSequenceSpace {
                 iss,
                 una: iss,
                 nxt: iss,
-                wnd,
+                wnd: 0,
                 up: false,
 
                 wl1: 0,
                 wl2: 0,
             },
             recv: RecvSequenceSpace {
-                irs: tcph.sequence_number(),
-                nxt: tcph.sequence_number() + 1,
-                wnd: tcph.window_size(),
-                up: false,
+                irs: 0,
+                nxt: 0,
+                wnd,
+                edge: None,
+                up: None,
             },
-            tcp: etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), iss, wnd),
+            tcp: etherparse::TcpHeader::new(local.port(), remote.port(), iss, wnd),
             ip: etherparse::Ipv4Header::new(
                 0,
                 64,
                 etherparse::IpTrafficClass::Tcp,
-                [
-                    iph.destination()[0],
-                    iph.destination()[1],
-                    iph.destination()[2],
-                    iph.destination()[3],
-                ],
-                [
-                    iph.source()[0],
-                    iph.source()[1],
-                    iph.source()[2],
-                    iph.source()[3],
-                ],
+                local.ip().octets(),
+                remote.ip().octets(),
             ),
 
             incoming: Default::default(),
+            recv_capacity: RECVQUEUE_SIZE,
+            pushed: false,
+            defer_ack: false,
+            ack_pending: false,
+            segments_since_ack: 0,
+            flush_requested: false,
+            corked: false,
+            abort_requested: false,
+            advertised_zero_window: false,
+            window_update_pending: false,
+            out_of_order: Default::default(),
+            stalled_segments: 0,
+            dup_acks: 0,
+            fin_at: None,
             unacked: Default::default(),
 
             closed: false,
             closed_at: None,
+            error: None,
+            syn_options: Vec::new(),
+            sack_permitted: false,
+            ao_key: None,
+            send_sne: Sne::default(),
+            recv_sne: Sne::default(),
+            sack_latest: None,
+            log,
+            header_template: None,
+            sent: Throughput::default(),
+            delivered: Throughput::default(),
+            urgent_clamped: 0,
+            drops: Drops::default(),
         };
 
-        // need to start establishing a connection
-        c.tcp.syn = true;
-        c.tcp.ack = true;
-        c.write(nic, c.send.nxt, 0)?;
+        if !c.config.ao_keys.is_empty() {
+            c.ao_key = Some(0);
+        }
+        // we do path mtu discovery (RFC 1191), so routers must not fragment our segments
+        c.ip.dont_fragment = true;
+        c
+    }
+
+    /// Settles on a USER_TIMEOUT given the one on the peer's SYN, if we want one at all.
+    /// Returns the option to put on our own SYN-ACK.
+    fn negotiate_user_timeout(&mut self, tcph: &etherparse::TcpHeaderSlice) -> Option<[u8; 4]> {
+        let local = self.config.user_timeout?;
+        let remote = find_option(tcph.options(), TCP_OPTION_USER_TIMEOUT)
+            .filter(|v| v.len() == 2)
+            .map(|v| decode_user_timeout(u16::from_be_bytes([v[0], v[1]])));
+        // RFC 5482 S3, with no upper or lower limits of our own
+        self.user_timeout = Some(remote.map_or(local, |remote| std::cmp::max(local, remote)));
+        let [hi, lo] = encode_user_timeout(local).to_be_bytes();
+        Some([TCP_OPTION_USER_TIMEOUT, 4, hi, lo])
+    }
+
+    /// A signature option of the right size, but with nothing signed yet.
+    fn signature_placeholder(&self) -> Vec<u8> {
+        match self.ao_key {
+            Some(i) => {
+                let key = &self.config.ao_keys[i];
+                ao::option(key.send_id, key.recv_id, &[0; ao::MAC_LEN])
+            }
+            None => {
+                let mut option = vec![1, 1, TCP_OPTION_MD5, 18];
+                option.extend_from_slice(&[0; 16]);
+                option
+            }
+        }
+    }
+
+    /// The option that signs the segment in `self.tcp`, once serialized as `header` (with the
+    /// placeholder signature in it), with `payload`.
+    fn signature(&mut self, header: &[u8], payload: &[u8]) -> Vec<u8> {
+        if let Some(i) = self.ao_key {
+            let key = &self.config.ao_keys[i];
+            let seq = self.tcp.sequence_number;
+            // a SYN goes out before the peer has picked an ISN, so it counts as zero
+            let peer_isn = if self.tcp.syn && !self.tcp.ack {
+                0
+            } else {
+                self.recv.irs
+            };
+            let traffic_key = ao::traffic_key(
+                &key.master_key,
+                (self.ip.source, self.tcp.source_port, self.send.iss),
+                (self.ip.destination, self.tcp.destination_port, peer_isn),
+            );
+            let mac = ao::mac(
+                &traffic_key,
+                self.send_sne.at(seq),
+                &self.ip.source,
+                &self.ip.destination,
+                header,
+                payload,
+            );
+            // RNextKeyID: we're happy to keep receiving on the same key
+            let option = ao::option(key.send_id, key.recv_id, &mac);
+            self.send_sne.advance(seq);
+            return option;
+        }
+
+        let key = self.config.md5().expect("signing without a key");
+        let mut option = vec![1, 1, TCP_OPTION_MD5, 18];
+        option.extend_from_slice(&md5_signature(
+            &self.ip.source,
+            &self.ip.destination,
+            header,
+            payload,
+            key,
+        ));
+        option
+    }
+
+    /// Whether an incoming segment is signed the way we require, if we require it at all.
+    ///
+    /// With TCP-AO, this also switches to whichever of our keys the peer asked for next.
+    fn signature_ok(
+        &mut self,
+        iph: &etherparse::Ipv4HeaderSlice,
+        tcph: &etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> bool {
+        if let Some(key) = self.config.md5() {
+            return md5_ok(key, iph, tcph, data);
+        }
+        if self.config.ao_keys.is_empty() {
+            return true;
+        }
+
+        let seqn = tcph.sequence_number();
+        // the peer's ISN is on its SYN; until we see that, there's nothing else it can send
+        let isn = if tcph.syn() { seqn } else { self.recv.irs };
+        let other_isn = if tcph.syn() && !tcph.ack() {
+            0
+        } else {
+            self.send.iss
+        };
+        let sne = self.recv_sne.at(seqn);
+        if ao_ok(&self.config.ao_keys, iph, tcph, data, (isn, other_isn), sne).is_none() {
+            return false;
+        }
+        self.recv_sne.advance(seqn);
 
-        Ok(Some(c))
+        // RFC 5925 S7.5.2: RNextKeyID is the key the peer wants to receive on from now on
+        let (_, rnext, _) = ao::parse(tcph.options()).expect("verified segment has a mac");
+        if let Some(i) = self.config.ao_keys.iter().position(|k| k.send_id == rnext) {
+            if self.ao_key != Some(i) {
+                eprintln!("peer asked for tcp-ao key {}; switching", rnext);
+                self.ao_key = Some(i);
+            }
+        }
+        true
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, seq: u32, mut limit: usize) -> io::Result<usize> {
+    fn write(&mut self, nic: &mut Nic, seq: u32, mut limit: usize) -> io::Result<usize> {
         let mut buf = [0u8; 1500];
         // self.tcp.sequence_number = self.send.nxt;
         self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
+        self.tcp.window_size = self.recv.wnd;
         // if !self.tcp.syn &&
 
         // TODO: return +1 for SYN/FIN
-        println!(
-            "write(seq: {}, limit: {}) syn {:?} fin {:?}",
-            seq, limit, self.tcp.syn, self.tcp.fin
-        );
 
-        let mut offset = seq.wrapping_sub(self.send.una) as usize;
+        // a window probe goes out just before SND.UNA, where there is nothing in unacked to send.
+        // neither is there past the end, which is where an ACK sent while our SYN is outstanding
+        // points: the SYN takes up sequence space but isn't in unacked.
+        let mut offset = if wrapping_lt(seq, self.send.una) {
+            self.unacked.len()
+        } else {
+            std::cmp::min(seq.wrapping_sub(self.send.una) as usize, self.unacked.len())
+        };
         // we want self.unacked[nunacked..]
         if let Some(closed_at) = self.closed_at {
             if seq == closed_at.wrapping_add(1) {
@@ -236,12 +1775,47 @@ impl Connection {
             h = &[];
             t = &t[(offset - skipped)..];
         }
+        // options (like a TFO cookie) that were set for just this segment; SACK blocks and a
+        // signature are worked out afresh for every segment, so they get added on top of these
+        let sack = if self.tcp.syn {
+            Vec::new()
+        } else {
+            let room = MAX_OPTIONS_LEN - self.tcp.options().len() - self.config.signature_len();
+            self.sack_option(room)
+        };
+        let signed = self.config.signature_len() != 0;
+        // most segments of a bulk send add neither, and go out with the header as it is. the rest
+        // need the segment's own options put back once they have been written.
+        let mut base_options = Vec::new();
+        let segment_options = if signed || !sack.is_empty() {
+            let segment_options = self.tcp.options().to_vec();
+            base_options.extend_from_slice(&segment_options);
+            base_options.extend(sack);
+            Some(segment_options)
+        } else {
+            None
+        };
+        // the signature goes in as a placeholder first, so the header is the right size
+        if signed {
+            let mut opts = base_options.clone();
+            opts.extend(self.signature_placeholder());
+            self.tcp
+                .set_options_raw(&opts)
+                .expect("signature does not fit in tcp options");
+        } else if segment_options.is_some() {
+            self.tcp
+                .set_options_raw(&base_options)
+                .expect("sack blocks do not fit in tcp options");
+        }
+
         let max_data = std::cmp::min(limit, h.len() + t.len());
         let size = std::cmp::min(
             buf.len(),
             self.tcp.header_len() as usize + self.ip.header_len() + max_data,
         );
-        self.ip.set_payload_len(size - self.ip.header_len());
+        self.ip
+            .set_payload_len(size - self.ip.header_len())
+            .expect("payload too large for ip header");
 
         // the kernel is nice and does this for us
         // self.tcp.checksum = self
@@ -249,13 +1823,28 @@ impl Connection {
         //     .calc_checksum_ipv4(&self.ip, &[])
         //     .expect("failed to compute checksum");
 
+        // most of a bulk send goes out without any options at all, and those segments' headers
+        // only differ in a few fields, which get patched into a copy of the template at the end
+        let plain = !signed
+            && segment_options.is_none()
+            && self.tcp.options().is_empty()
+            && self.ip.header_len() + self.tcp.header_len() as usize == PLAIN_HEADER_LEN;
+
         // write out the header
         let buf_len = buf.len();
         let mut unwritten = &mut buf[..];
-        self.ip.write(&mut unwritten);
+        if plain {
+            unwritten = &mut unwritten[PLAIN_HEADER_LEN..];
+        } else {
+            self.ip
+                .write(&mut unwritten)
+                .expect("failed to write ip header");
+        }
         let ip_header_ends_at = buf_len - unwritten.len();
 
-        unwritten = &mut unwritten[self.tcp.header_len() as usize..];
+        if !plain {
+            unwritten = &mut unwritten[self.tcp.header_len() as usize..];
+        }
         let tcp_header_ends_at = buf_len - unwritten.len();
 
         let payload_bytes = {
@@ -275,97 +1864,760 @@ impl Connection {
         };
         let payload_ends_at = buf_len - unwritten.len();
 
-        self.tcp.checksum = self
-            .tcp
-            .calc_checksum_ipv4(&self.ip, &buf[tcp_header_ends_at..payload_ends_at])
-            .expect("failed to compute checksume");
+        if plain {
+            self.write_plain_header(&mut buf[..payload_ends_at]);
+        } else {
+            if signed {
+                let mut header = Vec::with_capacity(self.tcp.header_len() as usize);
+                self.tcp
+                    .write(&mut header)
+                    .expect("failed to write tcp header");
+                let mut opts = base_options;
+                opts.extend(self.signature(&header, &buf[tcp_header_ends_at..payload_ends_at]));
+                self.tcp
+                    .set_options_raw(&opts)
+                    .expect("signature does not fit in tcp options");
+            }
+
+            self.tcp.checksum = self
+                .tcp
+                .calc_checksum_ipv4(&self.ip, &buf[tcp_header_ends_at..payload_ends_at])
+                .expect("failed to compute checksume");
 
-        let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_header_ends_at];
-        self.tcp.write(&mut tcp_header_buf);
+            let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_header_ends_at];
+            self.tcp
+                .write(&mut tcp_header_buf)
+                .expect("failed to write tcp header");
+        }
+        if let Some(segment_options) = segment_options {
+            self.tcp
+                .set_options_raw(&segment_options)
+                .expect("failed to restore tcp options");
+        }
 
+        if !wrapping_lt(seq, self.send.nxt) {
+            self.sent.record(self.config.now(), payload_bytes);
+        }
+        let sent = LoggedSegment::sent(&self.tcp, payload_bytes);
+        self.log(LogEntry::Out(sent));
         let mut next_seq = seq.wrapping_add(payload_bytes as u32);
         if self.tcp.syn {
             next_seq = next_seq.wrapping_add(1);
             self.tcp.syn = false;
         }
         if self.tcp.fin {
+            // the FIN comes right after the data, wherever that sits relative to our SYN
+            self.closed_at = Some(next_seq);
             next_seq = next_seq.wrapping_add(1);
             self.tcp.fin = false;
         }
+        if next_seq != seq && !wrapping_lt(seq, self.send.una) {
+            // this segment takes the place of whatever was sent from `seq` on before
+            let covered: Vec<u32> = self
+                .timers
+                .send_times
+                .range(seq..)
+                .map(|(&s, _)| s)
+                .take_while(|&s| wrapping_lt(s, next_seq))
+                .collect();
+            for s in &covered {
+                self.timers.send_times.remove(s);
+            }
+            let resent = wrapping_lt(seq, self.send.nxt) || !covered.is_empty();
+            self.timers
+                .send_times
+                .insert(seq, (self.config.now(), resent));
+        }
         if wrapping_lt(self.send.nxt, next_seq) {
             self.send.nxt = next_seq;
         }
-        self.timers.send_times.insert(seq, time::Instant::now());
+        if payload_bytes != 0 || next_seq != seq {
+            self.note_activity(self.config.now());
+            self.timers.last_send = Some(self.config.now());
+            if self.timers.unacked_since.is_none() {
+                self.timers.unacked_since = Some(self.config.now());
+            }
+        }
+
+        // whatever we send carries our latest ACK and window
+        self.ack_pending = false;
+        self.segments_since_ack = 0;
+        self.timers.ack_due = None;
+        self.window_update_pending = false;
+        self.advertised_zero_window = self.recv.wnd == 0;
+        let edge = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+        if self.recv.edge.is_none_or(|old| wrapping_lt(old, edge)) {
+            self.recv.edge = Some(edge);
+        }
 
         nic.send(&buf[..payload_ends_at])?;
         Ok(payload_bytes)
     }
 
-    pub fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        self.tcp.rst = true;
-        // TODO: fix sequence numbers here
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
-        self.write(nic, self.send.nxt, 0)?;
-        Ok(())
+    /// Fills in the headers of a segment without options, whose payload is already in place after
+    /// them in `packet`, from [`Connection::header_template`] and what is in `self.ip` and
+    /// `self.tcp` for this segment.
+    fn write_plain_header(&mut self, packet: &mut [u8]) {
+        let template = match self.header_template {
+            Some(template) => template,
+            None => {
+                // everything but the fields patched below is the same on every segment
+                let mut template = [0u8; PLAIN_HEADER_LEN];
+                let mut unwritten = &mut template[..];
+                self.ip
+                    .write(&mut unwritten)
+                    .expect("failed to write ip header");
+                self.tcp
+                    .write(&mut unwritten)
+                    .expect("failed to write tcp header");
+                *self.header_template.insert(template)
+            }
+        };
+        let (header, payload) = packet.split_at_mut(PLAIN_HEADER_LEN);
+        header.copy_from_slice(&template);
+        let (ip, tcp) = header.split_at_mut(PLAIN_HEADER_LEN - TCP_HEADER_LEN);
+
+        ip[2..4].copy_from_slice(&(packet_len(PLAIN_HEADER_LEN + payload.len())).to_be_bytes());
+        ip[10..12].copy_from_slice(&[0, 0]);
+        let ip_checksum = fold_checksum(sum_words(0, ip));
+        ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+        tcp[4..8].copy_from_slice(&self.tcp.sequence_number.to_be_bytes());
+        tcp[8..12].copy_from_slice(&self.tcp.acknowledgment_number.to_be_bytes());
+        tcp[12] = (TCP_HEADER_LEN as u8 / 4) << 4 | self.tcp.ns as u8;
+        tcp[13] = tcp_flags(&self.tcp);
+        tcp[14..16].copy_from_slice(&self.tcp.window_size.to_be_bytes());
+        tcp[16..18].copy_from_slice(&[0, 0]);
+        tcp[18..20].copy_from_slice(&self.tcp.urgent_pointer.to_be_bytes());
+
+        // RFC 793 S3.1: the pseudo header is the addresses, the protocol and the segment length
+        let segment_len = packet_len(TCP_HEADER_LEN + payload.len());
+        let mut sum = sum_words(0, &ip[12..20]);
+        sum += u32::from(IP_PROTOCOL_TCP) + u32::from(segment_len);
+        sum = sum_words(sum_words(sum, tcp), payload);
+        self.tcp.checksum = fold_checksum(sum);
+        tcp[16..18].copy_from_slice(&self.tcp.checksum.to_be_bytes());
+    }
+
+    /// Notes that something was sent or received at `now`, which puts off every timer that only
+    /// runs while the connection is idle.
+    ///
+    /// Anything that measures idleness goes off `last_activity`, so that this is the only place
+    /// that has to know about them.
+    fn note_activity(&mut self, now: time::Instant) {
+        self.timers.last_activity = now;
+    }
+
+    /// Acknowledges what we have received so far, or arranges for that to happen soon.
+    ///
+    /// The ACK is delayed (RFC 1122 S4.2.3.2) until enough segments are waiting for it or the
+    /// delay runs out, and even then it is held back until the end of a batch. Without a delay,
+    /// it goes out straight away.
+    fn ack(&mut self, nic: &mut Nic) -> io::Result<()> {
+        let delay = self.config.ack_delay.unwrap_or(DEFAULT_ACK_DELAY);
+        if delay.is_zero() {
+            self.write(nic, self.send.nxt, 0)?;
+            return Ok(());
+        }
+        self.segments_since_ack += 1;
+        if self.segments_since_ack < self.config.ack_every.unwrap_or(DEFAULT_ACK_EVERY) {
+            let due = self.config.now() + delay;
+            self.timers.ack_due.get_or_insert(due);
+            return Ok(());
+        }
+        if self.defer_ack {
+            self.ack_pending = true;
+            return Ok(());
+        }
+        self.write(nic, self.send.nxt, 0)?;
+        Ok(())
+    }
+
+    pub fn send_rst(&mut self, nic: &mut Nic) -> io::Result<()> {
+        // <SEQ=SND.NXT><CTL=RST>
+        self.write_rst(nic, self.send.nxt, None)
+    }
+
+    /// Sends a bare RST with the given sequence number, and with ACK set if `ack` is given.
+    ///
+    /// This doesn't touch the connection's own header, so it is safe to use in any state.
+    fn write_rst(&mut self, nic: &mut Nic, seq: u32, ack: Option<u32>) -> io::Result<()> {
+        self.log(LogEntry::Out(LoggedSegment {
+            seq,
+            ack,
+            syn: false,
+            fin: false,
+            rst: true,
+            window: 0,
+            len: 0,
+        }));
+        write_rst(
+            nic,
+            (self.ip.source, self.tcp.source_port),
+            (self.ip.destination, self.tcp.destination_port),
+            seq,
+            ack,
+        )
+    }
+
+    /// Abortively closes the connection, sending a RST if the peer might still think it's open.
+    ///
+    /// `Connection` can't reach the NIC when it is dropped, so this has to be called first.
+    pub fn teardown(&mut self, nic: &mut Nic) -> io::Result<()> {
+        match self.state {
+            // RFC 793 S3.9: in LISTEN and SYN-SENT, an ABORT doesn't send anything
+            State::Listen | State::SynSent | State::Closed | State::TimeWait => {}
+            _ => self.send_rst(nic)?,
+        }
+        self.set_state(State::Closed);
+        Ok(())
+    }
+
+    /// Gives up on the connection without telling the peer, for when there is no way left to
+    /// reach it.
+    pub(crate) fn abort(&mut self, reason: ResetReason) {
+        self.error = Some(reason);
+        self.set_state(State::Closed);
+    }
+
+    /// Throws away everything that is queued in either direction, and has the next tick reset
+    /// the connection (like closing with `SO_LINGER` set to zero).
+    ///
+    /// Data that was written but not yet acknowledged is lost, as is anything received that the
+    /// application hasn't read yet.
+    pub(crate) fn abort_discard(&mut self) {
+        self.unacked.clear();
+        self.incoming.clear();
+        self.out_of_order.clear();
+        self.fin_at = None;
+        self.closed = true;
+        self.abort_requested = true;
+    }
+
+    /// Runs the connection's timers. Returns `true` once it is closed, be that after waiting out
+    /// TIME-WAIT or because it was reset, timed out or aborted, and so can be forgotten.
+    pub fn on_tick(&mut self, nic: &mut Nic) -> io::Result<bool> {
+        if let State::TimeWait = self.state {
+            let now = self.config.now();
+            let since = *self.timers.time_wait_since.get_or_insert(now);
+            let two_msl = 2 * self.config.msl.unwrap_or(DEFAULT_MSL);
+            if now.saturating_duration_since(since) > two_msl {
+                // anything from the connection still on its way has had time to die out
+                eprintln!("{:?} in TimeWait; closing", two_msl);
+                self.set_state(State::Closed);
+                return Ok(true);
+            }
+        }
+        self.on_timers(nic)?;
+        Ok(self.is_closed())
+    }
+
+    fn on_timers(&mut self, nic: &mut Nic) -> io::Result<()> {
+        if let State::Listen | State::Closed = self.state {
+            // nothing left to send, and nobody to send it to
+            return Ok(());
+        }
+
+        if self.abort_requested {
+            self.error = Some(ResetReason::UserAbort);
+            return self.teardown(nic);
+        }
+
+        let now = self.config.now();
+        if let Some(idle_timeout) = self.config.idle_timeout {
+            if let State::SynRcvd | State::Estab = self.state {
+                if now.saturating_duration_since(self.timers.last_activity) > idle_timeout {
+                    eprintln!("connection idle for more than {:?}; closing", idle_timeout);
+                    self.close()?;
+                }
+            }
+        }
+
+        if self
+            .timers
+            .unread_since
+            .is_some_and(|since| now.saturating_duration_since(since) > READ_COALESCE_DELAY)
+        {
+            // the rest of the data isn't coming any time soon, so hand over what we have
+            self.pushed = true;
+        }
+
+        if self.window_update_pending && self.state.is_synchronized() {
+            // the application has made room: <SEQ=SND.NXT><ACK=RCV.NXT><WND=RCV.WND>
+            self.write(nic, self.send.nxt, 0)?;
+        }
+
+        if self.timers.ack_due.is_some_and(|due| now >= due) {
+            // nothing came along for the delayed ACK to ride on
+            self.write(nic, self.send.nxt, 0)?;
+        }
+
+        if let Some(timeout) = self.config.fin_wait2_timeout {
+            if let State::FinWait2 = self.state {
+                // restored snapshots don't carry timers, so start one for them here
+                let since = *self.timers.fin_wait2_since.get_or_insert(now);
+                if now.saturating_duration_since(since) >= timeout {
+                    eprintln!(
+                        "no FIN from the peer after {:?} in FinWait2; giving up",
+                        timeout
+                    );
+                    self.error = Some(ResetReason::Timeout);
+                    if self.config.fin_wait2_reset {
+                        return self.teardown(nic);
+                    }
+                    self.set_state(State::Closed);
+                    return Ok(());
+                }
+            }
+        }
+
+        let nunacked = self.send.nxt.wrapping_sub(self.send.una);
+
+        if let (Some(user_timeout), Some(since)) = (self.user_timeout, self.timers.unacked_since) {
+            if nunacked != 0 && now.saturating_duration_since(since) > user_timeout {
+                eprintln!(
+                    "data unacknowledged for more than {:?}; aborting",
+                    user_timeout
+                );
+                self.error = Some(ResetReason::Timeout);
+                return self.teardown(nic);
+            }
+        }
+
+        if self.send.wnd == 0 && self.state.is_synchronized() && nunacked == 0 {
+            let since = *self.timers.zero_window_since.get_or_insert(now);
+            if now.saturating_duration_since(since) > self.backed_off_rto(self.timers.probes) {
+                // the ACK that reopened the window may have been lost, so ask again with a
+                // segment the peer has to answer: <SEQ=SND.NXT-1><ACK=RCV.NXT>
+                eprintln!("peer window still closed; probing");
+                self.timers.probes += 1;
+                self.timers.zero_window_since = Some(now);
+                self.write(nic, self.send.nxt.wrapping_sub(1), 0)?;
+            }
+        } else {
+            self.timers.zero_window_since = None;
+            self.timers.probes = 0;
+        }
+
+        let should_transmit = self
+            .retransmit_deadline()
+            .is_some_and(|deadline| now > deadline);
+
+        if should_transmit && !self.state.is_synchronized() {
+            self.timers.retransmits += 1;
+            // the peer's user timeout only arrives with its SYN, so nothing else bounds these
+            let (retries, what) = match self.state {
+                State::SynRcvd => (
+                    self.config
+                        .syn_ack_retries
+                        .unwrap_or(DEFAULT_SYN_ACK_RETRIES),
+                    "ack for our syn-ack",
+                ),
+                _ => (
+                    self.config.syn_retries.unwrap_or(DEFAULT_SYN_RETRIES),
+                    "answer to our syn",
+                ),
+            };
+            if self.timers.retransmits > retries {
+                eprintln!("no {} after {} retries; giving up", what, retries);
+                self.error = Some(ResetReason::Timeout);
+                self.set_state(State::Closed);
+                return Ok(());
+            }
+            self.write_syn(nic)?;
+        } else if should_transmit {
+            // we should retransmint things!
+            self.timers.retransmits += 1;
+            if self.timers.retransmits >= PMTU_BLACKHOLE_TIMEOUTS && self.mss > DEFAULT_MSS {
+                eprintln!(
+                    "{} timeouts in a row with mss {}; assuming a pmtu blackhole",
+                    self.timers.retransmits, self.mss
+                );
+                self.mss = DEFAULT_MSS;
+            }
+            self.congestion.on_timeout(nunacked, self.mss);
+            let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32)
+                .min(self.congestion.cwnd)
+                .min(self.mss as u32);
+            if resend == self.unacked.len() as u32 && resend < self.send.wnd as u32 && self.closed {
+                self.tcp.fin = true;
+            }
+            self.write(nic, self.send.una, resend as usize)?;
+        } else if self.flush_requested {
+            self.flush_requested = false;
+            self.flush_output(nic)?;
+        } else {
+            self.send_new_data(nic, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends everything queued that the windows allow right away, even if pacing would have held
+    /// some of it back.
+    ///
+    /// With `config.max_burst` set, that many segments go out and the next tick carries on.
+    pub fn flush_output(&mut self, nic: &mut Nic) -> io::Result<()> {
+        let max_burst = self.config.max_burst.map_or(usize::MAX, |max| max.max(1));
+        for _ in 0..max_burst {
+            if self.send_new_data(nic, false)? == 0 {
+                return Ok(());
+            }
+        }
+        self.flush_requested = true;
+        Ok(())
+    }
+
+    /// Has the next tick do a [`Connection::flush_output`].
+    pub(crate) fn request_flush(&mut self) {
+        self.flush_requested = true;
+    }
+
+    /// Holds back new data while `corked` is set, except for full-sized segments, so that an
+    /// application writing a message in pieces gets it sent in as few segments as possible.
+    /// Uncorking sends whatever is left on the next tick.
+    pub(crate) fn set_cork(&mut self, corked: bool) {
+        if self.corked && !corked {
+            self.flush_requested = true;
+        }
+        self.corked = corked;
+    }
+
+    /// Sends the next segment of new data, if there is any and there's space in the window for
+    /// it. Returns how many bytes of data went out.
+    fn send_new_data(&mut self, nic: &mut Nic, pace: bool) -> io::Result<usize> {
+        let nunacked = self.send.nxt.wrapping_sub(self.send.una);
+        // the SYN and FIN take up sequence space but aren't in self.unacked
+        let unsent = (self.unacked.len() as u32).saturating_sub(nunacked);
+        if !self.state.is_synchronized() || (unsent == 0 && self.closed_at.is_some()) {
+            return Ok(0);
+        }
+
+        if nunacked == 0 && unsent != 0 {
+            let now = self.config.now();
+            if self
+                .timers
+                .last_send
+                .is_some_and(|sent| now.duration_since(sent) > self.rto())
+            {
+                self.congestion.on_idle_restart(self.mss);
+            }
+        }
+
+        let allowed = self.effective_send_window();
+        if allowed == 0 {
+            return Ok(0);
+        }
+
+        if pace && unsent != 0 && self.is_paced() {
+            return Ok(0);
+        }
+
+        if self.corked && !self.closed && unsent < self.mss as u32 {
+            // wait for the rest of the segment (or for the application to uncork)
+            return Ok(0);
+        }
+
+        let send = std::cmp::min(unsent, allowed).min(self.mss as u32);
+        if send == unsent && send < allowed && self.closed && self.closed_at.is_none() {
+            self.tcp.fin = true;
+        }
+        if send == 0 && !self.tcp.fin {
+            // nothing to send; ACKs go out as segments arrive
+            return Ok(0);
+        }
+
+        let sent = self.write(nic, self.send.nxt, send as usize)?;
+        if self.config.pacing && sent != 0 && self.timers.last_rtt.is_some() {
+            // send at a rate of one window per RTT. before there's a sample, srtt is only a
+            // guess, and a conservative one at that.
+            let window = std::cmp::min(self.send.wnd as u32, self.congestion.cwnd);
+            let gap = self.timers.srtt * sent as f64 / std::cmp::max(window, 1) as f64;
+            self.timers.paced_until = Some(self.config.now() + Duration::from_secs_f64(gap));
+        }
+        Ok(sent)
+    }
+
+    /// How much more data we may have in flight right now: whatever is left of the smaller of the
+    /// peer's window and the congestion window once the bytes already in flight are counted.
+    fn effective_send_window(&self) -> u32 {
+        let window = std::cmp::min(self.send.wnd as u32, self.congestion.cwnd);
+        window.saturating_sub(self.send.nxt.wrapping_sub(self.send.una))
+    }
+
+    /// Counts a duplicate ACK, and retransmits the segment at SND.UNA once there have been enough
+    /// of them (RFC 5681 S3.2).
+    ///
+    /// With only a few segments in flight there may never be three duplicate ACKs, so the
+    /// threshold is lowered to one less than the number of outstanding segments when no new data
+    /// can be sent to make up the difference (RFC 5827 S3.1).
+    fn on_dup_ack(&mut self, nic: &mut Nic) -> io::Result<()> {
+        self.dup_acks += 1;
+
+        let mss = self.mss as u32;
+        let nunacked = self.send.nxt.wrapping_sub(self.send.una);
+        let outstanding = nunacked.div_ceil(mss);
+        let unsent = (self.unacked.len() as u32).saturating_sub(nunacked);
+        let can_send_new = unsent != 0 && self.effective_send_window() != 0;
+        let threshold = if outstanding < 4 && !can_send_new {
+            std::cmp::max(outstanding.saturating_sub(1), 1)
+        } else {
+            3
+        };
+        if self.dup_acks != threshold {
+            return Ok(());
+        }
+
+        eprintln!(
+            "{} duplicate acks for {}; fast retransmit",
+            self.dup_acks, self.send.una
+        );
+        self.congestion.on_fast_retransmit(nunacked, self.mss);
+        let resend = std::cmp::min(self.unacked.len() as u32, mss);
+        self.write(nic, self.send.una, resend as usize)?;
+        Ok(())
+    }
+
+    /// RACK (RFC 8985 S6.2), as far as it goes without SACK: once a segment sent at
+    /// RACK.xmit_ts has been ACKed, the segment at SND.UNA must have been lost if it was sent more
+    /// than a reordering window before that.
+    ///
+    /// With only cumulative ACKs, that happens when a retransmission is ACKed while later
+    /// segments of the original flight are still missing. Those then go out again straight away
+    /// rather than one RTO at a time.
+    fn rack_detect_loss(&mut self, nic: &mut Nic) -> io::Result<()> {
+        let (Some(xmit_ts), Some(min_rtt)) = (self.timers.rack_xmit_ts, self.timers.min_rtt) else {
+            return Ok(());
+        };
+        if self.send.nxt == self.send.una {
+            return Ok(());
+        }
+        let sent = match self.timers.send_times.range(self.send.una..).next() {
+            Some((_, &(sent, _))) => sent,
+            None => return Ok(()),
+        };
+        // RFC 8985 S6.2.1
+        let reo_wnd = min_rtt / 4;
+        if sent + reo_wnd >= xmit_ts {
+            return Ok(());
+        }
+
+        // the congestion window already came down for the retransmission that exposed this loss
+        eprintln!("rack: segment at {} was lost", self.send.una);
+        let resend = std::cmp::min(self.unacked.len(), self.mss as usize);
+        self.write(nic, self.send.una, resend)?;
+        Ok(())
+    }
+
+    /// Handles an ICMP error that the network sent back about one of our segments, the one
+    /// whose sequence number it quotes as `seq`.
+    ///
+    /// "Fragmentation needed" (type 3, code 4) shrinks the MSS to fit the reported next-hop MTU,
+    /// and everything in flight is sent again in smaller segments. "Protocol unreachable" and
+    /// "port unreachable" (type 3, codes 2 and 3) are hard errors that abort a connection that
+    /// is still opening (RFC 1122 S4.2.3.9). Anything else is a soft error, and only logged: the
+    /// network may well recover before the connection times out.
+    ///
+    /// An error quoting anything but unacknowledged data is ignored (RFC 5927 S4.1), and so is
+    /// a hard error against a synchronized connection (RFC 5927 S4.2), so that a blind attacker
+    /// can neither reset the connection nor shrink its MSS.
+    pub fn on_icmp_error(
+        &mut self,
+        nic: &mut Nic,
+        icmp_type: u8,
+        icmp_code: u8,
+        next_hop_mtu: u16,
+        seq: u32,
+    ) -> io::Result<()> {
+        // SND.UNA =< SEG.SEQ < SND.NXT
+        if !is_between_wrapped(self.send.una.wrapping_sub(1), seq, self.send.nxt) {
+            eprintln!(
+                "icmp error for seq {} outside {}..{}; ignoring",
+                seq, self.send.una, self.send.nxt
+            );
+            return Ok(());
+        }
+        match (icmp_type, icmp_code) {
+            (3, 4) => {}
+            (3, 2) | (3, 3) if !self.state.is_synchronized() => {
+                eprintln!("hard icmp error in {:?}; aborting", self.state);
+                self.error = Some(if let State::SynSent = self.state {
+                    ResetReason::RefusedOnConnect
+                } else {
+                    ResetReason::Unreachable
+                });
+                self.set_state(State::Closed);
+                return Ok(());
+            }
+            _ => {
+                eprintln!("soft icmp error in {:?}; carrying on", self.state);
+                return Ok(());
+            }
+        }
+
+        let mss = if next_hop_mtu == 0 {
+            // pre-RFC 1191 routers don't tell us the MTU, so play it safe
+            DEFAULT_MSS
+        } else {
+            // minus the IP and TCP headers, and never below the minimum IP MTU of 68
+            std::cmp::max(next_hop_mtu, 68) - 40
+        };
+        if mss >= self.mss {
+            return Ok(());
+        }
+        eprintln!(
+            "path mtu is {}; shrinking mss {} -> {}",
+            next_hop_mtu, self.mss, mss
+        );
+        self.mss = mss;
+
+        // everything past SND.UNA was sent too large, so go back and send it again
+        if let State::Estab | State::FinWait1 | State::Closing | State::CloseWait | State::LastAck =
+            self.state
+        {
+            self.send.nxt = self.send.una;
+            self.closed_at = None;
+        }
+        self.on_timers(nic)
     }
 
-    pub fn on_tick(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        let nunacked = self.send.nxt.wrapping_sub(self.send.una);
-        let unsent = self.unacked.len() as u32 - nunacked;
+    /// The current retransmission timeout.
+    fn rto(&self) -> Duration {
+        if self.timers.last_rtt.is_none() {
+            return self.config.initial_rto.unwrap_or(DEFAULT_INITIAL_RTO);
+        }
+        let min = self.config.min_rto.unwrap_or(DEFAULT_MIN_RTO);
+        let max = self.config.max_rto.unwrap_or(DEFAULT_MAX_RTO);
+        // don't panic in clamp over a misconfiguration; the upper bound wins
+        Duration::from_secs_f64(1.5 * self.timers.srtt)
+            .max(min)
+            .min(max)
+    }
 
-        let waited_for = self
-            .timers
+    /// When the oldest unacknowledged segment is due to be retransmitted, if anything is in
+    /// flight.
+    ///
+    /// The timeout doubles with every retransmission that goes unacknowledged (RFC 6298 S5.5),
+    /// up to the maximum RTO.
+    fn retransmit_deadline(&self) -> Option<time::Instant> {
+        if self.send.nxt == self.send.una {
+            return None;
+        }
+        let rto = self.backed_off_rto(self.timers.retransmits);
+        self.timers
             .send_times
             .range(self.send.una..)
             .next()
-            .map(|t| t.1.elapsed());
-
-        let should_transmit = if let Some(waited_for) = waited_for {
-            waited_for > time::Duration::from_secs(1)
-                && waited_for.as_secs_f64() > 1.5 * self.timers.srtt
-        } else {
-            false
-        };
+            .map(|(_, &(sent, _))| sent + rto)
+    }
 
-        if should_transmit {
-            // we should retransmint things!
-            let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32);
-            if resend < self.send.wnd as u32 && self.closed {
-                self.tcp.fin = true;
-                self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
-            }
-            self.write(nic, self.send.una, resend as usize)?;
-        } else {
-            // we should send new data if we have new data and space in the window
-            if unsent == 0 && self.closed_at.is_some() {
-                return Ok(());
-            }
+    /// The retransmission timeout after it has been doubled `n` times, up to the maximum RTO.
+    fn backed_off_rto(&self, n: u32) -> Duration {
+        let max = self.config.max_rto.unwrap_or(DEFAULT_MAX_RTO);
+        self.rto()
+            .saturating_mul(1 << std::cmp::min(n, 16))
+            .min(max)
+    }
 
-            let allowed = self.send.wnd as u32 - nunacked;
-            if allowed == 0 {
-                return Ok(());
-            }
+    /// The earliest time at which [`Connection::on_tick`] has something to do, if any timer is
+    /// armed.
+    ///
+    /// An event loop can sleep until then rather than ticking on a fixed interval. Note that
+    /// data queued by the application also needs a tick to go out.
+    pub fn next_timeout(&self) -> Option<time::Instant> {
+        if let State::Closed = self.state {
+            return None;
+        }
 
-            let send = std::cmp::min(unsent, allowed);
-            if send < allowed && self.closed && self.closed_at.is_none() {
-                self.tcp.fin = true;
-                self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
+        let mut deadlines = vec![self.retransmit_deadline()];
+        if let State::SynRcvd | State::Estab = self.state {
+            deadlines.push(
+                self.config
+                    .idle_timeout
+                    .map(|idle| self.timers.last_activity + idle),
+            );
+        }
+        if self.send.nxt != self.send.una {
+            if let (Some(user_timeout), Some(since)) =
+                (self.user_timeout, self.timers.unacked_since)
+            {
+                deadlines.push(Some(since + user_timeout));
             }
+        }
+        if self.is_paced() {
+            deadlines.push(self.timers.paced_until);
+        }
+        deadlines.push(self.timers.ack_due);
+        if let State::TimeWait = self.state {
+            let two_msl = 2 * self.config.msl.unwrap_or(DEFAULT_MSL);
+            deadlines.push(self.timers.time_wait_since.map(|since| since + two_msl));
+        }
+        if let (State::FinWait2, Some(timeout)) = (&self.state, self.config.fin_wait2_timeout) {
+            deadlines.push(self.timers.fin_wait2_since.map(|since| since + timeout));
+        }
+        if let Some(since) = self.timers.zero_window_since {
+            deadlines.push(Some(since + self.backed_off_rto(self.timers.probes)));
+        }
+        if self.config.read_low_water.is_some() && !self.pushed {
+            deadlines.push(
+                self.timers
+                    .unread_since
+                    .map(|since| since + READ_COALESCE_DELAY),
+            );
+        }
+        deadlines.into_iter().flatten().min()
+    }
 
-            self.write(nic, self.send.nxt, send as usize)?;
+    /// Whether pacing is holding back new data right now.
+    fn is_paced(&self) -> bool {
+        if !self.config.pacing || self.timers.last_rtt.is_none() {
+            return false;
         }
+        self.timers
+            .paced_until
+            .is_some_and(|t| self.config.now() < t)
+    }
 
-        Ok(())
+    /// Like [`Connection::on_packet`] for each of `segments` in turn, except that in-order data
+    /// is acknowledged once at the end rather than segment by segment.
+    pub fn on_packets(&mut self, nic: &mut Nic, segments: &[Segment<'_>]) -> io::Result<Available> {
+        self.defer_ack = true;
+        let result = segments.iter().try_for_each(|s| {
+            self.on_packet(nic, s.iph.clone(), s.tcph.clone(), s.data)
+                .map(|_| ())
+        });
+        self.defer_ack = false;
+        result?;
+
+        if self.ack_pending && !matches!(self.state, State::Closed) {
+            self.write(nic, self.send.nxt, 0)?;
+        }
+        Ok(self.availability())
     }
 
     pub fn on_packet<'a>(
         &mut self,
-        nic: &mut tun_tap::Iface,
+        nic: &mut Nic,
         iph: etherparse::Ipv4HeaderSlice<'a>,
         tcph: etherparse::TcpHeaderSlice<'a>,
         data: &'a [u8],
     ) -> io::Result<Available> {
+        if !checksum_ok(&iph, &tcph, data) {
+            // the kernel doesn't check these on a tun device, so it's up to us
+            eprintln!("dropping segment with a bad checksum");
+            self.drops.checksum += 1;
+            return Ok(self.availability());
+        }
+        if !self.signature_ok(&iph, &tcph, data) {
+            // RFC 2385 S2.0, RFC 5925 S7.6: drop it without so much as an ACK
+            eprintln!("dropping segment with a missing or bad signature");
+            self.drops.signature += 1;
+            return Ok(self.availability());
+        }
+
+        self.note_activity(self.config.now());
+        self.log(LogEntry::In(LoggedSegment::received(&tcph, data.len())));
+
         // first, check that sequence numbers are valid
         let seqn = tcph.sequence_number();
         let mut slen = data.len() as u32;
@@ -375,105 +2627,291 @@ impl Connection {
         if tcph.syn() {
             slen += 1;
         }
+
+        if let State::Estab = self.state {
+            if slen == 0 && seqn == self.recv.nxt && tcph.ack() && !tcph.rst() {
+                // a pure ACK right at RCV.NXT, which is most of what a bulk sender gets back.
+                // it passes every check below and has nothing for the receive side, so skip
+                // straight to the ACK processing.
+                self.on_ack(nic, &tcph, data)?;
+                return Ok(self.availability());
+            }
+        }
+
+        if let State::Closed = self.state {
+            reset_segment(nic, &iph, &tcph, data.len())?;
+            return Ok(self.availability());
+        }
+
+        if let State::Listen = self.state {
+            self.on_listen(nic, &iph, &tcph, data)?;
+            return Ok(self.availability());
+        }
+
+        if let State::SynSent = self.state {
+            return self.on_syn_sent(nic, &tcph);
+        }
+
+        if let State::TimeWait = self.state {
+            // all we're waiting for here is a retransmission of the peer's FIN, in case our ACK
+            // of it got lost, so ACK it again. anything else, RSTs included (RFC 1337), is
+            // dropped.
+            if tcph.fin() && !tcph.rst() {
+                eprintln!("retransmitted FIN in TimeWait");
+                self.write(nic, self.send.nxt, 0)?;
+                self.timers.time_wait_since = Some(self.config.now());
+            }
+            return Ok(self.availability());
+        }
+
+        // fourth, check the SYN bit, ahead of the sequence numbers
+        if let State::SynRcvd = self.state {
+            if tcph.syn()
+                && tcph.ack()
+                && seqn.wrapping_add(1) == self.recv.nxt
+                && tcph.acknowledgment_number() == self.send.nxt
+                && slen == 1
+            {
+                // a simultaneous open (RFC 793 S3.4, figure 8): this is the peer's SYN again,
+                // now ACKing ours, and so all that is new about it is the ACK
+                eprintln!("SYN-ACK in SynRcvd; simultaneous open");
+                self.on_ack(nic, &tcph, data)?;
+                return Ok(self.availability());
+            }
+        }
+        if tcph.syn() {
+            // RFC 5961 S4.2: whatever its sequence number, a SYN on a synchronized connection
+            // only gets a challenge ACK. if the peer really has restarted, it'll answer that with
+            // a RST, and otherwise we haven't let a blind attacker reset us. the same goes for
+            // SYN-RECEIVED, where we already have the peer's SYN: another one, or one carrying
+            // data, mustn't move RCV.NXT, and a plain retransmission only needs our ACK anyway.
+            eprintln!("SYN in {:?}; sending challenge ack", self.state);
+            self.write(nic, self.send.nxt, 0)?;
+            return Ok(self.availability());
+        }
+
         let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
         let okay = if slen == 0 {
             // zero-length segment has separate rules for acceptance
             if self.recv.wnd == 0 {
-                if seqn != self.recv.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
-                false
+                seqn == self.recv.nxt
             } else {
-                true
+                is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
             }
+        } else if self.recv.wnd == 0 {
+            // a full buffer still has to hear the peer close: a bare FIN takes up no window, so
+            // it's acceptable right at RCV.NXT
+            data.is_empty() && tcph.fin() && seqn == self.recv.nxt
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
-                && !is_between_wrapped(
+            is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
+                || is_between_wrapped(
                     self.recv.nxt.wrapping_sub(1),
                     seqn.wrapping_add(slen - 1),
                     wend,
                 )
-            {
-                false
-            } else {
-                true
-            }
+                // RFC 793 only asks whether either end is in the window, which turns away a
+                // segment that overhangs both edges. it still covers RCV.NXT, and the peer would
+                // only resend it the same way, so take it and trim both ends.
+                || is_between_wrapped(seqn.wrapping_sub(1), self.recv.nxt, seqn.wrapping_add(slen))
         };
 
         if !okay {
             eprintln!("NOT OKAY");
-            self.write(nic, self.send.nxt, 0)?;
+            if slen != 0 && self.recv.wnd == 0 {
+                self.drops.zero_window += 1;
+            } else {
+                self.drops.out_of_window += 1;
+            }
+            // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>, to get the peer back in step, unless it was a
+            // RST (RFC 793 S3.9). a retransmission of the FIN we already have is answered even
+            // when dropping the rest quietly: our ACK of it was lost, and nothing else will get
+            // the peer out of LAST-ACK.
+            let repeated_fin = tcph.fin() && seqn.wrapping_add(slen) == self.recv.nxt;
+            if !tcph.rst() && (!self.config.drop_unacceptable || repeated_fin) {
+                self.write(nic, self.send.nxt, 0)?;
+            }
+            return Ok(self.availability());
+        }
+
+        // second, check the RST bit
+        if tcph.rst() {
+            // an acceptable RST tears the connection down, whatever state we're in. anything
+            // already received can still be read before the application hears about it.
+            eprintln!("connection reset by peer in {:?}", self.state);
+            self.error = Some(ResetReason::PeerReset);
+            self.set_state(State::Closed);
             return Ok(self.availability());
         }
 
         if !tcph.ack() {
-            if tcph.syn() {
-                // got SYN part of initial handshake
-                assert!(data.is_empty());
-                self.recv.nxt = seqn.wrapping_add(1);
-            }
             return Ok(self.availability());
         }
-        // TODO: if _not_acceptable, send ACK
-        // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
-        //
-        // valid segment check, ok if it acks at least one byte, which means that at least one of
-        // the following is true:
-        //
-        // RCV.NXT =< SEG.SEQ < RCV.NXT + RCV.WND
-        // RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
-        //
+        // fifth, check the ACK field
+        if !self.on_ack(nic, &tcph, data)? {
+            return Ok(self.availability());
+        }
+
+        if slen == 0 {
+            // a pure ACK (or window update, or keepalive reply) has nothing for the receive side,
+            // and mustn't be ACKed itself
+            return Ok(self.availability());
+        }
+
+        // seventh, process the segment text
+        let fin = self.on_text(nic, &tcph, data)?;
+
+        // eighth, check the FIN bit
+        if fin {
+            eprintln!("IS FIN (in {:?})", self.state);
+            match self.state {
+                State::Estab => {
+                    // the peer is done sending, and has had our ACK. the application can still
+                    // read what's left, and send until it closes its end too.
+                    self.set_state(State::CloseWait);
+                }
+                State::FinWait1 => {
+                    // our FIN and the peer's crossed on the wire: it hasn't ACKed ours yet, or
+                    // we'd be in FIN-WAIT-2 by now. we've ACKed its FIN; once ours is ACKed too,
+                    // we're done.
+                    self.set_state(State::Closing);
+                }
+                State::FinWait2 => {
+                    // recv.nxt is already past the FIN, and the ACK for it has gone out with the
+                    // rest of the segment. we're done, save for hanging around in case that ACK
+                    // gets lost.
+                    self.set_state(State::TimeWait);
+                }
+                State::Closing | State::CloseWait | State::LastAck => {
+                    // we already have the peer's FIN, so this changes nothing (RFC 793 S3.9)
+                }
+                // every other state has either returned before getting to the segment text, or
+                // (SYN-RECEIVED) moved on with the ACK
+                _ => unreachable!("FIN processed in {:?}", self.state),
+            }
+        }
 
-        // if tcph.acknowledgment_number()
+        Ok(self.availability())
+    }
 
+    /// The ACK half of [`Connection::on_packet`]: takes acknowledged data off the send queue,
+    /// updates the timers, congestion control and send window, and moves the state along if
+    /// that acknowledged our SYN or FIN.
+    ///
+    /// Returns `false` if the segment got the connection reset, and shouldn't be looked at any
+    /// further.
+    fn on_ack(
+        &mut self,
+        nic: &mut Nic,
+        tcph: &etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<bool> {
+        let seqn = tcph.sequence_number();
         let ackn = tcph.acknowledgment_number();
+        let mut syn_acked = false;
         if let State::SynRcvd = self.state {
-            // expect to get an ACK for out SYN
-            if is_between_wrapped(
-                self.send.una.wrapping_sub(1),
-                ackn,
-                self.send.nxt.wrapping_add(1),
-            ) {
+            // expect to get an ACK for out SYN: SND.UNA < SEG.ACK =< SND.NXT
+            if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+                syn_acked = true;
                 // must have ACKed our SYN, since we detected at least one acked byte,
-                // and we have only sent one byte (the SYN).
-                self.state = State::Estab;
+                // and we have only sent one byte (the SYN). the ACK itself needs no answer: if
+                // it carries nothing else, nothing goes back until there's data or a FIN to send.
+                // if we were closed in the meantime, that FIN can go now.
+                self.set_state(if self.closed {
+                    State::FinWait1
+                } else {
+                    State::Estab
+                });
             } else {
-                // TODO: RST: <SEQ=SEG.ACK><CTL=RST>
+                // whatever this is acknowledging, it isn't our SYN: <SEQ=SEG.ACK><CTL=RST>
+                eprintln!("unacceptable ack {} in SynRcvd; resetting", ackn);
+                self.write_rst(nic, ackn, None)?;
+                self.set_state(State::Closed);
+                return Ok(false);
             }
         }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+        if let State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::Closing
+        | State::CloseWait
+        | State::LastAck = self.state
+        {
+            // RFC 5681 S2
+            let dup_ack = ackn == self.send.una
+                && self.send.nxt != self.send.una
+                && data.is_empty()
+                && !tcph.fin()
+                && tcph.window_size() == self.send.wnd;
+
             if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
-                println!(
-                    "ack for {} (last: {}); prune in {:?}",
-                    ackn, self.send.una, self.unacked
-                );
+                println!("ack for {} (last: {})", ackn, self.send.una);
                 if !self.unacked.is_empty() {
-                    let nacked = self
-                        .unacked
-                        .drain(..ackn.wrapping_sub(self.send.una) as usize)
-                        .count();
+                    // our SYN and FIN take up a sequence number each, but have no byte in unacked
+                    let acked = std::cmp::min(
+                        ackn.wrapping_sub(self.send.una) as usize - syn_acked as usize,
+                        self.unacked.len(),
+                    );
+                    self.unacked.drain(..acked);
 
                     let una = self.send.una;
+                    let now = self.config.now();
                     let srtt = &mut self.timers.srtt;
-                    let old = std::mem::replace(&mut self.timers.send_times, BTreeMap::new());
-                    self.timers
-                        .send_times
-                        .extend(old.into_iter().filter_map(|(seq, sent)| {
-                            if is_between_wrapped(una, seq, ackn) {
-                                *srtt = 0.8 * *srtt + (1.0 - 0.8) * sent.elapsed().as_secs_f64();
-                                None
-                            } else {
-                                Some((seq, sent))
+                    let last_rtt = &mut self.timers.last_rtt;
+                    let min_rtt = &mut self.timers.min_rtt;
+                    let xmit_ts = &mut self.timers.rack_xmit_ts;
+                    let old = std::mem::take(&mut self.timers.send_times);
+                    self.timers.send_times.extend(old.into_iter().filter_map(
+                        |(seq, (sent, resent))| {
+                            if !is_between_wrapped(una.wrapping_sub(1), seq, ackn) {
+                                return Some((seq, (sent, resent)));
                             }
-                        }));
+                            if !resent {
+                                let rtt = now.saturating_duration_since(sent);
+                                // RFC 6298 S2.2: the first sample stands on its own
+                                *srtt = match *last_rtt {
+                                    None => rtt.as_secs_f64(),
+                                    Some(_) => 0.8 * *srtt + (1.0 - 0.8) * rtt.as_secs_f64(),
+                                };
+                                *last_rtt = Some(rtt);
+                                *min_rtt = Some(min_rtt.map_or(rtt, |min| min.min(rtt)));
+                            }
+                            *xmit_ts = Some(xmit_ts.map_or(sent, |ts| ts.max(sent)));
+                            None
+                        },
+                    ));
+                }
+                if ackn != self.send.una {
+                    self.timers.retransmits = 0;
+                    self.timers.unacked_since = if ackn == self.send.nxt {
+                        None
+                    } else {
+                        Some(self.config.now())
+                    };
                 }
+                self.congestion
+                    .on_ack(ackn.wrapping_sub(self.send.una), self.mss);
                 self.send.una = ackn;
+                self.dup_acks = 0;
+                if self.config.rack {
+                    self.rack_detect_loss(nic)?;
+                }
+            } else if dup_ack {
+                self.on_dup_ack(nic)?;
+            }
+
+            // RFC 793 S3.9: only take the window from segments newer than the last one we took it
+            // from, so that a reordered old segment can't shrink it again
+            if is_between_wrapped(
+                self.send.una.wrapping_sub(1),
+                ackn,
+                self.send.nxt.wrapping_add(1),
+            ) && (wrapping_lt(self.send.wl1, seqn)
+                || (self.send.wl1 == seqn && !wrapping_lt(ackn, self.send.wl2)))
+            {
+                self.send.wnd = tcph.window_size();
+                self.send.wl1 = seqn;
+                self.send.wl2 = ackn;
             }
 
             // TODO: prune self.unacked
@@ -482,48 +2920,260 @@ impl Connection {
         }
 
         if let State::FinWait1 = self.state {
-            if self.send.una == self.send.iss + 2 {
-                // our FIN has been ACKed!
-                self.state = State::FinWait2;
+            if self
+                .closed_at
+                .is_some_and(|at| wrapping_lt(at, self.send.una))
+            {
+                // SND.UNA has moved past our FIN, so it has been ACKed, along with whatever came
+                // before it! the peer can carry on sending until it closes its end
+                self.set_state(State::FinWait2);
+            }
+        }
+        if let State::Closing = self.state {
+            if self
+                .closed_at
+                .is_some_and(|at| wrapping_lt(at, self.send.una))
+            {
+                // both FINs have been ACKed, so all that's left is hanging around in case the
+                // peer didn't get our ACK of its FIN
+                self.set_state(State::TimeWait);
+            }
+        }
+        if let State::LastAck = self.state {
+            if self
+                .closed_at
+                .is_some_and(|at| wrapping_lt(at, self.send.una))
+            {
+                // the peer had already closed its end, so with our FIN ACKed there is nothing
+                // left of the connection
+                self.set_state(State::Closed);
             }
         }
+        Ok(true)
+    }
 
+    /// The receiving half of [`Connection::on_packet`], for a segment that takes up sequence
+    /// space: buffers its data, in order or not, and acknowledges it.
+    ///
+    /// Returns whether that brought us to the peer's FIN.
+    fn on_text(
+        &mut self,
+        nic: &mut Nic,
+        tcph: &etherparse::TcpHeaderSlice,
+        data: &[u8],
+    ) -> io::Result<bool> {
+        let seqn = tcph.sequence_number();
+        let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+        let mut fin = tcph.fin();
         if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            let mut unread_data_at = (self.recv.nxt - seqn) as usize;
+            if wrapping_lt(self.recv.nxt, seqn) {
+                // there's a hole in front of this segment, so hang on to it until the hole has
+                // been filled. we can't act on a FIN until then either.
+                let keep = std::cmp::min(data.len(), wend.wrapping_sub(seqn) as usize);
+                eprintln!(
+                    "out-of-order segment at {} (expected {}); buffering {}b",
+                    seqn, self.recv.nxt, keep
+                );
+                // a bare FIN has no data to hold on to, or to SACK: a block can't be empty
+                // (RFC 2018 S3)
+                if keep > 0 {
+                    let buffered = self.out_of_order.entry(seqn).or_default();
+                    if buffered.len() < keep {
+                        *buffered = data[..keep].to_vec();
+                    }
+                    self.sack_latest = Some(seqn);
+                }
+                if fin && keep == data.len() {
+                    self.fin_at = Some(seqn.wrapping_add(keep as u32));
+                }
+
+                // data keeps arriving but the hole isn't getting filled. most likely the same
+                // segment is being lost over and over, which is worth telling someone about.
+                self.stalled_segments += 1;
+                if self
+                    .stalled_segments
+                    .is_multiple_of(STUCK_RECEIVER_SEGMENTS)
+                {
+                    let hole_end = self.out_of_order_ranges()[0].0;
+                    eprintln!(
+                        "receiver stuck: still missing {}..{} after {} out-of-order segments",
+                        self.recv.nxt, hole_end, self.stalled_segments
+                    );
+                    self.log(LogEntry::Stuck(self.recv.nxt, hole_end));
+                }
+                self.evict_out_of_order();
+                // let the peer know where the hole is: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>. this
+                // duplicate ACK is what drives the peer's fast retransmit, so it goes out straight
+                // away rather than being held back like other ACKs (RFC 5681 S4.2).
+                self.write(nic, self.send.nxt, 0)?;
+                return Ok(false);
+            }
+
+            let mut unread_data_at = self.recv.nxt.wrapping_sub(seqn) as usize;
             if unread_data_at > data.len() {
                 // we must have received a re-transmitted FIN that we have already seen
                 // nxt points to beyond the fin, but the fin is not in data!
                 assert_eq!(unread_data_at, data.len() + 1);
                 unread_data_at = 0;
             }
-            self.incoming.extend(&data[unread_data_at..]);
 
-            self.recv.nxt = seqn
-                .wrapping_add(data.len() as u32)
-                .wrapping_add(if tcph.fin() { 1 } else { 0 });
+            // trim both ends to the window: whatever comes before RCV.NXT we already have, and
+            // only what fits up to RCV.NXT + RCV.WND is taken. the rest is dropped (along with
+            // any FIN that came after it), and the peer will have to retransmit it later.
+            let accept_end = std::cmp::min(data.len(), unread_data_at + self.recv.wnd as usize);
+            let overran = accept_end < data.len();
+            if overran {
+                eprintln!(
+                    "segment overruns receive window; dropping {}b",
+                    data.len() - accept_end
+                );
+                fin = false;
+            }
+            if tcph.urg() {
+                self.on_urgent(seqn, tcph.urgent_pointer(), data.len());
+            }
+            self.deliver(&data[unread_data_at..accept_end]);
+            if unread_data_at < accept_end {
+                self.stalled_segments = 0;
+            }
+
+            let filled_hole = !self.out_of_order.is_empty();
+            self.recv.nxt =
+                seqn.wrapping_add(accept_end as u32)
+                    .wrapping_add(if fin { 1 } else { 0 });
+            if fin {
+                self.fin_at = None;
+            } else {
+                self.reassemble();
+                if self.fin_at.is_some_and(|at| at == self.recv.nxt) {
+                    // that filled the last hole in front of a FIN we've been holding on to
+                    self.fin_at = None;
+                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                    fin = true;
+                }
+            }
+            self.received(tcph.psh() || fin);
             // Send an acknowledgement of the form: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
-            self.write(nic, self.send.nxt, 0)?;
+            if filled_hole {
+                // the peer is waiting to hear that its retransmission made it (RFC 5681 S4.2)
+                self.write(nic, self.send.nxt, 0)?;
+            } else if fin {
+                // there's nothing more coming for a delayed ACK to cover
+                self.write(nic, self.send.nxt, 0)?;
+            } else if overran {
+                // the peer is sending past our window; tell it where the window really ends
+                // before it sends any more that we'll only drop
+                self.write(nic, self.send.nxt, 0)?;
+            } else {
+                self.ack(nic)?;
+            }
         }
+        Ok(fin)
+    }
 
-        if tcph.fin() {
-            eprintln!("IS FIN (in {:?})", self.state);
-            match self.state {
-                State::FinWait2 => {
-                    self.write(nic, self.send.nxt, 0)?;
-                    self.state = State::TimeWait;
-                }
-                _ => unimplemented!(),
+    /// Moves RCV.UP up to the end of the urgent data in a segment starting at `seqn` (RFC 793
+    /// S3.9, sixth, check the URG bit).
+    ///
+    /// The urgent pointer is the peer's word only, so one that points past the `len` bytes the
+    /// segment actually carries is cut back to its end rather than believed.
+    fn on_urgent(&mut self, seqn: u32, pointer: u16, len: usize) {
+        let mut pointer = pointer as usize;
+        if pointer > len {
+            eprintln!(
+                "urgent pointer {} is past the end of a {}b segment; clamping it",
+                pointer, len
+            );
+            self.urgent_clamped += 1;
+            pointer = len;
+        }
+        let up = seqn.wrapping_add(pointer as u32);
+        if self.recv.up.is_none_or(|old| wrapping_lt(old, up)) {
+            self.recv.up = Some(up);
+        }
+    }
+
+    /// Handles a segment arriving in SYN-SENT (RFC 793 S3.9).
+    fn on_syn_sent(
+        &mut self,
+        nic: &mut Nic,
+        tcph: &etherparse::TcpHeaderSlice,
+    ) -> io::Result<Available> {
+        let seqn = tcph.sequence_number();
+        let ackn = tcph.acknowledgment_number();
+        if tcph.ack() && !is_between_wrapped(self.send.iss, ackn, self.send.nxt.wrapping_add(1)) {
+            // not an answer to our SYN (a stale or spoofed segment, perhaps), so we stay in
+            // SYN-SENT. unless this is a RST: <SEQ=SEG.ACK><CTL=RST>
+            eprintln!("unacceptable ack {} in SynSent", ackn);
+            if !tcph.rst() {
+                self.write_rst(nic, ackn, None)?;
+            }
+            return Ok(self.availability());
+        }
+
+        if tcph.rst() {
+            if tcph.ack() {
+                eprintln!("connection refused");
+                self.error = Some(ResetReason::RefusedOnConnect);
+                self.set_state(State::Closed);
             }
+            return Ok(self.availability());
+        }
+
+        if !tcph.syn() {
+            return Ok(self.availability());
         }
 
+        self.recv.irs = seqn;
+        self.recv.nxt = seqn.wrapping_add(1);
+        self.send.wnd = tcph.window_size();
+        self.send.wl1 = seqn;
+        self.send.wl2 = ackn;
+        self.mss = peer_mss(tcph);
+        // we only offered it if it's configured
+        self.sack_permitted =
+            self.config.sack && find_option(tcph.options(), TCP_OPTION_SACK_PERMITTED).is_some();
+        // keep any ssthresh we were seeded with
+        self.congestion.cwnd = Congestion::initial_window(self.mss);
+        // we already told the peer what we want on our SYN
+        let _ = self.negotiate_user_timeout(tcph);
+
+        if tcph.ack() {
+            // our SYN has been ACKed
+            self.send.una = ackn;
+            self.timers.unacked_since = None;
+            self.set_state(State::Estab);
+            self.tcp.ack = true;
+            self.write(nic, self.send.nxt, 0)?;
+        } else {
+            // simultaneous open: our SYNs crossed (RFC 793 figure 8). we still need the peer to
+            // ACK our SYN, so send it again along with an ACK of the peer's.
+            eprintln!("simultaneous open");
+            self.set_state(State::SynRcvd);
+            self.tcp.ack = true;
+            self.write_syn(nic)?;
+        }
         Ok(self.availability())
     }
 
     pub fn close(&mut self) -> io::Result<()> {
-        self.closed = true;
         match self.state {
+            State::Listen => {
+                // with no peer yet, there's nobody to tell
+                self.set_state(State::Closed);
+            }
+            State::SynRcvd if !self.unacked.is_empty() => {
+                // RFC 793 S3.9: data the application has queued waits for the handshake to
+                // finish, and so does the FIN that follows it
+                self.closed = true;
+            }
             State::SynRcvd | State::Estab => {
-                self.state = State::FinWait1;
+                self.closed = true;
+                self.set_state(State::FinWait1);
+            }
+            State::CloseWait => {
+                // our FIN goes out once whatever is still queued has been sent
+                self.closed = true;
+                self.set_state(State::LastAck);
             }
             State::FinWait1 | State::FinWait2 => {}
             _ => {
@@ -537,6 +3187,315 @@ impl Connection {
     }
 }
 
+/// Answers a segment that has no connection to go to the way RFC 793 S3.9 says a CLOSED
+/// connection should: anything but a RST gets a RST back.
+pub fn reset_segment(
+    nic: &mut Nic,
+    iph: &etherparse::Ipv4HeaderSlice,
+    tcph: &etherparse::TcpHeaderSlice,
+    data_len: usize,
+) -> io::Result<()> {
+    if tcph.rst() {
+        return Ok(());
+    }
+
+    let local = (
+        iph.destination()
+            .try_into()
+            .expect("ipv4 address is 4 bytes"),
+        tcph.destination_port(),
+    );
+    let remote = (
+        iph.source().try_into().expect("ipv4 address is 4 bytes"),
+        tcph.source_port(),
+    );
+    if tcph.ack() {
+        // <SEQ=SEG.ACK><CTL=RST>
+        write_rst(nic, local, remote, tcph.acknowledgment_number(), None)
+    } else {
+        // <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+        let mut slen = data_len as u32;
+        if tcph.syn() {
+            slen += 1;
+        }
+        if tcph.fin() {
+            slen += 1;
+        }
+        write_rst(
+            nic,
+            local,
+            remote,
+            0,
+            Some(tcph.sequence_number().wrapping_add(slen)),
+        )
+    }
+}
+
+/// Sends a bare RST from `local` to `remote`, with ACK set if `ack` is given.
+fn write_rst(
+    nic: &mut Nic,
+    local: ([u8; 4], u16),
+    remote: ([u8; 4], u16),
+    seq: u32,
+    ack: Option<u32>,
+) -> io::Result<()> {
+    let mut tcp = etherparse::TcpHeader::new(local.1, remote.1, seq, 0);
+    tcp.acknowledgment_number = ack.unwrap_or(0);
+    tcp.ack = ack.is_some();
+    tcp.rst = true;
+
+    let ip = etherparse::Ipv4Header::new(
+        tcp.header_len(),
+        64,
+        etherparse::IpTrafficClass::Tcp,
+        local.0,
+        remote.0,
+    );
+    tcp.checksum = tcp
+        .calc_checksum_ipv4(&ip, &[])
+        .expect("failed to compute checksum");
+
+    let mut buf = [0u8; 60];
+    let buf_len = buf.len();
+    let mut unwritten = &mut buf[..];
+    ip.write(&mut unwritten).expect("failed to write ip header");
+    tcp.write(&mut unwritten)
+        .expect("failed to write tcp header");
+    let ends_at = buf_len - unwritten.len();
+
+    eprintln!("sending RST (seq: {}, ack: {:?})", seq, ack);
+    nic.send(&buf[..ends_at])?;
+    Ok(())
+}
+
+/// The RFC 2385 signature of a segment with the given (fully serialized) tcp header and payload.
+fn md5_signature(src: &[u8], dst: &[u8], header: &[u8], payload: &[u8], key: &[u8]) -> [u8; 16] {
+    let mut input = Vec::with_capacity(12 + 20 + payload.len() + key.len());
+    // pseudo-header
+    input.extend_from_slice(src);
+    input.extend_from_slice(dst);
+    input.extend_from_slice(&[0, 0x06]);
+    input.extend_from_slice(&((header.len() + payload.len()) as u16).to_be_bytes());
+    // the header without options, with a zero checksum
+    input.extend_from_slice(&header[..16]);
+    input.extend_from_slice(&[0, 0]);
+    input.extend_from_slice(&header[18..20]);
+    input.extend_from_slice(payload);
+    input.extend_from_slice(key);
+    crate::md5::digest(&input)
+}
+
+/// The MSS option for our SYN or SYN-ACK: the largest segment we are willing to receive.
+fn mss_option() -> Vec<u8> {
+    let mut option = vec![TCP_OPTION_MSS, 4];
+    option.extend_from_slice(&MAX_MSS.to_be_bytes());
+    option
+}
+
+/// The MSS the peer asked for on its SYN.
+fn peer_mss(tcph: &etherparse::TcpHeaderSlice) -> u16 {
+    find_option(tcph.options(), TCP_OPTION_MSS)
+        .filter(|v| v.len() == 2)
+        .map_or(DEFAULT_MSS, |v| u16::from_be_bytes([v[0], v[1]]))
+        .clamp(1, MAX_MSS)
+}
+
+/// Adds `data` to the running one's complement sum `sum` as big-endian 16-bit words, the last
+/// one padded with a zero byte if need be (RFC 1071).
+fn sum_words(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+/// The internet checksum for a one's complement sum from [`sum_words`].
+fn fold_checksum(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The flags byte of `tcp`'s header, CWR down to FIN.
+fn tcp_flags(tcp: &etherparse::TcpHeader) -> u8 {
+    [
+        tcp.fin, tcp.syn, tcp.rst, tcp.psh, tcp.ack, tcp.urg, tcp.ece, tcp.cwr,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |flags, (bit, &set)| flags | (set as u8) << bit)
+}
+
+/// A length that goes in a 16-bit header field; nothing we send is anywhere near too long.
+fn packet_len(len: usize) -> u16 {
+    u16::try_from(len).expect("packet too large for a length field")
+}
+
+/// Whether an incoming segment's tcp checksum is right.
+fn checksum_ok(
+    iph: &etherparse::Ipv4HeaderSlice,
+    tcph: &etherparse::TcpHeaderSlice,
+    data: &[u8],
+) -> bool {
+    tcph.calc_checksum_ipv4(iph, data)
+        .is_ok_and(|checksum| checksum == tcph.checksum())
+}
+
+/// Whether an incoming segment carries a valid RFC 2385 signature for `key`.
+fn md5_ok(
+    key: &[u8],
+    iph: &etherparse::Ipv4HeaderSlice,
+    tcph: &etherparse::TcpHeaderSlice,
+    data: &[u8],
+) -> bool {
+    match find_option(tcph.options(), TCP_OPTION_MD5) {
+        Some(signature) => constant_time_eq(
+            signature,
+            &md5_signature(iph.source(), iph.destination(), tcph.slice(), data, key),
+        ),
+        None => false,
+    }
+}
+
+/// Which of `keys` an incoming segment carries a valid TCP-AO MAC for, if any, given the ISNs
+/// of its sender and receiver (zero for the receiver's, on a SYN) and the sender's SNE.
+fn ao_ok(
+    keys: &[AoKey],
+    iph: &etherparse::Ipv4HeaderSlice,
+    tcph: &etherparse::TcpHeaderSlice,
+    data: &[u8],
+    isns: (u32, u32),
+    sne: u32,
+) -> Option<usize> {
+    let (key_id, _, mac) = ao::parse(tcph.options())?;
+    let i = keys.iter().position(|k| k.recv_id == key_id)?;
+    let src: [u8; 4] = iph.source().try_into().expect("ipv4 address is 4 bytes");
+    let dst: [u8; 4] = iph
+        .destination()
+        .try_into()
+        .expect("ipv4 address is 4 bytes");
+    let traffic_key = ao::traffic_key(
+        &keys[i].master_key,
+        (src, tcph.source_port(), isns.0),
+        (dst, tcph.destination_port(), isns.1),
+    );
+    let expected = ao::mac(&traffic_key, sne, &src, &dst, tcph.slice(), data);
+    Some(i).filter(|_| constant_time_eq(mac, &expected))
+}
+
+/// Decodes the value of a USER_TIMEOUT option: a granularity bit (minutes if set, otherwise
+/// seconds) followed by a 15-bit timeout.
+fn decode_user_timeout(v: u16) -> Duration {
+    let n = (v & 0x7fff) as u64;
+    if v & 0x8000 != 0 {
+        Duration::from_secs(n * 60)
+    } else {
+        Duration::from_secs(n)
+    }
+}
+
+/// The inverse of [`decode_user_timeout`], rounding down to what the option can express.
+fn encode_user_timeout(d: Duration) -> u16 {
+    let secs = d.as_secs();
+    if secs <= 0x7fff {
+        secs as u16
+    } else {
+        0x8000 | std::cmp::min(secs / 60, 0x7fff) as u16
+    }
+}
+
+/// Lays out the options for a SYN (or SYN-ACK), each already padded to a multiple of 4 bytes and
+/// given most important first, leaving `signature_len` bytes of room for a signature.
+///
+/// Whatever doesn't fit in the 40 bytes a TCP header has for options is left out, rather than
+/// producing a header that overruns its data offset.
+fn fit_syn_options(options: Vec<Vec<u8>>, signature_len: usize) -> Vec<u8> {
+    let room = MAX_OPTIONS_LEN - signature_len;
+    let mut laid_out = Vec::new();
+    for option in options {
+        debug_assert!(
+            option.len().is_multiple_of(4),
+            "unpadded option {:?}",
+            option
+        );
+        if laid_out.len() + option.len() > room {
+            let kind = option.iter().find(|&&b| b != 1);
+            eprintln!("no room for option {:?} on our syn; leaving it out", kind);
+            continue;
+        }
+        laid_out.extend(option);
+    }
+    laid_out
+}
+
+/// Finds the option of the given kind in a raw TCP options area and returns its value (the bytes
+/// after the kind and length octets).
+fn find_option(mut options: &[u8], kind: u8) -> Option<&[u8]> {
+    while let Some(&k) = options.first() {
+        match k {
+            // end of option list
+            0 => return None,
+            // no-operation
+            1 => options = &options[1..],
+            _ => {
+                let len = *options.get(1)? as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if k == kind {
+                    return Some(&options[2..len]);
+                }
+                options = &options[len..];
+            }
+        }
+    }
+    None
+}
+
+/// The TFO cookie we hand out to (and expect back from) the client at `client`: an HMAC of its
+/// address under our secret, truncated to 64 bits (RFC 7413 S4.1.2), so that nobody without the
+/// secret can make one up for an address they don't own.
+/// Compares `a` and `b` in time that depends only on their lengths, so that how long a guess
+/// took to turn away says nothing about how much of it was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn tfo_cookie(key: &[u8; 16], client: &[u8]) -> [u8; 8] {
+    let mut cookie = [0u8; 8];
+    cookie.copy_from_slice(&sha1::hmac(key, client)[..8]);
+    cookie
+}
+
+fn choose_iss(strategy: IssStrategy, local: SocketAddrV4, remote: SocketAddrV4) -> u32 {
+    match strategy {
+        IssStrategy::Random => {
+            // RFC 6528 S3: ISN = M + F(localip, localport, remoteip, remoteport, secretkey), where
+            // M ticks every 4 microseconds and F is a keyed hash
+            static SECRET: OnceLock<RandomState> = OnceLock::new();
+            let f = SECRET
+                .get_or_init(RandomState::new)
+                .hash_one((local, remote));
+            let m = time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros()
+                / 4;
+            (m as u32).wrapping_add(f as u32)
+        }
+        IssStrategy::Fixed(iss) => iss,
+        IssStrategy::Incrementing => {
+            static NEXT: AtomicU32 = AtomicU32::new(0);
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+}
+
 fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
     // From RFC1323:
     // TCP determines if a data segment is "old" or "new" by testing
@@ -545,9 +3504,14 @@ fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
     // insure that new data is never mistakenly considered old and vice-versa,
     // the left edge of the sender's window has to be at most
     // 2 ** 31 away from the right edge of the receiver's window
-    lhs.wrapping_sub(rhs) > 2 ^ 31
+    lhs.wrapping_sub(rhs) > (1 << 31)
 }
 
 fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
     wrapping_lt(start, x) && wrapping_lt(x, end)
 }
+
+#[cfg(feature = "testing")]
+pub mod bench;
+#[cfg(test)]
+pub(crate) mod tests;
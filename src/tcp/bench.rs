@@ -0,0 +1,99 @@
+//! Connections set up for the benchmarks in `benches/`, which have no other way in.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use super::*;
+
+const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5000);
+const ISS: u32 = 1000;
+const IRS: u32 = 5000;
+/// an Ethernet-sized segment, rather than the 536 bytes we assume without an MSS option
+const MSS: u16 = 1460;
+
+/// An established connection on a mock nic.
+fn established() -> Connection {
+    let mut c = Connection::fixture(
+        LOCAL,
+        REMOTE,
+        State::Estab,
+        ConnectionConfig::default(),
+        ISS,
+        IRS,
+    );
+    c.mss = MSS;
+    c.send.wnd = u16::MAX;
+    c.congestion.cwnd = u32::MAX;
+    c
+}
+
+/// A bulk send: a connection with `segments` full-sized segments queued, and room in both the
+/// peer's window and the congestion window to send them all at once.
+pub struct Burst {
+    c: Connection,
+    nic: Nic,
+}
+
+impl Burst {
+    pub fn new(segments: usize) -> Self {
+        let mut c = established();
+        c.unacked.extend(vec![b'x'; segments * MSS as usize]);
+        Burst {
+            c,
+            nic: Nic::mock(),
+        }
+    }
+
+    /// Sends everything queued, returning how many segments that took.
+    pub fn send(&mut self) -> usize {
+        self.c.flush_output(&mut self.nic).unwrap();
+        self.nic.take_sent().len()
+    }
+}
+
+/// What a bulk sender gets back: a connection with a segment in flight, and the pure ACK for it.
+pub struct PureAck {
+    c: Connection,
+    nic: Nic,
+    ack: Vec<u8>,
+}
+
+impl PureAck {
+    pub fn new() -> Self {
+        let mut c = established();
+        let mut nic = Nic::mock();
+        c.unacked.extend(vec![b'x'; MSS as usize]);
+        c.flush_output(&mut nic).unwrap();
+        nic.take_sent();
+
+        let mut tcp = etherparse::TcpHeader::new(REMOTE.port(), LOCAL.port(), c.recv.nxt, 1024);
+        tcp.ack = true;
+        tcp.acknowledgment_number = c.send.nxt;
+        let ip = etherparse::Ipv4Header::new(
+            tcp.header_len(),
+            64,
+            etherparse::IpTrafficClass::Tcp,
+            REMOTE.ip().octets(),
+            LOCAL.ip().octets(),
+        );
+        tcp.checksum = tcp.calc_checksum_ipv4(&ip, &[]).unwrap();
+        let mut ack = Vec::new();
+        ip.write(&mut ack).unwrap();
+        tcp.write(&mut ack).unwrap();
+        PureAck { c, nic, ack }
+    }
+
+    /// Hands the connection its ACK, returning how far SND.UNA is past our ISN afterwards.
+    pub fn deliver(&mut self) -> u32 {
+        let iph = etherparse::Ipv4HeaderSlice::from_slice(&self.ack).unwrap();
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&self.ack[iph.slice().len()..]).unwrap();
+        self.c.on_packet(&mut self.nic, iph, tcph, &[]).unwrap();
+        self.c.send.una.wrapping_sub(ISS)
+    }
+}
+
+impl Default for PureAck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,2325 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+
+use super::*;
+use crate::clock::MockClock;
+
+pub(crate) const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+pub(crate) const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5000);
+/// our ISN in fixtures
+pub(crate) const ISS: u32 = 1000;
+/// the peer's ISN in fixtures
+pub(crate) const IRS: u32 = 5000;
+
+/// A config whose timers go by a clock the test moves along by hand.
+fn clocked() -> (Arc<MockClock>, ConnectionConfig) {
+    let clock = Arc::new(MockClock::new());
+    let config = ConnectionConfig {
+        clock: Some(clock.clone()),
+        ..Default::default()
+    };
+    (clock, config)
+}
+
+fn fixture(state: State, config: ConnectionConfig) -> Connection {
+    Connection::fixture(LOCAL, REMOTE, state, config, ISS, IRS)
+}
+
+/// The header of a segment from the peer, with ACK set if `ack` is given.
+pub(crate) fn header(seq: u32, ack: Option<u32>) -> TcpHeader {
+    let mut tcp = TcpHeader::new(REMOTE.port(), LOCAL.port(), seq, 1024);
+    if let Some(ack) = ack {
+        tcp.ack = true;
+        tcp.acknowledgment_number = ack;
+    }
+    tcp
+}
+
+/// The IP packet carrying `tcp` and `data` from the peer, with both checksums filled in.
+pub(crate) fn packet(tcp: &TcpHeader, data: &[u8]) -> Vec<u8> {
+    packet_from(*REMOTE.ip(), tcp, data)
+}
+
+/// Like [`packet`], from some other peer at `src`.
+pub(crate) fn packet_from(src: Ipv4Addr, tcp: &TcpHeader, data: &[u8]) -> Vec<u8> {
+    let ip = etherparse::Ipv4Header::new(
+        tcp.header_len() + data.len() as u16,
+        64,
+        etherparse::IpTrafficClass::Tcp,
+        src.octets(),
+        LOCAL.ip().octets(),
+    );
+    let mut tcp = tcp.clone();
+    tcp.checksum = tcp.calc_checksum_ipv4(&ip, data).unwrap();
+    let mut buf = Vec::new();
+    ip.write(&mut buf).unwrap();
+    tcp.write(&mut buf).unwrap();
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// Splits an IP packet into its headers and payload.
+pub(crate) fn parse(packet: &[u8]) -> (Ipv4HeaderSlice<'_>, TcpHeaderSlice<'_>, &[u8]) {
+    let iph = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let tcph = TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]).unwrap();
+    let data = &packet[iph.slice().len() + tcph.slice().len()..];
+    (iph, tcph, data)
+}
+
+/// Hands a segment from the peer to `c`.
+fn deliver(c: &mut Connection, nic: &mut Nic, tcp: &TcpHeader, data: &[u8]) -> Available {
+    let packet = packet(tcp, data);
+    let (iph, tcph, data) = parse(&packet);
+    c.on_packet(nic, iph, tcph, data).unwrap()
+}
+
+/// The segments sent on `nic` since the last call, headers and payloads.
+pub(crate) fn sent(nic: &mut Nic) -> Vec<(TcpHeader, Vec<u8>)> {
+    sent_from(&nic.take_sent())
+}
+
+/// The headers and payloads of raw `packets`.
+fn sent_from(packets: &[Vec<u8>]) -> Vec<(TcpHeader, Vec<u8>)> {
+    packets
+        .iter()
+        .map(|packet| {
+            let (_, tcph, data) = parse(packet);
+            (tcph.to_header(), data.to_vec())
+        })
+        .collect()
+}
+
+#[test]
+fn mock_clock_fires_the_retransmission_timer() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    c.unacked.extend(b"hello");
+    c.on_tick(&mut nic).unwrap();
+    let first = sent(&mut nic);
+    assert_eq!(first.len(), 1);
+    assert_eq!(first[0].0.sequence_number, ISS + 1);
+    assert_eq!(first[0].1, b"hello");
+
+    // however often it ticks, nothing is resent until the clock says so
+    clock.advance(DEFAULT_INITIAL_RTO / 2);
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+
+    clock.advance(DEFAULT_INITIAL_RTO);
+    c.on_tick(&mut nic).unwrap();
+    let resent = sent(&mut nic);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].0.sequence_number, ISS + 1);
+    assert_eq!(resent[0].1, b"hello");
+
+    // and once it is ACKed, the timer is off for good
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 6)), &[]);
+    clock.advance(DEFAULT_MAX_RTO);
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+}
+
+#[test]
+fn fixture_takes_a_fin_in_fin_wait_2() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::FinWait2, ConnectionConfig::default());
+    let mut fin = header(IRS + 1, Some(ISS + 2));
+    fin.fin = true;
+    deliver(&mut c, &mut nic, &fin, &[]);
+    assert!(matches!(c.state, State::TimeWait));
+    let acks = sent(&mut nic);
+    assert_eq!(acks.len(), 1);
+    assert_eq!(acks[0].0.sequence_number, ISS + 2);
+    assert_eq!(acks[0].0.acknowledgment_number, IRS + 2);
+}
+
+#[test]
+fn fixture_delivers_data_when_established() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    let mut push = header(IRS + 1, Some(ISS + 1));
+    push.psh = true;
+    let a = deliver(&mut c, &mut nic, &push, b"hello");
+    assert!(a.contains(Available::READ));
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(c.recv.nxt, IRS + 6);
+}
+
+/// A listening endpoint on `LOCAL` that picks `ISS` as its ISN.
+fn listener() -> Connection {
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        ..Default::default()
+    };
+    Connection::listen(LOCAL, config)
+}
+
+pub(crate) fn syn(seq: u32) -> TcpHeader {
+    let mut syn = header(seq, None);
+    syn.syn = true;
+    syn
+}
+
+#[test]
+fn passive_open() {
+    let mut nic = Nic::mock();
+    let mut c = listener();
+    deliver(&mut c, &mut nic, &syn(IRS), &[]);
+    assert!(matches!(c.state, State::SynRcvd));
+    let syn_ack = sent(&mut nic);
+    assert_eq!(syn_ack.len(), 1);
+    let (ref tcp, _) = syn_ack[0];
+    assert!(tcp.syn && tcp.ack);
+    assert_eq!(tcp.sequence_number, ISS);
+    assert_eq!(tcp.acknowledgment_number, IRS + 1);
+    assert_eq!(tcp.destination_port, REMOTE.port());
+
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 1)), &[]);
+    assert!(matches!(c.state, State::Estab));
+}
+
+#[test]
+fn accept_answers_a_syn() {
+    let mut nic = Nic::mock();
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        ..Default::default()
+    };
+    let first = packet(&syn(IRS), &[]);
+    let (iph, tcph, data) = parse(&first);
+    let c = Connection::accept(&mut nic, iph, tcph, data, config.clone())
+        .unwrap()
+        .unwrap();
+    assert!(matches!(c.state, State::SynRcvd));
+    assert_eq!(sent(&mut nic).len(), 1);
+
+    // a stray ACK doesn't start anything, and gets reset
+    let stray = packet(&header(IRS, Some(ISS)), &[]);
+    let (iph, tcph, data) = parse(&stray);
+    assert!(Connection::accept(&mut nic, iph, tcph, data, config)
+        .unwrap()
+        .is_none());
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    assert!(rst[0].0.rst);
+}
+
+#[test]
+fn initial_window_follows_the_negotiated_mss() {
+    let mut nic = Nic::mock();
+    let mut c = listener();
+    let mut with_mss = syn(IRS);
+    with_mss
+        .set_options_raw(&[TCP_OPTION_MSS, 4, 0x03, 0xe8])
+        .unwrap();
+    deliver(&mut c, &mut nic, &with_mss, &[]);
+    assert_eq!(c.mss, 1000);
+    // RFC 6928: min(10*MSS, max(2*MSS, 14600))
+    assert_eq!(c.cwnd(), 10000);
+
+    // and the SYN-ACK tells the peer what we can take, ahead of any other option
+    let syn_ack = sent(&mut nic);
+    let options = syn_ack[0].0.options();
+    assert_eq!(&options[..2], &[TCP_OPTION_MSS, 4]);
+    assert_eq!(u16::from_be_bytes([options[2], options[3]]), MAX_MSS);
+}
+
+#[test]
+fn bogus_ack_in_syn_rcvd_is_reset_at_its_ack_number() {
+    // one that acknowledges nothing at all, and one from beyond anything we've sent
+    for ack in [ISS, ISS + 5] {
+        let mut nic = Nic::mock();
+        let mut c = fixture(State::SynRcvd, ConnectionConfig::default());
+        deliver(&mut c, &mut nic, &header(IRS + 1, Some(ack)), &[]);
+        assert!(matches!(c.state, State::Closed));
+        let rst = sent(&mut nic);
+        assert_eq!(rst.len(), 1);
+        let (ref tcp, _) = rst[0];
+        assert!(tcp.rst && !tcp.ack);
+        assert_eq!(tcp.sequence_number, ack);
+    }
+}
+
+#[test]
+fn hard_icmp_error_fails_a_connect() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::SynSent, ConnectionConfig::default());
+    // port unreachable, quoting our SYN
+    c.on_icmp_error(&mut nic, 3, 3, 0, ISS).unwrap();
+    assert!(matches!(c.state, State::Closed));
+    assert_eq!(
+        c.error().unwrap().kind(),
+        ResetReason::RefusedOnConnect.kind()
+    );
+}
+
+/// An established connection with "hello" in flight.
+fn in_flight(nic: &mut Nic) -> Connection {
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.unacked.extend(b"hello");
+    c.on_tick(nic).unwrap();
+    assert_eq!(sent(nic).len(), 1);
+    c
+}
+
+#[test]
+fn icmp_errors_dont_abort_an_established_connection() {
+    let mut nic = Nic::mock();
+    let mut c = in_flight(&mut nic);
+    // host unreachable is soft, and port unreachable no longer counts once synchronized
+    c.on_icmp_error(&mut nic, 3, 1, 0, ISS + 1).unwrap();
+    c.on_icmp_error(&mut nic, 3, 3, 0, ISS + 1).unwrap();
+    assert!(matches!(c.state, State::Estab));
+    assert!(c.error().is_none());
+}
+
+#[test]
+fn fragmentation_needed_shrinks_the_mss() {
+    let mut nic = Nic::mock();
+    let mut c = in_flight(&mut nic);
+    c.mss = MAX_MSS;
+    c.on_icmp_error(&mut nic, 3, 4, 576, ISS + 1).unwrap();
+    assert_eq!(c.mss, 536);
+    // and what was in flight goes again
+    let resent = sent(&mut nic);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].0.sequence_number, ISS + 1);
+}
+
+#[test]
+fn icmp_error_quoting_data_outside_the_window_is_ignored() {
+    let mut nic = Nic::mock();
+    let mut c = in_flight(&mut nic);
+    c.mss = MAX_MSS;
+    // already ACKed, and not yet sent
+    for seq in [ISS, ISS + 6] {
+        c.on_icmp_error(&mut nic, 3, 4, 576, seq).unwrap();
+        assert_eq!(c.mss, MAX_MSS);
+    }
+    assert!(sent(&mut nic).is_empty());
+
+    let mut c = fixture(State::SynSent, ConnectionConfig::default());
+    c.on_icmp_error(&mut nic, 3, 3, 0, ISS + 1).unwrap();
+    assert!(matches!(c.state, State::SynSent));
+}
+
+#[test]
+fn close_before_the_handshake_is_done_changes_nothing() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::SynSent, ConnectionConfig::default());
+    assert!(c.close().is_err());
+    assert!(!c.closed);
+
+    // so the handshake completes as usual, without a FIN tagging along
+    let mut syn_ack = syn(IRS);
+    syn_ack.ack = true;
+    syn_ack.acknowledgment_number = ISS + 1;
+    deliver(&mut c, &mut nic, &syn_ack, &[]);
+    assert!(matches!(c.state, State::Estab));
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).iter().all(|(tcp, _)| !tcp.fin));
+}
+
+/// Sends `data` from `c`, and has the peer ACK it `rtt` later.
+fn round_trip(c: &mut Connection, nic: &mut Nic, clock: &MockClock, data: &[u8], rtt: Duration) {
+    c.unacked.extend(data);
+    c.on_tick(nic).unwrap();
+    assert_eq!(sent(nic).len(), 1);
+    clock.advance(rtt);
+    let ack = header(c.recv.nxt, Some(c.send.nxt));
+    deliver(c, nic, &ack, &[]);
+}
+
+#[test]
+fn smoothed_rtt_follows_the_delay() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    assert_eq!(c.smoothed_rtt(), None);
+    let delay = Duration::from_millis(100);
+    for _ in 0..3 {
+        round_trip(&mut c, &mut nic, &clock, b"hello", delay);
+    }
+    assert_eq!(c.last_rtt(), Some(delay));
+    let srtt = c.smoothed_rtt().unwrap();
+    assert!(
+        srtt > delay * 9 / 10 && srtt < delay * 11 / 10,
+        "{:?}",
+        srtt
+    );
+}
+
+#[test]
+fn retransmitted_segments_are_not_timed() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    c.unacked.extend(b"hello");
+    c.on_tick(&mut nic).unwrap();
+    clock.advance(DEFAULT_INITIAL_RTO + Duration::from_millis(1));
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 2);
+
+    // the ACK could be for either transmission, so it says nothing about the RTT
+    clock.advance(Duration::from_millis(10));
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 6)), &[]);
+    assert_eq!(c.send.una, ISS + 6);
+    assert_eq!(c.last_rtt(), None);
+
+    // new data is timed again
+    round_trip(
+        &mut c,
+        &mut nic,
+        &clock,
+        b"world",
+        Duration::from_millis(50),
+    );
+    assert_eq!(c.last_rtt(), Some(Duration::from_millis(50)));
+}
+
+#[test]
+fn tfo_cookie_lets_syn_data_through() {
+    let key = [7; 16];
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        tfo_key: Some(key),
+        ..Default::default()
+    };
+
+    // the first SYN asks for a cookie, and its data has to wait for the handshake
+    let mut nic = Nic::mock();
+    let mut c = Connection::listen(LOCAL, config.clone());
+    let mut request = syn(IRS);
+    request
+        .set_options_raw(&[1, 1, TCP_OPTION_FAST_OPEN, 2])
+        .unwrap();
+    deliver(&mut c, &mut nic, &request, b"hi");
+    assert!(c.incoming.is_empty());
+    let syn_ack = sent(&mut nic);
+    let cookie = find_option(syn_ack[0].0.options(), TCP_OPTION_FAST_OPEN).unwrap();
+    assert_eq!(cookie, tfo_cookie(&key, &REMOTE.ip().octets()));
+    assert_eq!(cookie, &sha1::hmac(&key, &REMOTE.ip().octets())[..8]);
+
+    // and the next one gets its data delivered straight away
+    let mut c = Connection::listen(LOCAL, config);
+    let mut option = vec![1, 1, TCP_OPTION_FAST_OPEN, 10];
+    option.extend_from_slice(cookie);
+    let mut replay = syn(IRS);
+    replay.set_options_raw(&option).unwrap();
+    deliver(&mut c, &mut nic, &replay, b"hi");
+    assert_eq!(c.incoming, b"hi");
+    assert_eq!(c.recv.nxt, IRS + 3);
+    assert_eq!(sent(&mut nic)[0].0.acknowledgment_number, IRS + 3);
+}
+
+#[test]
+fn segment_larger_than_the_window_is_trimmed_to_it() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.resize_recv_buffer(8);
+    assert_eq!(c.recv.wnd, 8);
+    deliver(
+        &mut c,
+        &mut nic,
+        &header(IRS + 1, Some(ISS + 1)),
+        &[b'x'; 20],
+    );
+    assert_eq!(c.incoming.len(), 8);
+    assert_eq!(c.recv.nxt, IRS + 9);
+    // and the ACK only claims what was kept
+    c.on_tick(&mut nic).unwrap();
+    let ack = sent(&mut nic).pop().unwrap().0;
+    assert_eq!(ack.acknowledgment_number, IRS + 9);
+    assert_eq!(ack.window_size, 0);
+}
+
+#[test]
+fn idle_connection_is_closed() {
+    let (clock, config) = clocked();
+    let config = ConnectionConfig {
+        idle_timeout: Some(Duration::from_secs(10)),
+        ..config
+    };
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    clock.advance(Duration::from_secs(9));
+    c.on_tick(&mut nic).unwrap();
+    assert!(matches!(c.state, State::Estab));
+
+    clock.advance(Duration::from_secs(2));
+    c.on_tick(&mut nic).unwrap();
+    assert!(matches!(c.state, State::FinWait1));
+    assert!(sent(&mut nic).iter().any(|(tcp, _)| tcp.fin));
+}
+
+/// How many segments `ticks` ticks send of a queue three segments long, with the clock standing
+/// still, once an RTT sample is in.
+fn segments_over_ticks(pacing: bool, ticks: usize) -> usize {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig { pacing, ..config });
+    round_trip(
+        &mut c,
+        &mut nic,
+        &clock,
+        b"hello",
+        Duration::from_millis(100),
+    );
+    sent(&mut nic);
+
+    // room for all three at once, as far as the windows go
+    c.send.wnd = 4 * DEFAULT_MSS;
+    c.unacked.extend(&[b'x'; 3 * DEFAULT_MSS as usize]);
+    (0..ticks)
+        .map(|_| {
+            c.on_tick(&mut nic).unwrap();
+            sent(&mut nic).len()
+        })
+        .sum()
+}
+
+#[test]
+fn pacing_spreads_segments_over_ticks() {
+    assert_eq!(segments_over_ticks(false, 3), 3);
+    assert_eq!(segments_over_ticks(true, 3), 1);
+
+    // a segment's share of the RTT later, the next one may go
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            pacing: true,
+            ..config
+        },
+    );
+    round_trip(
+        &mut c,
+        &mut nic,
+        &clock,
+        b"hello",
+        Duration::from_millis(100),
+    );
+    c.unacked.extend(&[b'x'; 3 * DEFAULT_MSS as usize]);
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 1);
+    clock.advance(Duration::from_millis(60));
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 1);
+}
+
+/// Has the peer send `data` at `seq` to an established fixture, ACKing our SYN.
+fn send_at(c: &mut Connection, nic: &mut Nic, seq: u32, data: &[u8]) -> Available {
+    deliver(c, nic, &header(seq, Some(ISS + 1)), data)
+}
+
+#[test]
+fn out_of_order_ranges_reports_each_span() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    send_at(&mut c, &mut nic, IRS + 21, b"bbbb");
+    send_at(&mut c, &mut nic, IRS + 11, b"aaaa");
+    assert_eq!(
+        c.out_of_order_ranges(),
+        vec![(IRS + 11, IRS + 15), (IRS + 21, IRS + 25)]
+    );
+    assert_eq!(c.recv.nxt, IRS + 1);
+}
+
+#[test]
+fn closed_connection_resets_data() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Closed, ConnectionConfig::default());
+    send_at(&mut c, &mut nic, IRS + 1, b"hello");
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    let (ref tcp, _) = rst[0];
+    assert!(tcp.rst);
+    assert_eq!(tcp.sequence_number, ISS + 1);
+    assert!(c.incoming.is_empty());
+}
+
+#[test]
+fn teardown_resets_a_live_connection() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.teardown(&mut nic).unwrap();
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    assert!(rst[0].0.rst);
+    assert_eq!(rst[0].0.sequence_number, ISS + 1);
+    assert!(matches!(c.state, State::Closed));
+
+    // SYN-SENT has nothing on the other end to tell
+    let mut c = fixture(State::SynSent, ConnectionConfig::default());
+    c.teardown(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+}
+
+/// Hands an IP packet, exactly as it came off the wire, to `c`.
+fn deliver_raw(c: &mut Connection, nic: &mut Nic, packet: &[u8]) -> Available {
+    let (iph, tcph, data) = parse(packet);
+    c.on_packet(nic, iph, tcph, data).unwrap()
+}
+
+#[test]
+fn md5_signatures_are_checked() {
+    let config = || ConnectionConfig {
+        md5_key: Some(b"secret".to_vec()),
+        ack_delay: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config());
+    let mut peer = Connection::fixture(REMOTE, LOCAL, State::Estab, config(), IRS, ISS);
+    peer.unacked.extend(b"hello");
+    peer.on_tick(&mut nic).unwrap();
+    let signed = nic.take_sent().remove(0);
+
+    // the same header on a different payload, checksum fixed up, but still the old signature
+    let (_, tcph, _) = parse(&signed);
+    let tampered = packet(&tcph.to_header(), b"jello");
+    deliver_raw(&mut c, &mut nic, &tampered);
+    let mut unsigned = tcph.to_header();
+    unsigned.set_options(&[]).unwrap();
+    deliver(&mut c, &mut nic, &unsigned, b"hello");
+    assert_eq!(c.drops.signature, 2);
+    assert!(c.incoming.is_empty());
+    assert!(sent(&mut nic).is_empty());
+
+    let a = deliver_raw(&mut c, &mut nic, &signed);
+    assert!(a.contains(Available::READ));
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 5);
+    assert_eq!(&buf[..5], b"hello");
+    // and our ACK is signed for the peer in turn
+    let ack = nic.take_sent().remove(0);
+    deliver_raw(&mut peer, &mut nic, &ack);
+    assert_eq!(peer.drops.signature, 0);
+    assert!(peer.unacked.is_empty());
+}
+
+#[test]
+fn user_timeout_aborts_before_the_retransmissions_run_out() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    c.user_timeout = Some(2 * DEFAULT_INITIAL_RTO);
+    c.unacked.extend(b"hello");
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 1);
+
+    clock.advance(DEFAULT_INITIAL_RTO + Duration::from_millis(1));
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic)[0].1, b"hello");
+    assert!(c.error().is_none());
+
+    // only one retransmission so far, but the data has been waiting too long
+    clock.advance(DEFAULT_INITIAL_RTO);
+    assert!(c.on_tick(&mut nic).unwrap());
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    assert!(rst[0].0.rst);
+    assert_eq!(c.error().unwrap().kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn syn_on_an_established_connection_gets_a_challenge_ack() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    deliver(&mut c, &mut nic, &syn(IRS + 100), &[]);
+    let ack = sent(&mut nic);
+    assert_eq!(ack.len(), 1);
+    let (ref tcp, _) = ack[0];
+    assert!(tcp.ack && !tcp.rst && !tcp.syn);
+    assert_eq!(tcp.sequence_number, ISS + 1);
+    assert_eq!(tcp.acknowledgment_number, IRS + 1);
+    assert!(matches!(c.state, State::Estab));
+    assert_eq!(c.recv.nxt, IRS + 1);
+}
+
+#[test]
+fn next_timeout_is_the_retransmission_deadline() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    assert_eq!(c.next_timeout(), None);
+
+    let sent_at = clock.now();
+    c.unacked.extend(b"hello");
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(c.next_timeout(), Some(sent_at + DEFAULT_INITIAL_RTO));
+
+    // the first retransmission backs the timer off
+    clock.advance(DEFAULT_INITIAL_RTO + Duration::from_millis(1));
+    let resent_at = clock.now();
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(c.next_timeout(), Some(resent_at + 2 * DEFAULT_INITIAL_RTO));
+}
+
+#[test]
+fn receiver_stuck_behind_a_hole_is_logged() {
+    let mut nic = Nic::mock();
+    let config = ConnectionConfig {
+        event_log: true,
+        ..Default::default()
+    };
+    let mut c = fixture(State::Estab, config);
+    let stuck = |c: &Connection| {
+        c.event_log()
+            .into_iter()
+            .filter(|e| matches!(e, LogEntry::Stuck(..)))
+            .collect::<Vec<_>>()
+    };
+    // the first byte never arrives, however many come after it
+    for i in 0..STUCK_RECEIVER_SEGMENTS {
+        assert!(stuck(&c).is_empty());
+        send_at(&mut c, &mut nic, IRS + 2 + i, b"x");
+    }
+    assert_eq!(stuck(&c), vec![LogEntry::Stuck(IRS + 1, IRS + 2)]);
+}
+
+#[test]
+fn fin_behind_a_hole_waits_for_it_to_fill() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    send_at(&mut c, &mut nic, IRS + 6, b"world");
+    let mut fin = header(IRS + 11, Some(ISS + 1));
+    fin.fin = true;
+    deliver(&mut c, &mut nic, &fin, &[]);
+    assert!(matches!(c.state, State::Estab));
+    assert!(!c.is_rcv_closed());
+
+    send_at(&mut c, &mut nic, IRS + 1, b"hello");
+    assert!(matches!(c.state, State::CloseWait));
+    assert_eq!(c.recv.nxt, IRS + 12);
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 10);
+    assert_eq!(&buf[..10], b"helloworld");
+    assert!(c.is_rcv_closed());
+}
+
+#[test]
+fn restored_snapshot_carries_on_the_transfer() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    send_at(&mut c, &mut nic, IRS + 1, b"hello");
+    c.unacked.extend(b"abc");
+    c.on_tick(&mut nic).unwrap();
+    nic.take_sent();
+
+    let mut c = Connection::restore(c.snapshot());
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 5);
+    assert_eq!(&buf[..5], b"hello");
+
+    // what was in flight is still timed, and goes again if the peer doesn't ACK it
+    clock.advance(DEFAULT_INITIAL_RTO + Duration::from_millis(1));
+    c.on_tick(&mut nic).unwrap();
+    let resent = sent(&mut nic);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].0.sequence_number, ISS + 1);
+    assert_eq!(resent[0].1, b"abc");
+
+    deliver(&mut c, &mut nic, &header(IRS + 6, Some(ISS + 4)), b"world");
+    assert_eq!(c.read(&mut buf), 5);
+    assert_eq!(&buf[..5], b"world");
+    c.unacked.extend(b"def");
+    c.on_tick(&mut nic).unwrap();
+    let next = sent(&mut nic);
+    assert_eq!(next.last().unwrap().0.sequence_number, ISS + 4);
+    assert_eq!(next.last().unwrap().1, b"def");
+}
+
+#[test]
+fn early_retransmit_with_two_segments_in_flight() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.send.wnd = 4 * DEFAULT_MSS;
+    let mss = c.mss as usize;
+    c.unacked.extend(vec![b'x'; 2 * mss]);
+    c.flush_output(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 2);
+
+    // the first segment was lost; the second brings a single duplicate ACK back
+    let mut dup = header(IRS + 1, Some(ISS + 1));
+    dup.window_size = c.send.wnd;
+    deliver(&mut c, &mut nic, &dup, &[]);
+    let resent = sent(&mut nic);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].0.sequence_number, ISS + 1);
+    assert_eq!(resent[0].1.len(), mss);
+}
+
+#[test]
+fn small_cwnd_limits_what_is_sent() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.send.wnd = 4 * DEFAULT_MSS;
+    let mss = c.mss as u32;
+    c.set_cwnd(mss);
+    c.unacked.extend(vec![b'x'; 3 * mss as usize]);
+    c.flush_output(&mut nic).unwrap();
+    let first = sent(&mut nic);
+    assert_eq!(first.len(), 1);
+    assert_eq!(c.cwnd(), mss);
+
+    // an ACK opens it up by a segment, so the next two may go
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 1 + mss)), &[]);
+    c.flush_output(&mut nic).unwrap();
+    assert_eq!(c.cwnd(), 2 * mss);
+    assert_eq!(sent(&mut nic).len(), 2);
+}
+
+#[test]
+fn tiny_segments_are_readable_once_at_the_low_water_mark() {
+    let mut nic = Nic::mock();
+    let config = ConnectionConfig {
+        read_low_water: Some(10),
+        ..Default::default()
+    };
+    let mut c = fixture(State::Estab, config);
+    let readable: Vec<bool> = (0..5)
+        .map(|i| send_at(&mut c, &mut nic, IRS + 1 + 2 * i, b"ab").contains(Available::READ))
+        .collect();
+    assert_eq!(readable, [false, false, false, false, true]);
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 10);
+}
+
+#[test]
+fn batch_of_segments_is_acked_once() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    let packets: Vec<_> = [(0, b"abc"), (3, b"def"), (6, b"ghi")]
+        .iter()
+        .map(|(offset, data)| packet(&header(IRS + 1 + offset, Some(ISS + 1)), *data))
+        .collect();
+    let segments: Vec<_> = packets
+        .iter()
+        .map(|packet| {
+            let (iph, tcph, data) = parse(packet);
+            Segment { iph, tcph, data }
+        })
+        .collect();
+    c.on_packets(&mut nic, &segments).unwrap();
+
+    assert_eq!(c.recv.nxt, IRS + 10);
+    let acks = sent(&mut nic);
+    assert_eq!(acks.len(), 1);
+    assert_eq!(acks[0].0.acknowledgment_number, IRS + 10);
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 9);
+    assert_eq!(&buf[..9], b"abcdefghi");
+}
+
+#[test]
+fn first_retransmission_waits_for_the_configured_initial_rto() {
+    let (clock, config) = clocked();
+    let initial_rto = Duration::from_millis(300);
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            initial_rto: Some(initial_rto),
+            ..config
+        },
+    );
+    c.unacked.extend(b"hello");
+    c.on_tick(&mut nic).unwrap();
+    sent(&mut nic);
+
+    clock.advance(initial_rto - Duration::from_millis(1));
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+    clock.advance(Duration::from_millis(2));
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic)[0].1, b"hello");
+}
+
+#[test]
+fn simultaneous_open_establishes_both_ends() {
+    let config = |iss| ConnectionConfig {
+        iss: IssStrategy::Fixed(iss),
+        ..Default::default()
+    };
+    let (mut a_nic, mut b_nic) = (Nic::mock(), Nic::mock());
+    let mut a = Connection::connect(&mut a_nic, LOCAL, REMOTE, config(ISS)).unwrap();
+    let mut b = Connection::connect(&mut b_nic, REMOTE, LOCAL, config(IRS)).unwrap();
+
+    // the SYNs cross, then so do the SYN-ACKs they are answered with
+    let (a_syn, b_syn) = (a_nic.take_sent(), b_nic.take_sent());
+    deliver_raw(&mut a, &mut a_nic, &b_syn[0]);
+    deliver_raw(&mut b, &mut b_nic, &a_syn[0]);
+    assert!(matches!(a.state, State::SynRcvd));
+    assert!(matches!(b.state, State::SynRcvd));
+
+    let (a_syn_ack, b_syn_ack) = (a_nic.take_sent(), b_nic.take_sent());
+    let (tcph, _) = &sent_from(&a_syn_ack)[0];
+    assert!(tcph.syn && tcph.ack);
+    assert_eq!(tcph.sequence_number, ISS);
+    assert_eq!(tcph.acknowledgment_number, IRS + 1);
+    deliver_raw(&mut a, &mut a_nic, &b_syn_ack[0]);
+    deliver_raw(&mut b, &mut b_nic, &a_syn_ack[0]);
+    assert!(matches!(a.state, State::Estab));
+    assert!(matches!(b.state, State::Estab));
+    assert_eq!((a.send.nxt, a.recv.nxt), (ISS + 1, IRS + 1));
+    assert_eq!((b.send.nxt, b.recv.nxt), (IRS + 1, ISS + 1));
+}
+
+#[test]
+fn flush_output_sends_what_pacing_holds_back() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            pacing: true,
+            ..config
+        },
+    );
+    round_trip(
+        &mut c,
+        &mut nic,
+        &clock,
+        b"hello",
+        Duration::from_millis(100),
+    );
+    sent(&mut nic);
+    c.send.wnd = 4 * DEFAULT_MSS;
+    for _ in 0..3 {
+        c.unacked.extend(&[b'x'; DEFAULT_MSS as usize]);
+    }
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 1);
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+
+    c.flush_output(&mut nic).unwrap();
+    let rest = sent(&mut nic);
+    assert_eq!(rest.len(), 2);
+    assert_eq!(rest[1].0.sequence_number, ISS + 6 + 2 * DEFAULT_MSS as u32);
+}
+
+#[test]
+fn out_of_order_segment_is_acked_straight_away() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    // in order, the ACK waits for the delayed-ACK timer
+    send_at(&mut c, &mut nic, IRS + 1, b"hello");
+    assert!(sent(&mut nic).is_empty());
+
+    send_at(&mut c, &mut nic, IRS + 11, b"later");
+    let acks = sent(&mut nic);
+    assert_eq!(acks.len(), 1);
+    assert_eq!(acks[0].0.acknowledgment_number, IRS + 6);
+}
+
+#[test]
+fn connect_sends_from_the_chosen_address() {
+    let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 9), 40000);
+    let mut nic = Nic::mock();
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        ..Default::default()
+    };
+    let mut c = Connection::connect(&mut nic, local, REMOTE, config).unwrap();
+    let syn = nic.take_sent();
+    let (iph, tcph, _) = parse(&syn[0]);
+    assert_eq!(iph.source_addr(), *local.ip());
+    assert_eq!(iph.destination_addr(), *REMOTE.ip());
+    assert_eq!(tcph.source_port(), local.port());
+
+    let mut syn_ack = TcpHeader::new(REMOTE.port(), local.port(), IRS, 1024);
+    syn_ack.syn = true;
+    syn_ack.ack = true;
+    syn_ack.acknowledgment_number = ISS + 1;
+    let ip = etherparse::Ipv4Header::new(
+        syn_ack.header_len(),
+        64,
+        etherparse::IpTrafficClass::Tcp,
+        REMOTE.ip().octets(),
+        local.ip().octets(),
+    );
+    syn_ack.checksum = syn_ack.calc_checksum_ipv4(&ip, &[]).unwrap();
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    syn_ack.write(&mut packet).unwrap();
+    deliver_raw(&mut c, &mut nic, &packet);
+    assert!(matches!(c.state, State::Estab));
+    let ack = nic.take_sent();
+    let (iph, _, _) = parse(&ack[0]);
+    assert_eq!(iph.source_addr(), *local.ip());
+}
+
+#[test]
+fn rack_rides_out_reordering_but_catches_a_loss() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            rack: true,
+            ..config
+        },
+    );
+    round_trip(
+        &mut c,
+        &mut nic,
+        &clock,
+        b"hello",
+        Duration::from_millis(100),
+    );
+    let wnd = 8 * DEFAULT_MSS;
+    let ack = |n: u32| {
+        let mut ack = header(IRS + 1, Some(n));
+        ack.window_size = wnd;
+        ack
+    };
+    deliver(&mut c, &mut nic, &ack(ISS + 6), &[]);
+    let mss = c.mss as u32;
+    c.unacked.extend(vec![b'x'; 4 * mss as usize]);
+    c.flush_output(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 4);
+    let start = ISS + 6;
+
+    // the first two arrive the wrong way round; they were sent together, so the second being
+    // ACKed first says nothing about the others
+    deliver(&mut c, &mut nic, &ack(start), &[]);
+    deliver(&mut c, &mut nic, &ack(start + 2 * mss), &[]);
+    assert!(sent(&mut nic).is_empty());
+
+    // the last two really were lost. once the retransmission of the third is ACKed, the fourth,
+    // sent well before it, must be gone too, and goes again without waiting for another RTO
+    clock.advance(c.rto() + Duration::from_millis(1));
+    c.on_tick(&mut nic).unwrap();
+    let resent = sent(&mut nic);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].0.sequence_number, start + 2 * mss);
+    deliver(&mut c, &mut nic, &ack(start + 3 * mss), &[]);
+    let resent = sent(&mut nic);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].0.sequence_number, start + 3 * mss);
+}
+
+#[test]
+fn peek_leaves_the_data_for_read() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    send_at(&mut c, &mut nic, IRS + 1, b"hello world");
+    let wnd = c.recv.wnd;
+
+    let mut peeked = [0; 5];
+    assert_eq!(c.peek(&mut peeked), 5);
+    assert_eq!(c.peek(&mut peeked), 5);
+    assert_eq!(c.recv.wnd, wnd);
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 11);
+    assert_eq!(&buf[..5], &peeked);
+    assert_eq!(&buf[..11], b"hello world");
+}
+
+#[test]
+fn pure_window_update_only_moves_the_send_window() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    let (una, nxt, recv_nxt) = (c.send.una, c.send.nxt, c.recv.nxt);
+    let mut update = header(IRS + 1, Some(ISS + 1));
+    update.window_size = 4000;
+    let a = deliver(&mut c, &mut nic, &update, &[]);
+    assert_eq!(c.send.wnd, 4000);
+    assert_eq!((c.send.una, c.send.nxt, c.recv.nxt), (una, nxt, recv_nxt));
+    assert!(!a.contains(Available::READ));
+    assert!(c.incoming.is_empty() && c.out_of_order.is_empty());
+    assert!(sent(&mut nic).is_empty());
+}
+
+#[test]
+fn retransmitted_fin_in_time_wait_is_acked_and_restarts_the_timer() {
+    let (clock, config) = clocked();
+    let msl = Duration::from_secs(1);
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::TimeWait,
+        ConnectionConfig {
+            msl: Some(msl),
+            ..config
+        },
+    );
+    c.on_tick(&mut nic).unwrap();
+
+    clock.advance(msl);
+    let mut fin = header(IRS + 1, Some(ISS + 2));
+    fin.fin = true;
+    deliver(&mut c, &mut nic, &fin, &[]);
+    let ack = sent(&mut nic);
+    assert_eq!(ack.len(), 1);
+    assert_eq!(ack[0].0.acknowledgment_number, IRS + 2);
+
+    // the 2MSL starts over from the FIN's arrival, so where it would have run out it hasn't
+    clock.advance(msl + Duration::from_millis(1));
+    assert!(!c.on_tick(&mut nic).unwrap());
+    assert!(matches!(c.state, State::TimeWait));
+    clock.advance(msl);
+    assert!(c.on_tick(&mut nic).unwrap());
+
+    // anything else is dropped without an answer
+    let mut c = fixture(State::TimeWait, ConnectionConfig::default());
+    send_at(&mut c, &mut nic, IRS + 2, b"late");
+    assert!(sent(&mut nic).is_empty());
+}
+
+#[test]
+fn active_close_goes_through_fin_wait_to_time_wait() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.close().unwrap();
+    assert!(matches!(c.state, State::FinWait1));
+    c.on_tick(&mut nic).unwrap();
+    let fin = sent(&mut nic);
+    assert_eq!(fin.len(), 1);
+    assert!(fin[0].0.fin);
+    assert_eq!(fin[0].0.sequence_number, ISS + 1);
+
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 2)), &[]);
+    assert!(matches!(c.state, State::FinWait2));
+    let mut fin = header(IRS + 1, Some(ISS + 2));
+    fin.fin = true;
+    deliver(&mut c, &mut nic, &fin, &[]);
+    assert!(matches!(c.state, State::TimeWait));
+    let ack = sent(&mut nic);
+    assert_eq!(ack.len(), 1);
+    assert_eq!(ack[0].0.acknowledgment_number, IRS + 2);
+}
+
+#[test]
+fn peer_can_keep_sending_after_we_close() {
+    let config = ConnectionConfig {
+        ack_delay: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    c.close().unwrap();
+    c.on_tick(&mut nic).unwrap();
+    sent(&mut nic);
+
+    let mut buf = [0; 16];
+    // in FIN-WAIT-1, the data doesn't ACK our FIN yet
+    let a = deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 1)), b"more");
+    assert!(matches!(c.state, State::FinWait1));
+    assert!(a.contains(Available::READ));
+    assert_eq!(sent(&mut nic)[0].0.acknowledgment_number, IRS + 5);
+    assert_eq!(c.read(&mut buf), 4);
+
+    // and in FIN-WAIT-2, after it has
+    deliver(&mut c, &mut nic, &header(IRS + 5, Some(ISS + 2)), b"data");
+    assert!(matches!(c.state, State::FinWait2));
+    assert_eq!(sent(&mut nic)[0].0.acknowledgment_number, IRS + 9);
+    assert_eq!(c.read(&mut buf), 4);
+    assert_eq!(&buf[..4], b"data");
+}
+
+#[test]
+fn draining_a_full_buffer_reopens_the_window() {
+    let config = ConnectionConfig {
+        ack_delay: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    let half = RECVQUEUE_SIZE as u32 / 2;
+    send_at(&mut c, &mut nic, IRS + 1, &vec![b'x'; half as usize]);
+    send_at(&mut c, &mut nic, IRS + 1 + half, &vec![b'y'; half as usize]);
+    let acks = sent(&mut nic);
+    assert_eq!(acks.last().unwrap().0.window_size, 0);
+
+    // nothing is said while the window stays shut
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+
+    let mut buf = vec![0; RECVQUEUE_SIZE];
+    assert_eq!(c.read(&mut buf), RECVQUEUE_SIZE);
+    c.on_tick(&mut nic).unwrap();
+    let update = sent(&mut nic);
+    assert_eq!(update.len(), 1);
+    assert_eq!(update[0].0.window_size, RECVQUEUE_SIZE as u16);
+    assert_eq!(update[0].0.acknowledgment_number, IRS + 1 + 2 * half);
+    assert!(update[0].1.is_empty());
+}
+
+#[test]
+fn iss_strategy_picks_the_syn_sequence_number() {
+    let syn_seq = |iss| {
+        let mut nic = Nic::mock();
+        let config = ConnectionConfig {
+            iss,
+            ..Default::default()
+        };
+        Connection::connect(&mut nic, LOCAL, REMOTE, config).unwrap();
+        let syn = sent(&mut nic);
+        assert!(syn[0].0.syn);
+        syn[0].0.sequence_number
+    };
+    assert_eq!(syn_seq(IssStrategy::Fixed(1000)), 1000);
+    assert_eq!(syn_seq(IssStrategy::Fixed(1000)), 1000);
+    let first = syn_seq(IssStrategy::Incrementing);
+    assert!(syn_seq(IssStrategy::Incrementing) > first);
+}
+
+#[test]
+fn abort_discard_throws_the_buffers_away_and_resets() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    send_at(&mut c, &mut nic, IRS + 1, b"unread");
+    send_at(&mut c, &mut nic, IRS + 20, b"out of order");
+    c.unacked.extend(b"unsent");
+    sent(&mut nic);
+
+    c.abort_discard();
+    assert!(c.unacked.is_empty() && c.incoming.is_empty() && c.out_of_order.is_empty());
+    assert!(c.on_tick(&mut nic).unwrap());
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    assert!(rst[0].0.rst);
+    assert!(rst[0].1.is_empty());
+    assert_eq!(c.error().unwrap().kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn effective_send_window_is_the_smaller_window_less_what_is_in_flight() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.set_cwnd(3000);
+    c.send.wnd = 2000;
+    assert_eq!(c.effective_send_window(), 2000);
+    c.send.wnd = 5000;
+    assert_eq!(c.effective_send_window(), 3000);
+
+    c.unacked.extend(b"hello");
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(c.effective_send_window(), 2995);
+    c.set_cwnd(4);
+    assert_eq!(c.effective_send_window(), 0);
+}
+
+#[test]
+fn syn_ack_is_resent_when_the_final_ack_is_lost() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = Connection::listen(
+        LOCAL,
+        ConnectionConfig {
+            iss: IssStrategy::Fixed(ISS),
+            ..config
+        },
+    );
+    deliver(&mut c, &mut nic, &syn(IRS), &[]);
+    assert_eq!(sent(&mut nic).len(), 1);
+
+    // the peer's ACK never makes it, so the SYN-ACK goes again, and then again after twice as long
+    clock.advance(DEFAULT_INITIAL_RTO + Duration::from_millis(1));
+    c.on_tick(&mut nic).unwrap();
+    let resent = sent(&mut nic);
+    assert_eq!(resent.len(), 1);
+    let (ref tcp, _) = resent[0];
+    assert!(tcp.syn && tcp.ack);
+    assert_eq!(tcp.sequence_number, ISS);
+    assert_eq!(tcp.acknowledgment_number, IRS + 1);
+    clock.advance(DEFAULT_INITIAL_RTO + Duration::from_millis(1));
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+    clock.advance(DEFAULT_INITIAL_RTO);
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 1);
+
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 1)), &[]);
+    assert!(matches!(c.state, State::Estab));
+    clock.advance(DEFAULT_MAX_RTO);
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+}
+
+#[test]
+fn probe_recovers_a_lost_window_update() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    let mut closed = header(IRS + 1, Some(ISS + 1));
+    closed.window_size = 0;
+    deliver(&mut c, &mut nic, &closed, &[]);
+    assert_eq!(c.send.wnd, 0);
+    c.unacked.extend(b"hello");
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+
+    // the peer reopens its window, but that ACK is lost. the probe makes it say so again.
+    clock.advance(DEFAULT_INITIAL_RTO + Duration::from_millis(1));
+    c.on_tick(&mut nic).unwrap();
+    let probe = sent(&mut nic);
+    assert_eq!(probe.len(), 1);
+    assert_eq!(probe[0].0.sequence_number, ISS);
+    assert!(probe[0].1.is_empty());
+
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 1)), &[]);
+    assert_eq!(c.send.wnd, 1024);
+    c.on_tick(&mut nic).unwrap();
+    let data = sent(&mut nic);
+    assert_eq!(data.len(), 1);
+    assert_eq!(data[0].1, b"hello");
+}
+
+#[test]
+fn shrinking_the_receive_buffer_keeps_the_right_edge() {
+    let config = ConnectionConfig {
+        ack_delay: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    let right_edge = |nic: &mut Nic| {
+        let (ref tcp, _) = sent(nic)[0];
+        tcp.acknowledgment_number + tcp.window_size as u32
+    };
+    send_at(&mut c, &mut nic, IRS + 1, &[b'x'; 100]);
+    let edge = right_edge(&mut nic);
+    assert_eq!(edge, IRS + 1 + RECVQUEUE_SIZE as u32);
+
+    // whatever was offered stays offered, even though it no longer all fits
+    c.resize_recv_buffer(200);
+    send_at(&mut c, &mut nic, IRS + 101, &[b'x'; 100]);
+    assert_eq!(right_edge(&mut nic), edge);
+    send_at(&mut c, &mut nic, IRS + 201, &[b'x'; 600]);
+    assert_eq!(right_edge(&mut nic), edge);
+
+    // but once the peer has used it up, the window is only what the new size leaves room for
+    let mut buf = vec![0; RECVQUEUE_SIZE];
+    assert_eq!(c.read(&mut buf), 800);
+    send_at(&mut c, &mut nic, IRS + 801, &[b'x'; 224]);
+    let (ref tcp, _) = sent(&mut nic)[0];
+    assert_eq!(tcp.acknowledgment_number, edge);
+    assert!(tcp.window_size <= 200);
+}
+
+#[test]
+fn slow_start_counts_bytes_acked() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    let mss = c.mss as u32;
+    let wnd = 8 * DEFAULT_MSS;
+    c.send.wnd = wnd;
+    c.set_cwnd(4 * mss);
+    c.set_ssthresh(u32::MAX);
+    let ack = |n: u32| {
+        let mut ack = header(IRS + 1, Some(n));
+        ack.window_size = wnd;
+        ack
+    };
+
+    // one stretch ACK for four segments only opens the window by L = 2 segments
+    c.unacked.extend(vec![b'x'; 4 * mss as usize]);
+    c.flush_output(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 4);
+    deliver(&mut c, &mut nic, &ack(ISS + 1 + 4 * mss), &[]);
+    assert_eq!(c.cwnd(), 6 * mss);
+
+    // and an ACK for a little data, by only that little
+    c.unacked.extend(&[b'x'; 100]);
+    c.flush_output(&mut nic).unwrap();
+    deliver(&mut c, &mut nic, &ack(ISS + 101 + 4 * mss), &[]);
+    assert_eq!(c.cwnd(), 6 * mss + 100);
+}
+
+#[test]
+fn missing_ranges_reports_each_gap() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    assert!(c.missing_ranges().is_empty());
+    send_at(&mut c, &mut nic, IRS + 11, b"aaaa");
+    send_at(&mut c, &mut nic, IRS + 21, b"bbbb");
+    assert_eq!(
+        c.missing_ranges(),
+        vec![(IRS + 1, IRS + 11), (IRS + 15, IRS + 21)]
+    );
+
+    // filling the first gap leaves only the second
+    send_at(&mut c, &mut nic, IRS + 1, b"0123456789");
+    assert_eq!(c.missing_ranges(), vec![(IRS + 15, IRS + 21)]);
+}
+
+#[test]
+fn syn_ack_with_a_bad_ack_in_syn_sent_is_reset() {
+    let mut nic = Nic::mock();
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        ..Default::default()
+    };
+    let mut c = Connection::connect(&mut nic, LOCAL, REMOTE, config).unwrap();
+    sent(&mut nic);
+
+    let mut syn_ack = syn(IRS);
+    syn_ack.ack = true;
+    syn_ack.acknowledgment_number = ISS + 100;
+    deliver(&mut c, &mut nic, &syn_ack, &[]);
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    let (ref tcp, _) = rst[0];
+    assert!(tcp.rst && !tcp.ack);
+    assert_eq!(tcp.sequence_number, ISS + 100);
+    assert!(matches!(c.state, State::SynSent));
+
+    // the real one still gets through
+    syn_ack.acknowledgment_number = ISS + 1;
+    deliver(&mut c, &mut nic, &syn_ack, &[]);
+    assert!(matches!(c.state, State::Estab));
+}
+
+#[test]
+fn zero_ack_delay_acks_every_segment() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            ack_delay: Some(Duration::ZERO),
+            ..Default::default()
+        },
+    );
+    for i in 0..3 {
+        send_at(&mut c, &mut nic, IRS + 1 + 5 * i, b"hello");
+        let ack = sent(&mut nic);
+        assert_eq!(ack.len(), 1);
+        assert_eq!(ack[0].0.acknowledgment_number, IRS + 6 + 5 * i);
+    }
+
+    // with the delay left alone, it takes ack_every segments to get an ACK out
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            ack_every: Some(3),
+            ..Default::default()
+        },
+    );
+    let acks: Vec<usize> = (0..3)
+        .map(|i| {
+            send_at(&mut c, &mut nic, IRS + 1 + 5 * i, b"hello");
+            sent(&mut nic).len()
+        })
+        .collect();
+    assert_eq!(acks, [0, 0, 1]);
+}
+
+#[test]
+fn one_segment_can_ack_update_the_window_and_carry_data() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            ack_delay: Some(Duration::ZERO),
+            ..Default::default()
+        },
+    );
+    c.unacked.extend(b"hello");
+    c.on_tick(&mut nic).unwrap();
+    sent(&mut nic);
+
+    let mut tcp = header(IRS + 1, Some(ISS + 6));
+    tcp.window_size = 3000;
+    let a = deliver(&mut c, &mut nic, &tcp, b"world");
+    assert_eq!(c.send.una, ISS + 6);
+    assert!(c.unacked.is_empty());
+    assert_eq!(c.send.wnd, 3000);
+    assert!(a.contains(Available::READ));
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 5);
+    assert_eq!(&buf[..5], b"world");
+    let ack = sent(&mut nic);
+    assert_eq!(ack.len(), 1);
+    assert_eq!(ack[0].0.sequence_number, ISS + 6);
+    assert_eq!(ack[0].0.acknowledgment_number, IRS + 6);
+}
+
+#[test]
+fn syn_ack_options_fit_with_everything_enabled() {
+    let key = b"secret".to_vec();
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        sack: true,
+        user_timeout: Some(Duration::from_secs(30)),
+        tfo_key: Some([7; 16]),
+        md5_key: Some(key.clone()),
+        ..Default::default()
+    };
+    let mut nic = Nic::mock();
+    let mut c = Connection::listen(LOCAL, config);
+
+    // a signed SYN asking for everything we could send back
+    let mut request = syn(IRS);
+    let mut options = vec![1, 1, TCP_OPTION_SACK_PERMITTED, 2];
+    options.extend_from_slice(&[TCP_OPTION_USER_TIMEOUT, 4, 0, 60]);
+    options.extend_from_slice(&[1, 1, TCP_OPTION_FAST_OPEN, 2]);
+    options.extend_from_slice(&[1, 1, TCP_OPTION_MD5, 18]);
+    options.extend_from_slice(&[0; 16]);
+    request.set_options_raw(&options).unwrap();
+    let mut header = Vec::new();
+    request.write(&mut header).unwrap();
+    let signature = md5_signature(
+        &REMOTE.ip().octets(),
+        &LOCAL.ip().octets(),
+        &header,
+        &[],
+        &key,
+    );
+    let at = options.len() - 16;
+    options[at..].copy_from_slice(&signature);
+    request.set_options_raw(&options).unwrap();
+    deliver(&mut c, &mut nic, &request, &[]);
+    assert!(matches!(c.state, State::SynRcvd));
+
+    let syn_ack = nic.take_sent();
+    let (_, tcph, _) = parse(&syn_ack[0]);
+    assert!(tcph.syn() && tcph.ack());
+    assert!(tcph.options().len() <= MAX_OPTIONS_LEN);
+    assert_eq!(tcph.slice().len(), tcph.data_offset() as usize * 4);
+    // the most important ones made it, and the signature is still there and good
+    assert!(find_option(tcph.options(), TCP_OPTION_MSS).is_some());
+    assert!(find_option(tcph.options(), TCP_OPTION_SACK_PERMITTED).is_some());
+    let (iph, tcph, data) = parse(&syn_ack[0]);
+    assert!(md5_ok(&key, &iph, &tcph, data));
+}
+
+#[test]
+fn cork_holds_pieces_back_until_uncorked() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.set_cork(true);
+    c.unacked.extend(b"HTTP/1.1 200 OK\r\n");
+    c.on_tick(&mut nic).unwrap();
+    c.unacked.extend(b"\r\nbody");
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+
+    c.set_cork(false);
+    c.on_tick(&mut nic).unwrap();
+    let segments = sent(&mut nic);
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].1, b"HTTP/1.1 200 OK\r\n\r\nbody");
+}
+
+#[test]
+fn busy_connection_is_never_idle() {
+    let (clock, config) = clocked();
+    let config = ConnectionConfig {
+        idle_timeout: Some(Duration::from_secs(10)),
+        ..config
+    };
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    let mut seq = IRS + 1;
+    for i in 0..6 {
+        clock.advance(Duration::from_secs(6));
+        c.on_tick(&mut nic).unwrap();
+        // every other round the peer sends something, and in between we do
+        if i % 2 == 0 {
+            send_at(&mut c, &mut nic, seq, b"ping");
+            seq += 4;
+        } else {
+            c.unacked.extend(b"pong");
+            c.on_tick(&mut nic).unwrap();
+            let una = c.send.nxt;
+            deliver(&mut c, &mut nic, &header(seq, Some(una)), &[]);
+        }
+        assert!(matches!(c.state, State::Estab));
+    }
+    assert!(!sent(&mut nic).iter().any(|(tcp, _)| tcp.fin));
+}
+
+/// The SACK blocks on a segment, in the order they were sent.
+fn sack_blocks(tcp: &TcpHeader) -> Vec<(u32, u32)> {
+    find_option(tcp.options(), TCP_OPTION_SACK).map_or(Vec::new(), |blocks| {
+        blocks
+            .chunks(8)
+            .map(|b| {
+                (
+                    u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+                    u32::from_be_bytes([b[4], b[5], b[6], b[7]]),
+                )
+            })
+            .collect()
+    })
+}
+
+#[test]
+fn sack_blocks_fit_and_start_with_the_newest() {
+    let send_holes = |max_sack_blocks| {
+        let mut nic = Nic::mock();
+        let mut c = fixture(
+            State::Estab,
+            ConnectionConfig {
+                max_sack_blocks,
+                ..Default::default()
+            },
+        );
+        c.sack_permitted = true;
+        for start in [10, 30, 50, 20, 40] {
+            send_at(&mut c, &mut nic, IRS + start, b"xxxx");
+        }
+        let (tcp, _) = sent(&mut nic).pop().unwrap();
+        assert!(tcp.options().len() <= MAX_OPTIONS_LEN);
+        sack_blocks(&tcp)
+    };
+    let ranges = |starts: &[u32]| -> Vec<(u32, u32)> {
+        starts.iter().map(|&s| (IRS + s, IRS + s + 4)).collect()
+    };
+
+    // five holes and room for four blocks: the newest goes first, then the rest in order
+    assert_eq!(send_holes(None), ranges(&[40, 10, 20, 30]));
+    assert_eq!(send_holes(Some(2)), ranges(&[40, 10]));
+}
+
+#[test]
+fn one_ack_can_cover_the_syn_and_fin() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::SynRcvd, ConnectionConfig::default());
+    c.close().unwrap();
+    assert!(matches!(c.state, State::FinWait1));
+    c.on_tick(&mut nic).unwrap();
+    let fin = sent(&mut nic);
+    assert_eq!(fin.len(), 1);
+    assert!(fin[0].0.fin);
+    assert_eq!(fin[0].0.sequence_number, ISS + 1);
+
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 2)), &[]);
+    assert!(matches!(c.state, State::FinWait2));
+    assert_eq!(c.send.una, ISS + 2);
+}
+
+#[test]
+fn close_in_syn_rcvd_sends_queued_data_once_established() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::SynRcvd, ConnectionConfig::default());
+    c.unacked.extend(b"hello");
+    c.close().unwrap();
+    assert!(matches!(c.state, State::SynRcvd));
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+
+    // the ACK of our SYN lets the data go, with the FIN right behind it, and one ACK covers both
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 1)), &[]);
+    assert!(matches!(c.state, State::FinWait1));
+    c.on_tick(&mut nic).unwrap();
+    let out = sent(&mut nic);
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].0.sequence_number, ISS + 1);
+    assert_eq!(out[0].1, b"hello");
+    assert!(out[0].0.fin);
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 7)), &[]);
+    assert!(matches!(c.state, State::FinWait2));
+    assert!(c.unacked.is_empty());
+}
+
+#[test]
+fn idle_restart_brings_cwnd_back_down() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+    let mss = c.mss as u32;
+    round_trip(
+        &mut c,
+        &mut nic,
+        &clock,
+        b"hello",
+        Duration::from_millis(100),
+    );
+    c.set_cwnd(20 * mss);
+
+    // carrying on straight away keeps the window it has
+    round_trip(
+        &mut c,
+        &mut nic,
+        &clock,
+        b"more",
+        Duration::from_millis(100),
+    );
+    assert!(c.cwnd() >= 20 * mss);
+
+    // but after sitting idle for longer than an RTO, it starts over from the initial window
+    clock.advance(c.rto() + Duration::from_millis(1));
+    c.unacked.extend(b"again");
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(c.cwnd(), Congestion::initial_window(c.mss));
+    assert_eq!(sent(&mut nic).len(), 1);
+}
+
+#[test]
+fn negotiated_options_reflect_the_handshake() {
+    let mut nic = Nic::mock();
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        sack: true,
+        ..Default::default()
+    };
+    let mut c = Connection::listen(LOCAL, config.clone());
+    let mut request = syn(IRS);
+    let mut options = vec![TCP_OPTION_MSS, 4];
+    options.extend_from_slice(&1000u16.to_be_bytes());
+    options.extend_from_slice(&[1, 1, TCP_OPTION_SACK_PERMITTED, 2]);
+    request.set_options_raw(&options).unwrap();
+    deliver(&mut c, &mut nic, &request, &[]);
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 1)), &[]);
+    assert!(matches!(c.state, State::Estab));
+    assert_eq!(
+        c.negotiated_options(),
+        NegotiatedOptions {
+            mss: 1000,
+            sack_permitted: true,
+            user_timeout: None,
+        }
+    );
+
+    // a bare SYN leaves everything at the defaults
+    let mut c = Connection::listen(LOCAL, config);
+    deliver(&mut c, &mut nic, &syn(IRS), &[]);
+    let options = c.negotiated_options();
+    assert_eq!(options.mss, DEFAULT_MSS);
+    assert!(!options.sack_permitted);
+}
+
+#[test]
+fn fin_is_taken_into_a_zero_window() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            ack_delay: Some(Duration::ZERO),
+            ..Default::default()
+        },
+    );
+    send_at(&mut c, &mut nic, IRS + 1, &[b'x'; RECVQUEUE_SIZE]);
+    assert_eq!(c.recv.wnd, 0);
+    sent(&mut nic);
+
+    let end = IRS + 1 + RECVQUEUE_SIZE as u32;
+    let mut fin = header(end, Some(ISS + 1));
+    fin.fin = true;
+    deliver(&mut c, &mut nic, &fin, &[]);
+    assert!(matches!(c.state, State::CloseWait));
+    let ack = sent(&mut nic);
+    assert_eq!(ack.len(), 1);
+    assert_eq!(ack[0].0.acknowledgment_number, end + 1);
+
+    // and the application gets all the data before seeing the end of it
+    let mut buf = vec![0; RECVQUEUE_SIZE];
+    assert_eq!(c.read(&mut buf), RECVQUEUE_SIZE);
+    assert!(c.is_rcv_closed());
+}
+
+#[test]
+fn stats_count_bytes_and_rates_over_the_clock() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config);
+
+    send_at(&mut c, &mut nic, IRS + 1, &[b'x'; 300]);
+    clock.advance(Duration::from_millis(500));
+    send_at(&mut c, &mut nic, IRS + 301, &[b'y'; 500]);
+    c.unacked.extend(vec![b'z'; 400]);
+    c.flush_output(&mut nic).unwrap();
+
+    let stats = c.stats();
+    assert_eq!(stats.bytes_received, 800);
+    assert_eq!(stats.bytes_sent, 400);
+    assert!(
+        (700.0..=900.0).contains(&stats.recv_rate),
+        "{}",
+        stats.recv_rate
+    );
+    assert!(
+        (300.0..=500.0).contains(&stats.send_rate),
+        "{}",
+        stats.send_rate
+    );
+
+    // the first segment falls out of the window, then everything does
+    clock.advance(Duration::from_millis(700));
+    assert_eq!(c.stats().recv_rate, 500.0);
+    clock.advance(Duration::from_secs(1));
+    let stats = c.stats();
+    assert_eq!((stats.recv_rate, stats.send_rate), (0.0, 0.0));
+    assert_eq!((stats.bytes_received, stats.bytes_sent), (800, 400));
+}
+
+fn reset_reason(c: &Connection) -> ResetReason {
+    let err = c.error().unwrap();
+    *err.get_ref()
+        .unwrap()
+        .downcast_ref::<ResetReason>()
+        .unwrap()
+}
+
+#[test]
+fn reset_reason_tells_a_peer_reset_from_a_timeout() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    let mut rst = header(IRS + 1, None);
+    rst.rst = true;
+    deliver(&mut c, &mut nic, &rst, &[]);
+    assert_eq!(reset_reason(&c), ResetReason::PeerReset);
+    assert_eq!(c.error().unwrap().kind(), io::ErrorKind::ConnectionReset);
+
+    // there's no keepalive to run out, so let an idle peer exhaust the user timeout instead
+    let (clock, config) = clocked();
+    let mut c = fixture(State::Estab, config);
+    c.user_timeout = Some(DEFAULT_INITIAL_RTO);
+    c.unacked.extend(b"anyone there?");
+    c.on_tick(&mut nic).unwrap();
+    clock.advance(2 * DEFAULT_INITIAL_RTO);
+    assert!(c.on_tick(&mut nic).unwrap());
+    assert_eq!(reset_reason(&c), ResetReason::Timeout);
+    assert_eq!(c.error().unwrap().kind(), io::ErrorKind::TimedOut);
+
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.abort_discard();
+    assert!(c.on_tick(&mut nic).unwrap());
+    assert_eq!(reset_reason(&c), ResetReason::UserAbort);
+}
+
+#[test]
+fn pure_ack_fast_path_moves_una_like_the_full_path() {
+    // the fast path only runs in Estab, so CloseWait goes the long way round
+    let mut results = Vec::new();
+    for state in [State::Estab, State::CloseWait] {
+        let mut nic = Nic::mock();
+        let mut c = fixture(state, ConnectionConfig::default());
+        c.unacked.extend(b"hello world");
+        c.flush_output(&mut nic).unwrap();
+        sent(&mut nic);
+
+        let mut ack = header(c.recv.nxt, Some(ISS + 1 + 5));
+        ack.window_size = 2000;
+        deliver(&mut c, &mut nic, &ack, &[]);
+        assert!(sent(&mut nic).is_empty());
+        results.push((c.send.una, c.send.wnd, c.unacked.clone()));
+    }
+    assert_eq!(results[0], results[1]);
+    assert_eq!(results[0].0, ISS + 1 + 5);
+    assert_eq!(results[0].1, 2000);
+}
+
+#[test]
+fn completing_the_handshake_sends_nothing_when_theres_nothing_to_say() {
+    let mut nic = Nic::mock();
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        ack_delay: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let first = packet(&syn(IRS), &[]);
+    let (iph, tcph, data) = parse(&first);
+    let mut c = Connection::accept(&mut nic, iph, tcph, data, config)
+        .unwrap()
+        .unwrap();
+    assert_eq!(sent(&mut nic).len(), 1);
+
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 1)), &[]);
+    assert!(matches!(c.state, State::Estab));
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic).is_empty());
+}
+
+#[test]
+fn event_log_records_the_handshake_in_order() {
+    let mut nic = Nic::mock();
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        event_log: true,
+        ..Default::default()
+    };
+    let first = packet(&syn(IRS), &[]);
+    let (iph, tcph, data) = parse(&first);
+    let mut c = Connection::accept(&mut nic, iph, tcph, data, config)
+        .unwrap()
+        .unwrap();
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 1)), &[]);
+    let seg = |seq, ack, syn| LoggedSegment {
+        seq,
+        ack,
+        syn,
+        fin: false,
+        rst: false,
+        window: 1024,
+        len: 0,
+    };
+    assert_eq!(
+        c.event_log(),
+        vec![
+            LogEntry::State("Listen".into()),
+            LogEntry::In(seg(IRS, None, true)),
+            LogEntry::State("SynRcvd".into()),
+            LogEntry::Out(seg(ISS, Some(IRS + 1), true)),
+            LogEntry::In(seg(IRS + 1, Some(ISS + 1), false)),
+            LogEntry::State("Estab".into()),
+        ]
+    );
+}
+
+#[test]
+fn segment_over_both_window_edges_keeps_only_the_middle() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            ack_delay: Some(Duration::ZERO),
+            ..Default::default()
+        },
+    );
+    c.resize_recv_buffer(8);
+    send_at(&mut c, &mut nic, IRS + 1, b"abc");
+    assert_eq!(c.recv.wnd, 5);
+    sent(&mut nic);
+
+    // "bc" is already here, and only five of the new bytes fit
+    send_at(&mut c, &mut nic, IRS + 2, b"bcDEFGHIJK");
+    assert_eq!(c.incoming, b"abcDEFGH");
+    assert_eq!(c.recv.nxt, IRS + 9);
+    let ack = sent(&mut nic).pop().unwrap().0;
+    assert_eq!(ack.acknowledgment_number, IRS + 9);
+    assert_eq!(ack.window_size, 0);
+}
+
+#[test]
+fn ao_macs_are_checked() {
+    let config = |send_id, recv_id| ConnectionConfig {
+        ao_keys: vec![AoKey {
+            send_id,
+            recv_id,
+            master_key: b"secret".to_vec(),
+        }],
+        ack_delay: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, config(1, 2));
+    let mut peer = Connection::fixture(REMOTE, LOCAL, State::Estab, config(2, 1), IRS, ISS);
+    peer.unacked.extend(b"hello");
+    peer.on_tick(&mut nic).unwrap();
+    let signed = nic.take_sent().remove(0);
+
+    // a different payload, checksum fixed up, under the old mac
+    let (_, tcph, _) = parse(&signed);
+    let tampered = packet(&tcph.to_header(), b"jello");
+    deliver_raw(&mut c, &mut nic, &tampered);
+    assert_eq!(c.drops.signature, 1);
+    assert!(c.incoming.is_empty());
+    assert!(sent(&mut nic).is_empty());
+
+    deliver_raw(&mut c, &mut nic, &signed);
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 5);
+    assert_eq!(&buf[..5], b"hello");
+    // our ACK carries a mac the peer accepts
+    let ack = nic.take_sent().remove(0);
+    let (_, ack_tcph, _) = parse(&ack);
+    assert!(ao::parse(ack_tcph.options()).is_some());
+    deliver_raw(&mut peer, &mut nic, &ack);
+    assert_eq!(peer.drops.signature, 0);
+    assert!(peer.unacked.is_empty());
+}
+
+#[test]
+fn syn_carrying_data_in_estab_only_gets_a_challenge_ack() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    // right at RCV.NXT, where the data would otherwise have been taken
+    let mut stray = header(IRS + 1, Some(ISS + 1));
+    stray.syn = true;
+    deliver(&mut c, &mut nic, &stray, b"sneaky");
+    let ack = sent(&mut nic);
+    assert_eq!(ack.len(), 1);
+    assert!(ack[0].0.ack && !ack[0].0.syn && !ack[0].0.rst);
+    assert_eq!(ack[0].0.acknowledgment_number, IRS + 1);
+    assert!(c.incoming.is_empty());
+    assert_eq!(c.recv.nxt, IRS + 1);
+    assert!(matches!(c.state, State::Estab));
+}
+
+#[test]
+fn unacceptable_segment_gets_an_ack_unless_configured_not_to() {
+    for drop_unacceptable in [false, true] {
+        let mut nic = Nic::mock();
+        let config = ConnectionConfig {
+            drop_unacceptable,
+            ..Default::default()
+        };
+        let mut c = fixture(State::Estab, config);
+        let beyond = IRS + 1 + RECVQUEUE_SIZE as u32 + 100;
+        deliver(&mut c, &mut nic, &header(beyond, Some(ISS + 1)), b"far out");
+        assert!(c.incoming.is_empty());
+        assert_eq!(c.drops.out_of_window, 1);
+        let acks = sent(&mut nic);
+        if drop_unacceptable {
+            assert!(acks.is_empty());
+        } else {
+            assert_eq!(acks.len(), 1);
+            assert_eq!(acks[0].0.sequence_number, ISS + 1);
+            assert_eq!(acks[0].0.acknowledgment_number, IRS + 1);
+        }
+    }
+}
+
+#[test]
+fn urgent_pointer_past_the_data_is_clamped() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            ack_delay: Some(Duration::ZERO),
+            ..Default::default()
+        },
+    );
+    let mut urgent = header(IRS + 1, Some(ISS + 1));
+    urgent.urg = true;
+    urgent.urgent_pointer = 60000;
+    deliver(&mut c, &mut nic, &urgent, b"abc");
+    assert_eq!(c.recv.up, Some(IRS + 4));
+    assert_eq!(c.stats().urgent_pointers_clamped, 1);
+
+    // and the connection carries on as usual
+    assert_eq!(sent(&mut nic)[0].0.acknowledgment_number, IRS + 4);
+    send_at(&mut c, &mut nic, IRS + 4, b"def");
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 6);
+    assert_eq!(&buf[..6], b"abcdef");
+    assert!(matches!(c.state, State::Estab));
+    assert_eq!(c.stats().urgent_pointers_clamped, 1);
+}
+
+#[test]
+fn drops_are_counted_by_reason_until_reset() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+
+    let mut corrupt = packet(&header(IRS + 1, Some(ISS + 1)), b"hello");
+    *corrupt.last_mut().unwrap() ^= 0xff;
+    deliver_raw(&mut c, &mut nic, &corrupt);
+    let beyond = IRS + 1 + RECVQUEUE_SIZE as u32 + 100;
+    deliver(&mut c, &mut nic, &header(beyond, Some(ISS + 1)), b"far out");
+    assert!(c.incoming.is_empty());
+    assert_eq!(
+        c.stats().dropped,
+        Drops {
+            checksum: 1,
+            out_of_window: 1,
+            ..Default::default()
+        }
+    );
+
+    c.reset_stats();
+    assert_eq!(c.stats().dropped, Drops::default());
+}
+
+#[test]
+fn syn_ack_never_offers_a_window_scale() {
+    // kind 3 (RFC 7323 S2.2); there's no constant, since we never send or honour it
+    const WINDOW_SCALE: u8 = 3;
+    for offered in [false, true] {
+        let mut nic = Nic::mock();
+        let config = ConnectionConfig {
+            iss: IssStrategy::Fixed(ISS),
+            ..Default::default()
+        };
+        let mut request = syn(IRS);
+        if offered {
+            request.set_options_raw(&[1, WINDOW_SCALE, 3, 7]).unwrap();
+        }
+        let first = packet(&request, &[]);
+        let (iph, tcph, data) = parse(&first);
+        let mut c = Connection::accept(&mut nic, iph, tcph, data, config)
+            .unwrap()
+            .unwrap();
+        let (syn_ack, _) = sent(&mut nic).remove(0);
+        assert!(find_option(syn_ack.options(), WINDOW_SCALE).is_none());
+        assert_eq!(syn_ack.window_size, RECVQUEUE_SIZE as u16);
+
+        // and the peer's windows are taken as they are, unscaled
+        let mut ack = header(IRS + 1, Some(ISS + 1));
+        ack.window_size = 300;
+        deliver(&mut c, &mut nic, &ack, &[]);
+        assert!(matches!(c.state, State::Estab));
+        assert_eq!(c.send.wnd, 300);
+    }
+}
+
+#[test]
+fn out_of_order_data_is_capped_furthest_first() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            max_out_of_order: Some(100),
+            ..Default::default()
+        },
+    );
+    c.sack_permitted = true;
+    // twenty segments of ten bytes, each with a gap in front
+    for i in 0..20 {
+        send_at(&mut c, &mut nic, IRS + 11 + 20 * i, &[b'x'; 10]);
+        let buffered: usize = c.out_of_order.values().map(Vec::len).sum();
+        assert!(buffered <= 100);
+    }
+    assert_eq!(c.out_of_order.len(), 10);
+    assert_eq!(c.out_of_order.keys().max(), Some(&(IRS + 11 + 20 * 9)));
+
+    // what was thrown away isn't SACKed either
+    let (ack, _) = sent(&mut nic).pop().unwrap();
+    let blocks = sack_blocks(&ack);
+    assert!(!blocks.is_empty());
+    assert!(blocks
+        .iter()
+        .all(|&(_, end)| !wrapping_lt(IRS + 11 + 20 * 9 + 10, end)));
+}
+
+#[test]
+fn max_burst_caps_each_flush() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            max_burst: Some(4),
+            ..Default::default()
+        },
+    );
+    let mss = c.mss as usize;
+    c.send.wnd = 20 * DEFAULT_MSS;
+    c.set_cwnd(20 * DEFAULT_MSS as u32);
+    c.unacked.extend(vec![b'x'; 10 * mss]);
+
+    c.flush_output(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 4);
+    // the rest goes out over the next ticks, a burst at a time
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 4);
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(sent(&mut nic).len(), 2);
+    assert_eq!(c.send.nxt, ISS + 1 + 10 * mss as u32);
+}
+
+#[test]
+fn passive_close_goes_through_close_wait_and_last_ack() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            ack_delay: Some(Duration::ZERO),
+            ..Default::default()
+        },
+    );
+    let mut fin = header(IRS + 1, Some(ISS + 1));
+    fin.fin = true;
+    deliver(&mut c, &mut nic, &fin, b"bye");
+    assert!(matches!(c.state, State::CloseWait));
+    let ack = sent(&mut nic);
+    assert_eq!(ack.len(), 1);
+    assert_eq!(ack[0].0.acknowledgment_number, IRS + 5);
+    assert!(!ack[0].0.fin);
+
+    // the application drains what's left, and can still send before closing its end
+    let mut buf = [0; 16];
+    assert_eq!(c.read(&mut buf), 3);
+    assert!(c.is_rcv_closed());
+    c.unacked.extend(b"ok");
+    c.close().unwrap();
+    assert!(matches!(c.state, State::LastAck));
+    c.flush_output(&mut nic).unwrap();
+    let out = sent(&mut nic);
+    assert_eq!(out.last().unwrap().1, b"ok");
+    assert!(out.last().unwrap().0.fin);
+
+    deliver(&mut c, &mut nic, &header(IRS + 5, Some(ISS + 4)), &[]);
+    assert!(matches!(c.state, State::Closed));
+    assert!(c.error().is_none());
+    assert!(sent(&mut nic).is_empty());
+}
+
+#[test]
+fn crossing_fins_go_through_closing_to_time_wait() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.close().unwrap();
+    c.on_tick(&mut nic).unwrap();
+    assert!(sent(&mut nic)[0].0.fin);
+    assert!(matches!(c.state, State::FinWait1));
+
+    // the peer's FIN was sent before ours arrived, so it doesn't ACK it
+    let mut fin = header(IRS + 1, Some(ISS + 1));
+    fin.fin = true;
+    deliver(&mut c, &mut nic, &fin, &[]);
+    assert!(matches!(c.state, State::Closing));
+    let ack = sent(&mut nic);
+    assert_eq!(ack.len(), 1);
+    assert_eq!(ack[0].0.acknowledgment_number, IRS + 2);
+
+    deliver(&mut c, &mut nic, &header(IRS + 2, Some(ISS + 2)), &[]);
+    assert!(matches!(c.state, State::TimeWait));
+    assert!(sent(&mut nic).is_empty());
+}
+
+#[test]
+fn time_wait_is_reaped_after_twice_the_default_msl() {
+    let (clock, config) = clocked();
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::FinWait2, config);
+    let mut fin = header(IRS + 1, Some(ISS + 2));
+    fin.fin = true;
+    deliver(&mut c, &mut nic, &fin, &[]);
+    assert!(matches!(c.state, State::TimeWait));
+
+    clock.advance(2 * DEFAULT_MSL - Duration::from_millis(1));
+    assert!(!c.on_tick(&mut nic).unwrap());
+    assert!(matches!(c.state, State::TimeWait));
+    clock.advance(Duration::from_millis(2));
+    assert!(c.on_tick(&mut nic).unwrap());
+}
+
+#[test]
+fn options_added_to_one_segment_dont_stick_to_the_next() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            ack_delay: Some(Duration::ZERO),
+            ..Default::default()
+        },
+    );
+    c.sack_permitted = true;
+    send_at(&mut c, &mut nic, IRS + 6, b"world");
+    let (ack, _) = sent(&mut nic).pop().unwrap();
+    assert_eq!(sack_blocks(&ack), vec![(IRS + 6, IRS + 11)]);
+
+    // the hole fills, so the next ACK has nothing to SACK, and data after it no options at all
+    send_at(&mut c, &mut nic, IRS + 1, b"hello");
+    let (ack, _) = sent(&mut nic).pop().unwrap();
+    assert_eq!(ack.acknowledgment_number, IRS + 11);
+    assert!(ack.options().is_empty());
+    c.unacked.extend(b"reply");
+    c.flush_output(&mut nic).unwrap();
+    let (data, payload) = sent(&mut nic).pop().unwrap();
+    assert_eq!(payload, b"reply");
+    assert!(data.options().is_empty());
+}
+
+#[test]
+fn headers_patched_from_the_template_match_what_etherparse_writes() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.send.wnd = 4 * DEFAULT_MSS;
+    // an odd length, so the checksum has a byte left over at the end
+    c.unacked.extend(&[b'x'; DEFAULT_MSS as usize + 7]);
+    c.close().unwrap();
+    c.flush_output(&mut nic).unwrap();
+    let packets = nic.take_sent();
+    assert_eq!(packets.len(), 2);
+    assert!(parse(&packets[1]).1.fin());
+
+    for packet in &packets {
+        let (iph, tcph, data) = parse(packet);
+        assert!(tcph.options().is_empty());
+        let ip = iph.to_header();
+        let mut tcp = tcph.to_header();
+        tcp.checksum = tcp.calc_checksum_ipv4(&ip, data).unwrap();
+        let mut expected = Vec::new();
+        ip.write(&mut expected).unwrap();
+        tcp.write(&mut expected).unwrap();
+        expected.extend_from_slice(data);
+        assert_eq!(packet, &expected);
+    }
+}
+
+#[test]
+fn bare_fin_behind_a_hole_isnt_sacked() {
+    let mut nic = Nic::mock();
+    let mut c = fixture(State::Estab, ConnectionConfig::default());
+    c.sack_permitted = true;
+    let mut fin = header(IRS + 6, Some(ISS + 1));
+    fin.fin = true;
+    deliver(&mut c, &mut nic, &fin, &[]);
+    assert!(c.out_of_order.is_empty());
+    assert!(c.out_of_order_ranges().is_empty());
+    let (ack, _) = sent(&mut nic).pop().unwrap();
+    assert_eq!(ack.acknowledgment_number, IRS + 1);
+    assert!(sack_blocks(&ack).is_empty());
+
+    send_at(&mut c, &mut nic, IRS + 1, b"hello");
+    assert!(matches!(c.state, State::CloseWait));
+    assert_eq!(c.recv.nxt, IRS + 7);
+}
+
+#[test]
+fn fin_wait_2_timeout_counts_from_entry_and_is_a_deadline() {
+    let (clock, config) = clocked();
+    let timeout = Duration::from_secs(60);
+    let mut nic = Nic::mock();
+    let mut c = fixture(
+        State::Estab,
+        ConnectionConfig {
+            fin_wait2_timeout: Some(timeout),
+            ..config
+        },
+    );
+    c.close().unwrap();
+    c.on_tick(&mut nic).unwrap();
+    clock.advance(Duration::from_millis(300));
+    deliver(&mut c, &mut nic, &header(IRS + 1, Some(ISS + 2)), &[]);
+    assert!(matches!(c.state, State::FinWait2));
+    let entered = clock.now();
+    assert_eq!(c.next_timeout(), Some(entered + timeout));
+
+    // a tick that comes late doesn't push the deadline back
+    clock.advance(Duration::from_secs(5));
+    c.on_tick(&mut nic).unwrap();
+    assert_eq!(c.next_timeout(), Some(entered + timeout));
+    clock.advance(timeout - Duration::from_secs(5));
+    assert!(c.on_tick(&mut nic).unwrap());
+    assert_eq!(c.error().unwrap().kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn retransmitted_fin_is_acked_even_when_dropping_unacceptable_segments() {
+    for state in [State::CloseWait, State::LastAck, State::Closing] {
+        let mut nic = Nic::mock();
+        let config = ConnectionConfig {
+            drop_unacceptable: true,
+            ..Default::default()
+        };
+        let mut c = fixture(state, config);
+        assert_eq!(c.recv.nxt, IRS + 2);
+
+        let mut fin = header(IRS + 1, Some(ISS + 1));
+        fin.fin = true;
+        deliver(&mut c, &mut nic, &fin, &[]);
+        let ack = sent(&mut nic);
+        assert_eq!(ack.len(), 1, "in {:?}", c.state);
+        assert_eq!(ack[0].0.acknowledgment_number, IRS + 2);
+
+        // anything else out of the window is still dropped without a word
+        send_at(&mut c, &mut nic, IRS + 1, b"x");
+        assert!(sent(&mut nic).is_empty());
+    }
+}
+
+#[test]
+fn constant_time_eq_compares_whole_slices() {
+    assert!(constant_time_eq(b"cookie", b"cookie"));
+    assert!(!constant_time_eq(b"cookie", b"cookiE"));
+    assert!(!constant_time_eq(b"cookie", b"Cookie"));
+    assert!(!constant_time_eq(b"cookie", b"cook"));
+    assert!(constant_time_eq(b"", b""));
+}
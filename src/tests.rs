@@ -0,0 +1,670 @@
+use super::*;
+use crate::tcp::tests::{header, packet_from, sent, syn, IRS, ISS, LOCAL, REMOTE};
+
+/// the quad of connections to `REMOTE`
+const QUAD: Quad = Quad {
+    src: (Ipv4Addr::new(10, 0, 0, 2), 5000),
+    dst: (Ipv4Addr::new(10, 0, 0, 1), 80),
+};
+/// a peer other than `REMOTE`, on the same port
+const OTHER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+fn quad(remote: Ipv4Addr) -> Quad {
+    Quad {
+        src: (remote, REMOTE.port()),
+        ..QUAD
+    }
+}
+
+/// An interface handle with no packet loop, whose connections get `config` and go by `clock`.
+fn handle(clock: &Arc<MockClock>, config: ConnectionConfig) -> InterfaceHandle {
+    let ih = Arc::new(Foobar::default());
+    ih.manager.lock().unwrap().config = ConnectionConfig {
+        clock: Some(clock.clone()),
+        ..config
+    };
+    ih
+}
+
+/// Puts a connection to `remote` (on `REMOTE`'s port) straight into `state`.
+fn insert(ih: &InterfaceHandle, remote: Ipv4Addr, state: State) {
+    let mut cm = ih.manager.lock().unwrap();
+    let peer = SocketAddrV4::new(remote, REMOTE.port());
+    let c = tcp::Connection::fixture(LOCAL, peer, state, cm.config.clone(), ISS, IRS);
+    cm.connections.insert(quad(remote), c);
+}
+
+/// Starts listening on `LOCAL`'s port, for any address and source.
+fn listen(ih: &InterfaceHandle) {
+    let mut cm = ih.manager.lock().unwrap();
+    let listener = Listener {
+        id: 0,
+        local: None,
+        allow: None,
+        pending: VecDeque::new(),
+    };
+    cm.pending.insert(LOCAL.port(), vec![listener]);
+}
+
+/// Hands the packet loop a segment from `REMOTE`, as if it had just come off the nic.
+fn dispatch(ih: &InterfaceHandle, nic: &mut Nic, tcp: &etherparse::TcpHeader, data: &[u8]) {
+    dispatch_from(ih, nic, *REMOTE.ip(), tcp, data);
+}
+
+/// Like [`dispatch`], from `remote` on `REMOTE`'s port.
+fn dispatch_from(
+    ih: &InterfaceHandle,
+    nic: &mut Nic,
+    remote: Ipv4Addr,
+    tcp: &etherparse::TcpHeader,
+    data: &[u8],
+) {
+    dispatch_packet(ih, nic, packet_from(remote, tcp, data));
+}
+
+/// Hands the packet loop an IP packet, exactly as it would come off the nic.
+fn dispatch_packet(ih: &InterfaceHandle, nic: &mut Nic, packet: Vec<u8>) {
+    nic.inject(packet);
+    let mut buf = [0u8; 1504];
+    let nbytes = nic.recv(&mut buf).unwrap();
+    let mut batches = HashMap::new();
+    on_ip_packet(nic, ih, &buf[..nbytes], &mut batches).unwrap();
+    for (q, segments) in batches {
+        on_segments(nic, ih, q, &segments).unwrap();
+    }
+}
+
+fn has_connection(ih: &InterfaceHandle) -> bool {
+    ih.manager.lock().unwrap().connections.contains_key(&QUAD)
+}
+
+#[test]
+fn fin_wait_2_timeout_reaps_the_connection() {
+    let clock = Arc::new(MockClock::new());
+    let config = ConnectionConfig {
+        fin_wait2_timeout: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let ih = handle(&clock, config);
+    let mut nic = Nic::mock();
+    insert(&ih, *REMOTE.ip(), State::FinWait2);
+
+    on_tick(&mut nic, &ih).unwrap();
+    assert!(has_connection(&ih));
+
+    clock.advance(Duration::from_secs(61));
+    on_tick(&mut nic, &ih).unwrap();
+    assert!(!has_connection(&ih));
+}
+
+#[test]
+fn closed_connection_is_kept_while_held() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    insert(&ih, *REMOTE.ip(), State::Closed);
+    ih.manager.lock().unwrap().held.insert(QUAD);
+
+    // the application has yet to hear that it closed
+    on_tick(&mut nic, &ih).unwrap();
+    assert!(has_connection(&ih));
+
+    ih.manager.lock().unwrap().held.remove(&QUAD);
+    on_tick(&mut nic, &ih).unwrap();
+    assert!(!has_connection(&ih));
+}
+
+#[test]
+fn connection_cap_only_counts_live_connections() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    ih.manager.lock().unwrap().max_connections = Some(1);
+    let mut nic = Nic::mock();
+    listen(&ih);
+
+    // one that has closed, but hasn't been reaped yet, doesn't take up the only slot
+    insert(&ih, OTHER, State::Closed);
+    dispatch(&ih, &mut nic, &syn(IRS), &[]);
+    assert!(has_connection(&ih));
+    let syn_ack = sent(&mut nic);
+    assert!(syn_ack[0].0.syn && syn_ack[0].0.ack);
+
+    // but a live one does, and a SYN beyond the cap is refused
+    ih.manager.lock().unwrap().connections.remove(&QUAD);
+    insert(&ih, OTHER, State::Estab);
+    dispatch(&ih, &mut nic, &syn(IRS), &[]);
+    assert!(!has_connection(&ih));
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    assert!(rst[0].0.rst);
+}
+
+#[test]
+fn stray_ack_is_reset_at_its_ack_number() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    let stray = header(IRS + 1, Some(ISS + 77));
+
+    let check = |nic: &mut Nic| {
+        let rst = sent(nic);
+        assert_eq!(rst.len(), 1);
+        let (ref tcp, _) = rst[0];
+        assert!(tcp.rst && !tcp.ack);
+        assert_eq!(tcp.sequence_number, ISS + 77);
+        assert_eq!(tcp.destination_port, REMOTE.port());
+    };
+
+    // with nobody listening on the port
+    dispatch(&ih, &mut nic, &stray, &[]);
+    check(&mut nic);
+
+    // with a listener that has no room for another connection
+    listen(&ih);
+    ih.manager.lock().unwrap().max_connections = Some(1);
+    insert(&ih, OTHER, State::Estab);
+    dispatch(&ih, &mut nic, &stray, &[]);
+    check(&mut nic);
+    assert!(!has_connection(&ih));
+}
+
+#[test]
+fn shutdown_all_waits_out_handshakes_and_closes() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    let connecting = Ipv4Addr::new(10, 0, 0, 4);
+    insert(&ih, *REMOTE.ip(), State::Estab);
+    insert(&ih, OTHER, State::CloseWait);
+    insert(&ih, connecting, State::SynSent);
+
+    assert_eq!(ih.manager.lock().unwrap().shutdown_all(), 3);
+    on_tick(&mut nic, &ih).unwrap();
+    // FINs from the two that were open, and nothing yet from the one still connecting
+    let fins = sent(&mut nic);
+    assert_eq!(fins.len(), 2);
+    assert!(fins.iter().all(|(tcp, _)| tcp.fin));
+
+    let mut syn_ack = syn(IRS);
+    syn_ack.ack = true;
+    syn_ack.acknowledgment_number = ISS + 1;
+    dispatch_from(&ih, &mut nic, connecting, &syn_ack, &[]);
+    assert_eq!(ih.manager.lock().unwrap().shutdown_all(), 3);
+    on_tick(&mut nic, &ih).unwrap();
+    let fin = sent(&mut nic);
+    assert!(fin.iter().any(|(tcp, _)| tcp.fin));
+
+    // every peer ACKs our FIN, and the two that hadn't closed their end yet do so too
+    let mut fin_ack = header(IRS + 1, Some(ISS + 2));
+    fin_ack.fin = true;
+    dispatch(&ih, &mut nic, &fin_ack, &[]);
+    dispatch_from(&ih, &mut nic, connecting, &fin_ack, &[]);
+    dispatch_from(&ih, &mut nic, OTHER, &header(IRS + 2, Some(ISS + 2)), &[]);
+    assert_eq!(ih.manager.lock().unwrap().shutdown_all(), 0);
+}
+
+#[test]
+fn new_connection_inherits_the_cached_ssthresh() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    ih.manager.lock().unwrap().ssthresh_cache = Some(HashMap::new());
+    let mut nic = Nic::mock();
+
+    // an earlier connection to the peer learns something about the path
+    insert(&ih, *REMOTE.ip(), State::Estab);
+    ih.manager
+        .lock()
+        .unwrap()
+        .connections
+        .get_mut(&QUAD)
+        .unwrap()
+        .set_ssthresh(5000);
+    dispatch(&ih, &mut nic, &header(IRS + 1, Some(ISS + 1)), &[]);
+    ih.manager.lock().unwrap().connections.remove(&QUAD);
+
+    listen(&ih);
+    dispatch(&ih, &mut nic, &syn(IRS), &[]);
+    let cm = ih.manager.lock().unwrap();
+    assert_eq!(cm.connections[&QUAD].ssthresh(), 5000);
+}
+
+/// A stream on an established connection to `REMOTE`.
+fn stream(ih: &InterfaceHandle) -> TcpStream {
+    insert(ih, *REMOTE.ip(), State::Estab);
+    ih.manager.lock().unwrap().held.insert(QUAD);
+    TcpStream::new(QUAD, ih.clone())
+}
+
+#[test]
+fn nonblocking_write_and_flush_would_block_when_full() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut s = stream(&ih);
+    s.set_nonblocking(true).unwrap();
+
+    let data = vec![b'x'; 2 * SENDQUEUE_SIZE];
+    assert_eq!(s.write(&data).unwrap(), SENDQUEUE_SIZE);
+    let full = s.write(&data[SENDQUEUE_SIZE..]).unwrap_err();
+    assert_eq!(full.kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(s.flush().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn blocking_write_and_flush_wait_for_the_peer() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    let mut s = stream(&ih);
+
+    let data = vec![b'x'; 3 * SENDQUEUE_SIZE];
+    let writer = thread::spawn(move || {
+        s.write_all(&data).unwrap();
+        s.flush().unwrap();
+    });
+    // play the packet loop and the peer, ACKing everything as it comes
+    let mut acked = ISS + 1;
+    while !writer.is_finished() {
+        on_tick(&mut nic, &ih).unwrap();
+        for (tcp, data) in sent(&mut nic) {
+            acked = tcp.sequence_number.wrapping_add(data.len() as u32);
+            dispatch(&ih, &mut nic, &header(IRS + 1, Some(acked)), &[]);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    writer.join().unwrap();
+    assert_eq!(acked, ISS + 1 + 3 * SENDQUEUE_SIZE as u32);
+}
+
+#[test]
+fn listener_allowlist_filters_syns() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    listen(&ih);
+    ih.manager
+        .lock()
+        .unwrap()
+        .pending
+        .get_mut(&LOCAL.port())
+        .unwrap()[0]
+        .allow = Some(vec![(Ipv4Addr::new(10, 0, 0, 2), 31)]);
+
+    dispatch(&ih, &mut nic, &syn(IRS), &[]);
+    assert!(has_connection(&ih));
+    let syn_ack = sent(&mut nic);
+    assert!(syn_ack[0].0.syn && syn_ack[0].0.ack);
+
+    let outside = Ipv4Addr::new(10, 0, 0, 4);
+    dispatch_from(&ih, &mut nic, outside, &syn(IRS), &[]);
+    assert!(!ih
+        .manager
+        .lock()
+        .unwrap()
+        .connections
+        .contains_key(&quad(outside)));
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    assert!(rst[0].0.rst);
+    assert_eq!(rst[0].0.acknowledgment_number, IRS + 1);
+}
+
+#[test]
+fn packet_with_a_bad_ip_checksum_is_dropped() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    listen(&ih);
+
+    let mut packet = packet_from(*REMOTE.ip(), &syn(IRS), &[]);
+    // the ttl, which the tcp checksum doesn't cover
+    packet[8] -= 1;
+    on_ip_packet(&mut nic, &ih, &packet, &mut HashMap::new()).unwrap();
+    assert!(!has_connection(&ih));
+    assert!(sent(&mut nic).is_empty());
+}
+
+/// An ICMP "fragmentation needed" from a router, quoting the first 28 bytes of `offending`.
+fn frag_needed(offending: &[u8], mtu: u16) -> Vec<u8> {
+    let mut icmp = vec![3, 4, 0, 0, 0, 0];
+    icmp.extend_from_slice(&mtu.to_be_bytes());
+    icmp.extend_from_slice(&offending[..28]);
+    let ip = etherparse::Ipv4Header::new(
+        icmp.len() as u16,
+        64,
+        etherparse::IpTrafficClass::Icmp,
+        [10, 0, 0, 254],
+        LOCAL.ip().octets(),
+    );
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(&icmp);
+    packet
+}
+
+#[test]
+fn frag_needed_shrinks_the_mss() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    insert(&ih, *REMOTE.ip(), State::Estab);
+    ih.manager
+        .lock()
+        .unwrap()
+        .connections
+        .get_mut(&QUAD)
+        .unwrap()
+        .unacked
+        .extend(&[b'x'; 500]);
+    on_tick(&mut nic, &ih).unwrap();
+    let offending = nic.take_sent().remove(0);
+
+    on_ip_packet(
+        &mut nic,
+        &ih,
+        &frag_needed(&offending, 300),
+        &mut HashMap::new(),
+    )
+    .unwrap();
+    // the 500 bytes that didn't make it go again in segments that fit
+    let resent = sent(&mut nic);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].0.sequence_number, ISS + 1);
+    assert_eq!(resent[0].1.len(), 260);
+    let cm = ih.manager.lock().unwrap();
+    assert_eq!(cm.connections[&QUAD].negotiated_options().mss, 260);
+}
+
+#[test]
+fn vectored_write_sends_the_slices_back_to_back() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    let mut s = stream(&ih);
+    let slices = [
+        IoSlice::new(b"abc"),
+        IoSlice::new(b""),
+        IoSlice::new(b"defgh"),
+    ];
+    assert_eq!(s.write_vectored(&slices).unwrap(), 8);
+    assert_eq!(s.write_vectored(&[IoSlice::new(b"ij")]).unwrap(), 2);
+
+    on_tick(&mut nic, &ih).unwrap();
+    let mut stream = Vec::new();
+    for (tcp, data) in sent(&mut nic) {
+        assert_eq!(tcp.sequence_number, ISS + 1 + stream.len() as u32);
+        stream.extend(data);
+    }
+    assert_eq!(stream, b"abcdefghij");
+}
+
+#[test]
+fn syns_are_turned_away_by_the_overload_policy_while_not_accepting() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    listen(&ih);
+    ih.manager.lock().unwrap().refusing = true;
+
+    dispatch(&ih, &mut nic, &syn(IRS), &[]);
+    assert!(!has_connection(&ih));
+    assert!(sent(&mut nic).is_empty());
+
+    ih.manager.lock().unwrap().overload_policy = OverloadPolicy::Reset;
+    dispatch(&ih, &mut nic, &syn(IRS), &[]);
+    assert!(!has_connection(&ih));
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    assert!(rst[0].0.rst);
+
+    // accepting again, the next try gets through
+    ih.manager.lock().unwrap().refusing = false;
+    dispatch(&ih, &mut nic, &syn(IRS), &[]);
+    assert!(has_connection(&ih));
+}
+
+#[test]
+fn capture_sees_packets_both_ways() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock_capturing(ih.capture.clone());
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    *ih.capture.lock().unwrap() = Some(Box::new(move |packet: &[u8], direction| {
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&packet[20..]).unwrap();
+        log.lock()
+            .unwrap()
+            .push((direction, tcph.syn(), tcph.ack()));
+    }));
+    listen(&ih);
+
+    dispatch(&ih, &mut nic, &syn(IRS), &[]);
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            (Direction::Incoming, true, false),
+            (Direction::Outgoing, true, true)
+        ]
+    );
+}
+
+#[test]
+fn segment_with_a_bogus_data_offset_is_dropped() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    insert(&ih, *REMOTE.ip(), State::Estab);
+
+    let good = packet_from(*REMOTE.ip(), &header(IRS + 1, Some(ISS + 1)), b"hello");
+    // too short to hold even the fixed header, and running past the end of the segment
+    for offset in [4, 15] {
+        let mut packet = good.clone();
+        packet[20 + 12] = offset << 4;
+        on_ip_packet(&mut nic, &ih, &packet, &mut HashMap::new()).unwrap();
+    }
+    assert!(sent(&mut nic).is_empty());
+    let cm = ih.manager.lock().unwrap();
+    let c = &cm.connections[&QUAD];
+    assert!(!c.is_readable());
+    assert_eq!(c.stats().bytes_received, 0);
+}
+
+#[test]
+fn reset_is_reported_after_the_data_before_it() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut nic = Nic::mock();
+    let mut s = stream(&ih);
+    dispatch(&ih, &mut nic, &header(IRS + 1, Some(ISS + 1)), b"hello");
+    let mut rst = header(IRS + 6, None);
+    rst.rst = true;
+    dispatch(&ih, &mut nic, &rst, &[]);
+
+    let mut buf = [0; 16];
+    assert_eq!(s.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    let err = s.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn poll_reports_only_the_connection_with_data_as_readable() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    insert(&ih, *REMOTE.ip(), State::Estab);
+    insert(&ih, OTHER, State::Estab);
+    let mut nic = Nic::mock();
+    dispatch(&ih, &mut nic, &header(IRS + 1, Some(ISS + 1)), b"hello");
+
+    let cm = ih.manager.lock().unwrap();
+    let ready = cm.poll();
+    // both can be written to, but only one has anything to read
+    assert_eq!(ready.len(), 2);
+    let readable: Vec<_> = ready
+        .iter()
+        .filter(|(_, ready)| ready.contains(tcp::Available::READ))
+        .map(|&(q, _)| q)
+        .collect();
+    assert_eq!(readable, vec![QUAD]);
+}
+
+#[test]
+fn payload_of_a_segment_with_options_starts_after_them() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut s = stream(&ih);
+    let mut nic = Nic::mock();
+
+    // two NOPs and a timestamp, which the stack doesn't use but has to step over
+    let mut tcp = header(IRS + 1, Some(ISS + 1));
+    tcp.set_options_raw(&[1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0])
+        .unwrap();
+    assert_eq!(tcp.header_len(), 32);
+    dispatch(&ih, &mut nic, &tcp, b"payload");
+
+    let mut buf = [0; 64];
+    let n = s.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"payload");
+}
+
+#[test]
+fn removed_device_aborts_every_connection() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut s = stream(&ih);
+    insert(&ih, OTHER, State::Estab);
+    s.write_all(b"hello").unwrap();
+
+    let mut nic = Nic::mock();
+    nic.remove(nix::errno::Errno::ENODEV as i32);
+    let e = on_tick(&mut nic, &ih).unwrap_err();
+    assert!(is_device_gone(&e));
+    on_device_gone(&ih);
+
+    let cm = ih.manager.lock().unwrap();
+    assert_eq!(cm.connections.len(), 2);
+    for c in cm.connections.values() {
+        let e = c.error().unwrap();
+        let reason = e.get_ref().unwrap().downcast_ref::<ResetReason>();
+        assert_eq!(reason, Some(&ResetReason::DeviceError));
+    }
+    drop(cm);
+    let e = s.write(b"more").unwrap_err();
+    assert_eq!(e.kind(), ResetReason::DeviceError.kind());
+}
+
+#[test]
+fn tcp_header_is_found_after_ip_options() {
+    let clock = Arc::new(MockClock::new());
+    let ih = handle(&clock, ConnectionConfig::default());
+    let mut s = stream(&ih);
+    let mut nic = Nic::mock();
+
+    let tcp = header(IRS + 1, Some(ISS + 1));
+    let data = b"through a router";
+    let mut ip = etherparse::Ipv4Header::new(
+        tcp.header_len() + data.len() as u16,
+        64,
+        etherparse::IpTrafficClass::Tcp,
+        REMOTE.ip().octets(),
+        LOCAL.ip().octets(),
+    );
+    // router alert (RFC 2113), which pushes the tcp header four bytes further in
+    ip.set_options(&[148, 4, 0, 0]).unwrap();
+    let mut tcp = tcp.clone();
+    tcp.checksum = tcp.calc_checksum_ipv4(&ip, data).unwrap();
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    tcp.write(&mut packet).unwrap();
+    packet.extend_from_slice(data);
+    assert_eq!(packet[0] & 0xf, 6);
+    dispatch_packet(&ih, &mut nic, packet);
+
+    let mut read = [0; 64];
+    let n = s.read(&mut read).unwrap();
+    assert_eq!(&read[..n], data);
+}
+
+#[test]
+fn high_water_mark_stops_writes_before_the_queue_is_full() {
+    let clock = Arc::new(MockClock::new());
+    let config = ConnectionConfig {
+        send_high_water: Some(100),
+        ..Default::default()
+    };
+    let ih = handle(&clock, config);
+    let mut s = stream(&ih);
+    s.set_nonblocking(true).unwrap();
+
+    // a write below the mark is taken whole, even though that takes the queue past it
+    assert_eq!(s.write(&[b'x'; 150]).unwrap(), 150);
+    {
+        let cm = ih.manager.lock().unwrap();
+        let c = &cm.connections[&QUAD];
+        assert!(!c.is_writable());
+        assert!(c.unacked.len() < SENDQUEUE_SIZE);
+    }
+    let e = s.write(b"more").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn connect_to_a_silent_host_times_out() {
+    let clock = Arc::new(MockClock::new());
+    let config = ConnectionConfig {
+        syn_retries: Some(2),
+        ..Default::default()
+    };
+    let ih = handle(&clock, config);
+    let mut iface = Interface {
+        ih: Some(ih.clone()),
+        jh: Some(thread::spawn(|| Ok(()))),
+    };
+    let connector = thread::spawn(move || iface.connect(*LOCAL.ip(), REMOTE).map(|_| ()));
+
+    // play the packet loop, for a peer that never answers
+    let mut nic = Nic::mock();
+    let mut syns = 0;
+    while !connector.is_finished() {
+        start_connecting(&mut nic, &mut ih.manager.lock().unwrap()).unwrap();
+        on_tick(&mut nic, &ih).unwrap();
+        syns += sent(&mut nic).iter().filter(|(tcp, _)| tcp.syn).count();
+        clock.advance(DEFAULT_MAX_RTO);
+        thread::sleep(Duration::from_millis(1));
+    }
+    let e = connector.join().unwrap().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(syns, 3);
+}
+
+#[test]
+fn dropping_a_listener_after_shutdown_resets_what_it_never_accepted() {
+    let clock = Arc::new(MockClock::new());
+    let config = ConnectionConfig {
+        iss: IssStrategy::Fixed(ISS),
+        ..Default::default()
+    };
+    let ih = handle(&clock, config);
+    let mut nic = Nic::mock();
+    listen(&ih);
+    let listener = TcpListener {
+        port: LOCAL.port(),
+        id: 0,
+        h: ih.clone(),
+    };
+    dispatch(&ih, &mut nic, &syn(IRS), &[]);
+    dispatch(&ih, &mut nic, &header(IRS + 1, Some(ISS + 1)), &[]);
+    sent(&mut nic);
+    assert!(has_connection(&ih));
+
+    assert_eq!(ih.manager.lock().unwrap().shutdown_all(), 1);
+    on_tick(&mut nic, &ih).unwrap();
+    assert!(sent(&mut nic)[0].0.fin);
+
+    drop(listener);
+    on_tick(&mut nic, &ih).unwrap();
+    let rst = sent(&mut nic);
+    assert_eq!(rst.len(), 1);
+    assert!(rst[0].0.rst);
+    assert!(!has_connection(&ih));
+    assert!(ih.manager.lock().unwrap().pending.is_empty());
+}
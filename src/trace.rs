@@ -0,0 +1,134 @@
+//! A readable one-line-per-segment trace, as an alternative to capturing raw packets.
+
+use std::{collections::HashMap, net::Ipv4Addr, sync::Mutex};
+
+use crate::nic::{Capture, Direction};
+
+type Endpoint = (Ipv4Addr, u16);
+
+/// Builds a [`Capture`] that hands `sink` a line like
+///
+/// ```text
+/// > 10.0.0.1:49152 -> 10.0.0.2:80 [P.] seq=1 ack=1 win=1024 len=5
+/// ```
+///
+/// for every TCP segment. Like tcpdump's default, sequence and acknowledgement numbers are
+/// relative to the first sequence number seen going each way (the ISS or IRS, if the trace was
+/// running when the connection opened), so the first byte of data is `seq=1`.
+pub fn segment_trace(sink: impl Fn(String) + Send + 'static) -> Capture {
+    let bases = Mutex::new(HashMap::new());
+    Box::new(move |packet, direction| {
+        if let Some(line) = describe(&mut bases.lock().unwrap(), packet, direction) {
+            sink(line);
+        }
+    })
+}
+
+/// Formats `packet`, if it's a TCP segment, first noting its sequence number as the base for
+/// its direction of the connection if this is the SYN (or the first segment we see).
+fn describe(
+    bases: &mut HashMap<(Endpoint, Endpoint), u32>,
+    packet: &[u8],
+    direction: Direction,
+) -> Option<String> {
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(packet).ok()?;
+    if iph.protocol() != 0x06 {
+        return None;
+    }
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]).ok()?;
+    let len = (iph.total_len() as usize).saturating_sub(iph.slice().len() + tcph.slice().len());
+
+    let src = (iph.source_addr(), tcph.source_port());
+    let dst = (iph.destination_addr(), tcph.destination_port());
+    let seq = tcph.sequence_number();
+    if tcph.syn() {
+        bases.insert((src, dst), seq);
+    }
+    let base = *bases.entry((src, dst)).or_insert(seq);
+
+    let mut flags = String::new();
+    for (set, flag) in [
+        (tcph.syn(), 'S'),
+        (tcph.fin(), 'F'),
+        (tcph.rst(), 'R'),
+        (tcph.psh(), 'P'),
+        (tcph.ack(), '.'),
+    ] {
+        if set {
+            flags.push(flag);
+        }
+    }
+
+    let mut line = format!(
+        "{} {}:{} -> {}:{} [{}] seq={}",
+        match direction {
+            Direction::Incoming => '<',
+            Direction::Outgoing => '>',
+        },
+        src.0,
+        src.1,
+        dst.0,
+        dst.1,
+        flags,
+        seq.wrapping_sub(base),
+    );
+    if tcph.ack() {
+        let ack = tcph.acknowledgment_number();
+        // until we've seen the other way, there's nothing to make the ACK relative to
+        let ack = bases
+            .get(&(dst, src))
+            .map_or(ack, |&peer_base| ack.wrapping_sub(peer_base));
+        line.push_str(&format!(" ack={}", ack));
+    }
+    line.push_str(&format!(" win={} len={}", tcph.window_size(), len));
+    Some(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::nic::Nic;
+    use crate::tcp::tests::{header, packet, parse, syn, IRS, ISS, LOCAL};
+    use crate::tcp::{Connection, ConnectionConfig, IssStrategy};
+
+    #[test]
+    fn trace_numbers_segments_from_the_handshake() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let capture = segment_trace(move |line| sink.lock().unwrap().push(line));
+        let mut nic = Nic::mock_capturing(Arc::new(Mutex::new(Some(capture))));
+        let config = ConnectionConfig {
+            iss: IssStrategy::Fixed(ISS),
+            ..Default::default()
+        };
+        let mut c = Connection::listen(LOCAL, config);
+        let mut buf = [0; 1504];
+        for (tcp, data) in [
+            (syn(IRS), &b""[..]),
+            (header(IRS + 1, Some(ISS + 1)), b""),
+            (header(IRS + 1, Some(ISS + 1)), b"hello"),
+        ] {
+            nic.inject(packet(&tcp, data));
+            let nbytes = nic.recv(&mut buf).unwrap();
+            let (iph, tcph, data) = parse(&buf[..nbytes]);
+            c.on_packet(&mut nic, iph, tcph, data).unwrap();
+        }
+        c.unacked.extend(b"hi");
+        c.on_tick(&mut nic).unwrap();
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(
+            lines[0],
+            "< 10.0.0.2:5000 -> 10.0.0.1:80 [S] seq=0 win=1024 len=0"
+        );
+        assert!(lines[1].starts_with("> 10.0.0.1:80 -> 10.0.0.2:5000 [S.] seq=0 ack=1 "));
+        assert_eq!(
+            lines[3],
+            "< 10.0.0.2:5000 -> 10.0.0.1:80 [.] seq=1 ack=1 win=1024 len=5"
+        );
+        assert!(lines.last().unwrap().contains(" seq=1 ack=6 "));
+        assert!(lines.last().unwrap().ends_with(" len=2"));
+    }
+}